@@ -1 +1,22 @@
-import('./pkg').catch(console.error);
+import './style.css';
+
+import('./pkg')
+  .then((wasm) => {
+    // Drop a `.json` or `.ron` preset anywhere on the page to load it
+    document.addEventListener('dragover', (event) => event.preventDefault());
+    document.addEventListener('drop', (event) => {
+      event.preventDefault();
+      const file = event.dataTransfer.files[0];
+      if (!file) {
+        return;
+      }
+      file.text().then((source) => {
+        try {
+          wasm.load_preset(source);
+        } catch (error) {
+          console.error(error);
+        }
+      });
+    });
+  })
+  .catch(console.error);
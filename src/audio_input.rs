@@ -0,0 +1,472 @@
+use crate::{document, error::DemoError, window};
+use js_sys::Array;
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+  AudioContext, AudioNode, GainNode, HtmlAudioElement, HtmlElement, HtmlOptionElement,
+  HtmlSelectElement, MediaDeviceInfo, MediaDeviceKind, MediaDevices, MediaElementAudioSourceNode,
+  MediaStream, MediaStreamAudioDestinationNode, MediaStreamAudioSourceNode, MediaStreamTrack,
+  MediaTrackConstraints, OscillatorNode, OscillatorType,
+};
+
+const STATUS_ELEMENT: &str = "audio-status";
+/// Shown while the visuals run on a fallback, asks for the microphone again
+const RETRY_BUTTON: &str = "audio-retry";
+/// A `<select>` listing the audio inputs, the empty value is the browser's default
+const DEVICE_SELECT: &str = "audio-input";
+/// Where the chosen `deviceId` is kept between visits
+const DEVICE_STORAGE_KEY: &str = "democ-audio-input";
+
+/// The synthetic source pulses a bass tone at 120 bpm, enough for the beat detector and bands
+const SYNTHETIC_FREQUENCY: f32 = 55.0;
+const SYNTHETIC_PULSE: f32 = 2.0;
+
+thread_local! {
+  /// Set up by the audio loop, used by `select_audio_input` and the device change listener
+  static INPUT: RefCell<Option<SharedInput>> = const { RefCell::new(None) };
+}
+
+/// The microphone, or what stands in for it, feeding the analyser
+pub(crate) type SharedInput = Rc<RefCell<AudioInput>>;
+
+/// Where the analyser gets its audio from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioSource {
+  /// Waiting for the microphone permission
+  Pending,
+  Microphone,
+  /// The music track, when the microphone is not available
+  Track,
+  /// Oscillators, when there is neither a microphone nor a track
+  Synthetic,
+}
+
+impl AudioSource {
+  fn name(self) -> &'static str {
+    match self {
+      AudioSource::Pending => "pending",
+      AudioSource::Microphone => "microphone",
+      AudioSource::Track => "track",
+      AudioSource::Synthetic => "synthetic",
+    }
+  }
+}
+
+pub(crate) struct AudioInput {
+  context: AudioContext,
+  /// Where every source goes to be analysed
+  analyser: AudioNode,
+  recording: MediaStreamAudioDestinationNode,
+  /// The music track as played through the context, when the page has one
+  track: Option<MediaElementAudioSourceNode>,
+  /// The stream of the connected input and its node
+  microphone: Option<(MediaStream, MediaStreamAudioSourceNode)>,
+  /// The oscillators and their output, while the synthetic source plays
+  synthetic: Option<([OscillatorNode; 2], GainNode)>,
+  source: AudioSource,
+  /// The input asked for, `None` for the browser's default
+  device_id: Option<String>,
+  /// The input actually connected, which can differ when the chosen one is unplugged
+  active_device_id: Option<String>,
+  /// The microphone was refused, asking again without a click only gets refused again
+  denied: bool,
+  /// A request for the microphone is still waiting on the browser
+  connecting: bool,
+  /// Another input was asked for while waiting, it is requested once the browser answers
+  reconnect: bool,
+}
+
+impl AudioInput {
+  fn use_microphone(&mut self, stream: MediaStream) -> Result<(), JsValue> {
+    self.stop_microphone();
+    self.stop_fallback();
+    let microphone = self.context.create_media_stream_source(&stream)?;
+    microphone.connect_with_audio_node(&self.analyser)?;
+    // Recordings get the mic, the track goes to them already
+    microphone.connect_with_audio_node(&self.recording)?;
+    self.active_device_id = stream
+      .get_audio_tracks()
+      .iter()
+      .next()
+      .and_then(|track| track.dyn_into::<MediaStreamTrack>().ok())
+      .and_then(|track| track.get_settings().get_device_id());
+    self.microphone = Some((stream, microphone));
+    self.source = AudioSource::Microphone;
+    Ok(())
+  }
+
+  /// Release the input, so the browser stops showing it as in use
+  fn stop_microphone(&mut self) {
+    if let Some((stream, microphone)) = self.microphone.take() {
+      let _ = microphone.disconnect();
+      for track in stream.get_tracks().iter() {
+        if let Ok(track) = track.dyn_into::<MediaStreamTrack>() {
+          track.stop();
+        }
+      }
+    }
+    self.active_device_id = None;
+  }
+
+  /// Analyse the track when there is one, otherwise generate something to react to. Neither
+  /// goes to the speakers or recordings on top of what they get already.
+  fn use_fallback(&mut self) -> Result<(), JsValue> {
+    self.stop_microphone();
+    if self.source == AudioSource::Track || self.source == AudioSource::Synthetic {
+      return Ok(());
+    }
+    if let Some(track) = &self.track {
+      track.connect_with_audio_node(&self.analyser)?;
+      self.source = AudioSource::Track;
+      return Ok(());
+    }
+
+    let tone = self.context.create_oscillator()?;
+    tone.set_type(OscillatorType::Sawtooth);
+    tone.frequency().set_value(SYNTHETIC_FREQUENCY);
+    let pulse = self.context.create_oscillator()?;
+    pulse.frequency().set_value(SYNTHETIC_PULSE);
+    let output = self.context.create_gain()?;
+    // Swings the gain between 0 and 1
+    output.gain().set_value(0.5);
+    let depth = self.context.create_gain()?;
+    depth.gain().set_value(0.5);
+
+    pulse.connect_with_audio_node(&depth)?;
+    depth.connect_with_audio_param(&output.gain())?;
+    tone.connect_with_audio_node(&output)?;
+    output.connect_with_audio_node(&self.analyser)?;
+    tone.start()?;
+    pulse.start()?;
+    self.synthetic = Some(([tone, pulse], output));
+    self.source = AudioSource::Synthetic;
+    Ok(())
+  }
+
+  fn stop_fallback(&mut self) {
+    if self.source == AudioSource::Track {
+      if let Some(track) = &self.track {
+        let _ = track.disconnect_with_audio_node(&self.analyser);
+      }
+    }
+    if let Some((oscillators, output)) = self.synthetic.take() {
+      for oscillator in &oscillators {
+        let _ = oscillator.stop();
+      }
+      let _ = output.disconnect();
+    }
+  }
+}
+
+/// Route the music track through `context` and hook up the retry button and input list. The
+/// analyser stays silent until `connect_microphone` settles on a source.
+pub(crate) fn init(
+  context: &AudioContext,
+  analyser: &AudioNode,
+  recording: &MediaStreamAudioDestinationNode,
+  track: Option<&HtmlAudioElement>,
+) -> Result<SharedInput, JsValue> {
+  let track = match track {
+    Some(track) => {
+      let track_node = context.create_media_element_source(track)?;
+      track_node.connect_with_audio_node(&context.destination())?;
+      track_node.connect_with_audio_node(recording)?;
+      Some(track_node)
+    }
+    None => None,
+  };
+
+  let input = Rc::new(RefCell::new(AudioInput {
+    context: context.clone(),
+    analyser: analyser.clone(),
+    recording: recording.clone(),
+    track,
+    microphone: None,
+    synthetic: None,
+    source: AudioSource::Pending,
+    device_id: stored_device_id(),
+    active_device_id: None,
+    denied: false,
+    connecting: false,
+    reconnect: false,
+  }));
+  INPUT.with(|shared| *shared.borrow_mut() = Some(input.clone()));
+
+  let document = document()?;
+  if let Some(button) = document.get_element_by_id(RETRY_BUTTON) {
+    let retried_input = input.clone();
+    let closure = Closure::wrap(Box::new(move || {
+      // A click is the user gesture a suspended context needs
+      let _ = retried_input.borrow().context.resume();
+      retried_input.borrow_mut().denied = false;
+      wasm_bindgen_futures::spawn_local(connect_microphone(retried_input.clone()));
+    }) as Box<dyn FnMut()>);
+    button.add_event_listener_with_callback("click", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  if let Some(select) = document.get_element_by_id(DEVICE_SELECT) {
+    let select = select.dyn_into::<HtmlSelectElement>()?;
+    let changed_select = select.clone();
+    let closure = Closure::wrap(Box::new(move || {
+      let value = changed_select.value();
+      let device_id = if value.is_empty() { None } else { Some(value) };
+      wasm_bindgen_futures::spawn_local(async move {
+        if let Err(err) = select_audio_input(device_id).await {
+          web_sys::console::error_1(&err);
+        }
+      });
+    }) as Box<dyn FnMut()>);
+    select.add_event_listener_with_callback("change", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  // Without media devices `connect_microphone` falls back and says why
+  if let Ok(media_devices) = window().navigator().media_devices() {
+    let changed_input = input.clone();
+    let closure = Closure::wrap(Box::new(move || {
+      wasm_bindgen_futures::spawn_local(devices_changed(changed_input.clone()));
+    }) as Box<dyn FnMut()>);
+    media_devices.set_ondevicechange(Some(closure.as_ref().unchecked_ref()));
+    closure.forget();
+  }
+
+  show_status(&input.borrow(), None);
+  Ok(input)
+}
+
+/// Ask for the chosen input and analyse it, falling back to the track or the synthetic source
+/// when it is refused or missing
+pub(crate) async fn connect_microphone(input: SharedInput) {
+  if let Err(err) = connect(&input).await {
+    web_sys::console::warn_1(&JsValue::from(err));
+  }
+}
+
+/// Only one request is made at a time, asking while one is pending queues another with whatever
+/// input is chosen by the time the first one settles
+async fn connect(input: &SharedInput) -> Result<(), DemoError> {
+  {
+    let mut input = input.borrow_mut();
+    if input.connecting {
+      input.reconnect = true;
+      return Ok(());
+    }
+    input.connecting = true;
+  }
+  let result = loop {
+    let device_id = input.borrow().device_id.clone();
+    let result = match request_microphone(device_id.as_deref()).await {
+      Ok(stream) => input.borrow_mut().use_microphone(stream).map_err(DemoError::from),
+      Err(err) => Err(err),
+    };
+    if !std::mem::take(&mut input.borrow_mut().reconnect) {
+      break result;
+    }
+  };
+
+  {
+    let mut input = input.borrow_mut();
+    input.connecting = false;
+    if let Err(err) = &result {
+      input.denied = matches!(err, DemoError::AudioPermissionDenied(_));
+      if let Err(fallback_err) = input.use_fallback() {
+        web_sys::console::error_1(&fallback_err);
+      }
+    }
+    show_status(&input, result.as_ref().err());
+  }
+
+  // Labels are only handed out once an input was allowed
+  if let Err(err) = refresh_device_list().await {
+    web_sys::console::error_1(&err);
+  }
+  result
+}
+
+/// Hot-swap when the connected input disappears, or the chosen one comes back
+async fn devices_changed(input: SharedInput) {
+  let devices = match audio_input_devices().await {
+    Ok(devices) => devices,
+    Err(err) => return web_sys::console::error_1(&err),
+  };
+  let present = |id: &str| devices.iter().any(|device| device.device_id() == id);
+
+  let reconnect = {
+    let input = input.borrow();
+    let chosen_is_back =
+      input.device_id.as_deref().is_some_and(present) && input.active_device_id != input.device_id;
+    match input.source {
+      AudioSource::Pending => false,
+      AudioSource::Microphone => {
+        let active_is_gone = input.active_device_id.as_deref().is_some_and(|id| !present(id));
+        active_is_gone || chosen_is_back
+      }
+      // Plugging an interface in after starting without one
+      AudioSource::Track | AudioSource::Synthetic => !input.denied && !devices.is_empty(),
+    }
+  };
+  if reconnect {
+    connect_microphone(input).await;
+  } else if let Err(err) = refresh_device_list().await {
+    web_sys::console::error_1(&err);
+  }
+}
+
+/// The audio inputs as `{ deviceId, label }` objects. Labels stay empty until the microphone has
+/// been allowed.
+#[wasm_bindgen]
+pub async fn audio_inputs() -> Result<Array, JsValue> {
+  let list = Array::new();
+  for device in audio_input_devices().await? {
+    let entry = js_sys::Object::new();
+    js_sys::Reflect::set(&entry, &"deviceId".into(), &device.device_id().into())?;
+    js_sys::Reflect::set(&entry, &"label".into(), &device.label().into())?;
+    list.push(&entry);
+  }
+  Ok(list)
+}
+
+/// Listen to the input with `device_id` from now on and on later visits, or to the browser's
+/// default with `undefined`. Resolves once the input is connected, or right away when it was
+/// queued behind a request the browser has not answered yet.
+#[wasm_bindgen]
+pub async fn select_audio_input(device_id: Option<String>) -> Result<(), JsValue> {
+  let input = INPUT.with(|shared| shared.borrow().clone()).ok_or("Audio has not been started")?;
+  store_device_id(device_id.as_deref());
+  {
+    let mut input = input.borrow_mut();
+    input.device_id = device_id;
+    input.denied = false;
+  }
+  Ok(connect(&input).await?)
+}
+
+async fn audio_input_devices() -> Result<Vec<MediaDeviceInfo>, JsValue> {
+  let devices = JsFuture::from(media_devices()?.enumerate_devices()?).await?;
+  Ok(
+    Array::from(&devices)
+      .iter()
+      .filter_map(|device| device.dyn_into::<MediaDeviceInfo>().ok())
+      .filter(|device| device.kind() == MediaDeviceKind::Audioinput)
+      .collect(),
+  )
+}
+
+/// Only there on secure origins
+fn media_devices() -> Result<MediaDevices, DemoError> {
+  window()
+    .navigator()
+    .media_devices()
+    .map_err(|_| DemoError::UnsupportedFeature("microphone input".to_string()))
+}
+
+/// Processing meant for calls flattens music: echo cancellation eats the bass, noise
+/// suppression the cymbals, and gain control pumps the level
+async fn request_microphone(device_id: Option<&str>) -> Result<MediaStream, DemoError> {
+  let audio = MediaTrackConstraints::new();
+  audio.set_echo_cancellation_bool(false);
+  audio.set_noise_suppression_bool(false);
+  audio.set_auto_gain_control_bool(false);
+  // Interfaces often default to mono, stereo is only used when they have it
+  audio.set_channel_count_i32(2);
+  // Not `exact`, an unplugged interface gives way to another input instead of silence
+  if let Some(device_id) = device_id {
+    audio.set_device_id_str(device_id);
+  }
+  let constraints = web_sys::MediaStreamConstraints::new();
+  constraints.set_audio(&audio);
+  constraints.set_video(&JsValue::FALSE);
+  let promise = media_devices()?.get_user_media_with_constraints(&constraints)?;
+  let stream = JsFuture::from(promise).await.map_err(user_media_error)?;
+  Ok(stream.dyn_into()?)
+}
+
+/// `getUserMedia` rejects with a `DOMException` named after what went wrong
+fn user_media_error(err: JsValue) -> DemoError {
+  let property =
+    |name: &str| js_sys::Reflect::get(&err, &name.into()).ok().and_then(|value| value.as_string());
+  match property("name").as_deref() {
+    Some("NotAllowedError") | Some("SecurityError") => {
+      DemoError::AudioPermissionDenied(property("message").unwrap_or_default())
+    }
+    Some("NotFoundError") => DemoError::UnsupportedFeature("a microphone".to_string()),
+    _ => DemoError::Js(err),
+  }
+}
+
+fn stored_device_id() -> Option<String> {
+  let storage = window().local_storage().ok().flatten()?;
+  storage.get_item(DEVICE_STORAGE_KEY).ok().flatten()
+}
+
+/// Storage can be disabled, the choice then only lasts for this visit
+fn store_device_id(device_id: Option<&str>) {
+  if let Ok(Some(storage)) = window().local_storage() {
+    let _ = match device_id {
+      Some(device_id) => storage.set_item(DEVICE_STORAGE_KEY, device_id),
+      None => storage.remove_item(DEVICE_STORAGE_KEY),
+    };
+  }
+}
+
+/// Fill the input list on the page, when it has one, with the chosen input selected
+async fn refresh_device_list() -> Result<(), JsValue> {
+  let document = document()?;
+  let select = match document.get_element_by_id(DEVICE_SELECT) {
+    Some(select) => select.dyn_into::<HtmlSelectElement>()?,
+    None => return Ok(()),
+  };
+  let devices = audio_input_devices().await?;
+  let chosen = INPUT
+    .with(|shared| shared.borrow().as_ref().and_then(|input| input.borrow().device_id.clone()));
+
+  select.set_inner_html("");
+  let default = HtmlOptionElement::new_with_text_and_value("Default input", "")?;
+  select.add_with_html_option_element(&default)?;
+  for (index, device) in devices.iter().enumerate() {
+    let label = match device.label() {
+      label if label.is_empty() => format!("Input {}", index + 1),
+      label => label,
+    };
+    let option = HtmlOptionElement::new_with_text_and_value(&label, &device.device_id())?;
+    option.set_selected(chosen.as_deref() == Some(device.device_id().as_str()));
+    select.add_with_html_option_element(&option)?;
+  }
+  Ok(())
+}
+
+/// Say what the visuals are reacting to, and why when it is not the microphone
+fn show_status(input: &AudioInput, err: Option<&DemoError>) {
+  let document = match window().document() {
+    Some(document) => document,
+    None => return,
+  };
+  if let Some(status) = document.get_element_by_id(STATUS_ELEMENT) {
+    let mut text = match input.source {
+      AudioSource::Pending => "Waiting for the microphone".to_string(),
+      AudioSource::Microphone => "Listening to the microphone".to_string(),
+      AudioSource::Track => "Reacting to the music track".to_string(),
+      AudioSource::Synthetic => "Reacting to a generated beat".to_string(),
+    };
+    if input.source == AudioSource::Microphone
+      && input.device_id.is_some()
+      && input.active_device_id != input.device_id
+    {
+      text.push_str(", the chosen input is not connected");
+    }
+    if let Some(err) = err {
+      text = format!("{} ({})", text, err);
+    }
+    status.set_text_content(Some(&text));
+    let _ = status.set_attribute("data-source", input.source.name());
+  }
+  let retry = document
+    .get_element_by_id(RETRY_BUTTON)
+    .and_then(|button| button.dyn_into::<HtmlElement>().ok());
+  if let Some(retry) = retry {
+    retry
+      .set_hidden(input.source == AudioSource::Microphone || input.source == AudioSource::Pending);
+  }
+}
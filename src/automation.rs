@@ -0,0 +1,140 @@
+use serde::Deserialize;
+
+/// How a segment of an automation curve moves from one keyframe to the next
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+  /// Hold the value until the next keyframe
+  Step,
+  #[default]
+  Linear,
+  EaseIn,
+  EaseOut,
+  EaseInOut,
+}
+
+impl Easing {
+  /// Map `t` in `0.0..=1.0` to the eased progress
+  pub fn ease(self, t: f32) -> f32 {
+    let t = t.clamp(0.0, 1.0);
+    match self {
+      Easing::Step => 0.0,
+      Easing::Linear => t,
+      Easing::EaseIn => t * t * t,
+      Easing::EaseOut => 1.0 - (1.0 - t).powi(3),
+      Easing::EaseInOut => {
+        if t < 0.5 {
+          4.0 * t * t * t
+        } else {
+          1.0 - (-2.0 * t + 2.0).powi(3) / 2.0
+        }
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keyframe {
+  /// Seconds, or beats when the curve is `in_beats`
+  pub time: f32,
+  pub value: f32,
+  /// How the segment starting at this keyframe reaches the next one
+  #[serde(default)]
+  pub easing: Easing,
+}
+
+/// A value drawn over time through keyframes, holding the first and last values outside them
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Automation {
+  /// Sorted by time
+  pub keyframes: Vec<Keyframe>,
+  /// Keyframe times count beats at the preset's tempo instead of seconds
+  #[serde(default)]
+  pub in_beats: bool,
+  /// Start over after the last keyframe
+  #[serde(default)]
+  pub looped: bool,
+}
+
+impl Automation {
+  /// The value at `time` seconds with the tempo at `tempo` beats per minute
+  pub fn value(&self, time: f32, tempo: f32) -> f32 {
+    let (first, last) = match (self.keyframes.first(), self.keyframes.last()) {
+      (Some(first), Some(last)) => (first, last),
+      _ => return 0.0,
+    };
+    let mut time = if self.in_beats { time * tempo / 60.0 } else { time };
+    let length = last.time - first.time;
+    if self.looped && length > 0.0 {
+      time = first.time + (time - first.time).rem_euclid(length);
+    }
+
+    if time <= first.time {
+      return first.value;
+    }
+    // The first keyframe at or after `time`, `time` is past the first one so `next` is at least 1
+    let next = self.keyframes.partition_point(|keyframe| keyframe.time < time);
+    if next == self.keyframes.len() {
+      return last.value;
+    }
+    let (from, to) = (&self.keyframes[next - 1], &self.keyframes[next]);
+    let span = to.time - from.time;
+    if span <= 0.0 {
+      return to.value;
+    }
+    let t = from.easing.ease((time - from.time) / span);
+    from.value + (to.value - from.value) * t
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::assert_close;
+
+  fn curve(easing: Easing, in_beats: bool, looped: bool) -> Automation {
+    let keyframes = vec![
+      Keyframe { time: 1.0, value: 0.0, easing },
+      Keyframe { time: 3.0, value: 1.0, easing: Easing::Linear },
+    ];
+    Automation { keyframes, in_beats, looped }
+  }
+
+  #[test]
+  fn the_same_time_gives_the_same_value() {
+    for easing in [Easing::Step, Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut]
+    {
+      let curve = curve(easing, false, true);
+      for time in [0.0, 1.5, 2.0, 2.75, 17.3] {
+        assert_eq!(curve.value(time, 120.0), curve.value(time, 120.0), "{:?}", easing);
+      }
+      // Looped curves wrap around on both sides of the keyframes
+      assert_eq!(curve.value(3.5, 120.0), curve.value(1.5, 120.0));
+      assert_eq!(curve.value(0.5, 120.0), curve.value(2.5, 120.0));
+    }
+  }
+
+  #[test]
+  fn easings_shape_the_segment() {
+    let at_half = |easing| curve(easing, false, false).value(2.0, 120.0);
+    assert_eq!(at_half(Easing::Step), 0.0);
+    assert_eq!(at_half(Easing::Linear), 0.5);
+    assert_eq!(at_half(Easing::EaseIn), 0.125);
+    assert_eq!(at_half(Easing::EaseOut), 0.875);
+    assert_eq!(at_half(Easing::EaseInOut), 0.5);
+    // Outside the keyframes the ends are held
+    assert_eq!(curve(Easing::EaseIn, false, false).value(0.0, 120.0), 0.0);
+    assert_eq!(curve(Easing::EaseIn, false, false).value(5.0, 120.0), 1.0);
+  }
+
+  #[test]
+  fn beat_keyframes_follow_the_tempo() {
+    let curve = curve(Easing::Linear, true, false);
+    // Beat 2 is halfway, one second in at 120 bpm and half a second at 240
+    assert_close(curve.value(1.0, 120.0), 0.5, 1e-6);
+    assert_close(curve.value(0.5, 240.0), 0.5, 1e-6);
+    assert_close(curve.value(1.5, 120.0), 1.0, 1e-6);
+  }
+}
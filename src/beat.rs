@@ -0,0 +1,61 @@
+/// Flags a beat when the energy of a band jumps well above its recent average
+pub struct BeatDetector {
+  /// Running average of the energy
+  average: f32,
+  /// How far above the average the energy has to be, e.g. `1.4` for 40% louder
+  threshold: f32,
+  /// Energies below this never count as a beat, so silence does not trigger on noise
+  floor: f32,
+  /// Seconds after a beat during which no new beat is reported
+  cooldown: f32,
+  since_last_beat: f32,
+  /// Smoothed seconds between beats, used to predict the next one
+  interval: f32,
+}
+
+impl BeatDetector {
+  pub fn new() -> Self {
+    BeatDetector {
+      average: 0.0,
+      threshold: 1.4,
+      floor: 0.1,
+      cooldown: 0.2,
+      since_last_beat: 0.0,
+      interval: 0.5,
+    }
+  }
+
+  /// Feed the energy of the current frame, `dt` seconds after the previous one. Returns `true` on
+  /// the frame a beat starts.
+  pub fn update(&mut self, energy: f32, dt: f32) -> bool {
+    self.since_last_beat += dt;
+    let is_beat = energy > self.floor
+      && energy > self.average * self.threshold
+      && self.since_last_beat >= self.cooldown;
+    if is_beat {
+      // Long gaps are breaks in the music rather than the tempo
+      if self.since_last_beat < 2.0 {
+        self.interval += (self.since_last_beat - self.interval) * 0.3;
+      }
+      self.since_last_beat = 0.0;
+    }
+
+    // About a second of memory, independent of the frame rate
+    let smoothing = (dt / 1.0).min(1.0);
+    self.average += (energy - self.average) * smoothing;
+
+    is_beat
+  }
+
+  /// `0.0` on a beat, ramping to `1.0` where the next beat is expected and wrapping around when
+  /// it does not come
+  pub fn phase(&self) -> f32 {
+    (self.since_last_beat / self.interval).fract()
+  }
+}
+
+impl Default for BeatDetector {
+  fn default() -> Self {
+    BeatDetector::new()
+  }
+}
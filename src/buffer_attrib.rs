@@ -2,6 +2,7 @@ use wasm_bindgen::prelude::*;
 use web_sys::{WebGl2RenderingContext, WebGlBuffer};
 
 pub struct BufferAttrib<'a> {
+  #[allow(dead_code)]
   pub name: String,
   pub buffer: &'a WebGlBuffer,
   pub target: u32,
@@ -10,6 +11,8 @@ pub struct BufferAttrib<'a> {
   pub normalize: bool,
   pub stride: i32,
   pub offset: i32,
+  /// `0` advances the attribute per vertex, `n` advances it once every `n` instances
+  pub divisor: u32,
 }
 
 pub(crate) fn bind_buffer_to_attrib(
@@ -28,6 +31,8 @@ pub(crate) fn bind_buffer_to_attrib(
     buffer_attrib.offset,
   );
 
+  gl_context.vertex_attrib_divisor(attribute, buffer_attrib.divisor);
+
   gl_context.enable_vertex_attrib_array(attribute);
 
   Ok(())
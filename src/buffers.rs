@@ -1,67 +1,42 @@
-use crate::utils::*;
-use std::collections::HashMap;
-use wasm_bindgen::prelude::*;
+use crate::error::DemoError;
 use web_sys::{WebGl2RenderingContext, WebGlBuffer};
 
-pub(crate) fn make_buffers(
+/// A buffer of `len` floats meant to be updated with `buffer_sub_data`
+pub(crate) fn init_dynamic_buffer(
   gl_context: &WebGl2RenderingContext,
-) -> Result<HashMap<String, WebGlBuffer>, JsValue> {
-  let mut buffers: HashMap<String, WebGlBuffer> = HashMap::new();
-
-  let target = WebGl2RenderingContext::ARRAY_BUFFER;
-  let usage = WebGl2RenderingContext::STATIC_DRAW;
-
-  // Create an array of position vertices for the square
-  let position_vertices = vec![-1.0, 1.0, 1.0, 1.0, -1.0, -1.0, 1.0, -1.0];
-
-  // Create an array of color vertices for the square
-  let color_vertices = vec![
-    1.0, 1.0, 1.0, 1.0, // white
-    1.0, 0.0, 0.0, 1.0, // red
-    0.0, 1.0, 0.0, 1.0, // green
-    0.0, 0.0, 1.0, 1.0, // blue
-  ];
-
-  let vertices_buffer = init_buffer(&gl_context, position_vertices, target, usage)?;
-  buffers.insert("vertices".into(), vertices_buffer);
-
-  let colors_buffer = init_buffer(&gl_context, color_vertices, target, usage)?;
-  buffers.insert("colors".into(), colors_buffer);
-
-  Ok(buffers)
+  len: usize,
+  target: u32,
+) -> Result<WebGlBuffer, DemoError> {
+  let buffer = gl_context
+    .create_buffer()
+    .ok_or_else(|| DemoError::BufferCreation("a dynamic buffer".to_string()))?;
+  gl_context.bind_buffer(target, Some(&buffer));
+  gl_context.buffer_data_with_i32(
+    target,
+    (len * std::mem::size_of::<f32>()) as i32,
+    WebGl2RenderingContext::DYNAMIC_DRAW,
+  );
+  Ok(buffer)
 }
 
-fn init_buffer(
+pub(crate) fn init_buffer(
   gl_context: &WebGl2RenderingContext,
-  vertices: Vec<f32>,
+  vertices: &[f32],
   target: u32,
   usage: u32,
-) -> Result<WebGlBuffer, String> {
-  // Create a buffer for the square's positions
-  let buffer = gl_context.create_buffer().ok_or("Failed to create position buffer")?;
+) -> Result<WebGlBuffer, DemoError> {
+  let buffer = gl_context
+    .create_buffer()
+    .ok_or_else(|| DemoError::BufferCreation("a vertex buffer".to_string()))?;
 
-  // Select the `vertices_buffer` as the on to apply buffer operations from here on out
+  // Select the new buffer as the one to apply buffer operations to from here on out
   gl_context.bind_buffer(target, Some(&buffer));
 
-  if vertices.len() == 8 {
-    let vertices_tmp: [f32; 8] = to_f32_8(vertices);
-    // Pass the list of vertices into WebGl to build the shape
-    unsafe {
-      let vertices_array = js_sys::Float32Array::view(&vertices_tmp);
-      gl_context.buffer_data_with_array_buffer_view(target, &vertices_array, usage);
-    }
-  } else if vertices.len() == 16 {
-    let vertices_tmp: [f32; 16] = to_f32_16(vertices);
-    // Pass the list of vertices into WebGl to build the shape
-    unsafe {
-      let vertices_array = js_sys::Float32Array::view(&vertices_tmp);
-      gl_context.buffer_data_with_array_buffer_view(target, &vertices_array, usage);
-    }
-  } else {
-    panic!(
-      "Expected a `vec` of length `8` or `16` but received a `vec` of length `{}`",
-      vertices.len()
-    );
+  // Pass the list of vertices into WebGl to build the shape. `view` is only valid until the next
+  // wasm allocation, which cannot happen before the upload.
+  unsafe {
+    let vertices_array = js_sys::Float32Array::view(vertices);
+    gl_context.buffer_data_with_array_buffer_view(target, &vertices_array, usage);
   }
 
   Ok(buffer)
@@ -0,0 +1,485 @@
+use crate::window;
+use nalgebra_glm::{self as glm, Mat4, Vec3};
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{HtmlCanvasElement, KeyboardEvent, MouseEvent, TouchEvent, WheelEvent};
+
+/// Radians of rotation per pixel of mouse or touch movement
+const LOOK_SENSITIVITY: f32 = 0.005;
+
+/// Keep the pitch away from the poles so `look_at` never sees `up` parallel to the view direction
+const MAX_PITCH: f32 = 1.55;
+
+/// Seconds it takes to travel between two saved keyframes
+const KEYFRAME_TRANSITION_SECONDS: f32 = 2.0;
+
+#[derive(Clone)]
+pub struct Camera {
+  pub position: Vec3,
+  pub target: Vec3,
+  pub up: Vec3,
+  /// Vertical field of view in radians
+  field_of_view: f32,
+  z_near: f32,
+  z_far: f32,
+  aspect: f32,
+  /// Applied after the projection to zoom in on one tile of the view, identity otherwise
+  tile: Mat4,
+  /// The projection only changes with the aspect, field of view or clip planes, so it is cached
+  projection: Option<Mat4>,
+}
+
+impl Camera {
+  pub fn new(position: Vec3, target: Vec3) -> Self {
+    Camera {
+      position,
+      target,
+      up: glm::vec3(0.0, 1.0, 0.0),
+      field_of_view: 45.0 * std::f32::consts::PI / 180.0,
+      z_near: 0.1,
+      z_far: 100.0,
+      aspect: 1.0,
+      tile: Mat4::identity(),
+      projection: None,
+    }
+  }
+
+  pub fn field_of_view(&self) -> f32 {
+    self.field_of_view
+  }
+
+  pub fn set_field_of_view(&mut self, field_of_view: f32) {
+    if field_of_view != self.field_of_view {
+      self.field_of_view = field_of_view;
+      self.projection = None;
+    }
+  }
+
+  pub fn set_aspect(&mut self, aspect: f32) {
+    if aspect != self.aspect && aspect.is_finite() && aspect > 0.0 {
+      self.aspect = aspect;
+      self.projection = None;
+    }
+  }
+
+  /// Render only part of the view, see `screenshot::tile_projection`
+  pub fn set_tile(&mut self, tile: Mat4) {
+    self.tile = tile;
+    self.projection = None;
+  }
+
+  pub fn view_matrix(&self) -> Mat4 {
+    glm::look_at(&self.position, &self.target, &self.up)
+  }
+
+  pub fn projection_matrix(&mut self) -> Mat4 {
+    let (aspect, field_of_view, z_near, z_far) =
+      (self.aspect, self.field_of_view, self.z_near, self.z_far);
+    let tile = self.tile;
+    *self
+      .projection
+      .get_or_insert_with(|| tile * glm::perspective(aspect, field_of_view, z_near, z_far))
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CameraMode {
+  Orbit,
+  Fly,
+  Fixed,
+}
+
+impl CameraMode {
+  fn next(self) -> Self {
+    match self {
+      CameraMode::Orbit => CameraMode::Fly,
+      CameraMode::Fly => CameraMode::Fixed,
+      CameraMode::Fixed => CameraMode::Orbit,
+    }
+  }
+}
+
+/// Camera shortcuts that work in every mode, on top of the fly keys
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RigCommand {
+  NextMode,
+  SaveKeyframe,
+  PrevKeyframe,
+  NextKeyframe,
+}
+
+impl RigCommand {
+  pub fn from_key(key: &str) -> Option<RigCommand> {
+    match key {
+      "v" | "V" => Some(RigCommand::NextMode),
+      "m" | "M" => Some(RigCommand::SaveKeyframe),
+      "[" => Some(RigCommand::PrevKeyframe),
+      "]" => Some(RigCommand::NextKeyframe),
+      _ => None,
+    }
+  }
+}
+
+/// Rotates the camera around a target point
+pub struct OrbitController {
+  pub target: Vec3,
+  pub yaw: f32,
+  pub pitch: f32,
+  pub distance: f32,
+}
+
+impl OrbitController {
+  pub fn from_camera(camera: &Camera) -> Self {
+    let offset = camera.position - camera.target;
+    let distance = glm::length(&offset).max(0.01);
+    OrbitController {
+      target: camera.target,
+      yaw: offset.x.atan2(offset.z),
+      pitch: (offset.y / distance).asin(),
+      distance,
+    }
+  }
+
+  pub fn rotate(&mut self, dx: f32, dy: f32) {
+    self.yaw -= dx * LOOK_SENSITIVITY;
+    self.pitch = (self.pitch + dy * LOOK_SENSITIVITY).clamp(-MAX_PITCH, MAX_PITCH);
+  }
+
+  /// Positive amounts move the camera away from the target
+  pub fn zoom(&mut self, amount: f32) {
+    self.distance = (self.distance * (1.0 + amount)).clamp(0.5, 50.0);
+  }
+
+  pub fn apply(&self, camera: &mut Camera) {
+    let offset = glm::vec3(
+      self.pitch.cos() * self.yaw.sin(),
+      self.pitch.sin(),
+      self.pitch.cos() * self.yaw.cos(),
+    );
+    camera.position = self.target + offset * self.distance;
+    camera.target = self.target;
+  }
+}
+
+/// First person camera moved with WASD/QE and steered with the mouse
+pub struct FlyController {
+  pub position: Vec3,
+  pub yaw: f32,
+  pub pitch: f32,
+  /// Units per second
+  pub speed: f32,
+  /// Held movement keys as (right, up, forward), each in `-1.0..=1.0`
+  movement: Vec3,
+}
+
+impl FlyController {
+  pub fn from_camera(camera: &Camera) -> Self {
+    let offset = camera.target - camera.position;
+    // A camera looking at its own position has no direction, face down -z like a new camera
+    let direction =
+      if glm::length(&offset) < 1e-6 { glm::vec3(0.0, 0.0, -1.0) } else { glm::normalize(&offset) };
+    FlyController {
+      position: camera.position,
+      yaw: (-direction.x).atan2(-direction.z),
+      pitch: direction.y.clamp(-1.0, 1.0).asin(),
+      speed: 3.0,
+      movement: glm::vec3(0.0, 0.0, 0.0),
+    }
+  }
+
+  fn forward(&self) -> Vec3 {
+    glm::vec3(
+      -self.yaw.sin() * self.pitch.cos(),
+      self.pitch.sin(),
+      -self.yaw.cos() * self.pitch.cos(),
+    )
+  }
+
+  pub fn look(&mut self, dx: f32, dy: f32) {
+    self.yaw -= dx * LOOK_SENSITIVITY;
+    self.pitch = (self.pitch - dy * LOOK_SENSITIVITY).clamp(-MAX_PITCH, MAX_PITCH);
+  }
+
+  /// Returns `false` if the key does not move the camera
+  pub fn set_key(&mut self, key: &str, pressed: bool) -> bool {
+    let amount = if pressed { 1.0 } else { 0.0 };
+    match key {
+      "w" | "W" => self.movement.z = amount,
+      "s" | "S" => self.movement.z = -amount,
+      "d" | "D" => self.movement.x = amount,
+      "a" | "A" => self.movement.x = -amount,
+      "e" | "E" => self.movement.y = amount,
+      "q" | "Q" => self.movement.y = -amount,
+      _ => return false,
+    }
+    true
+  }
+
+  pub fn update(&mut self, dt: f32, up: &Vec3) {
+    let forward = self.forward();
+    let right = glm::normalize(&glm::cross::<f32, glm::U3>(&forward, up));
+    let velocity = right * self.movement.x + up * self.movement.y + forward * self.movement.z;
+    self.position += velocity * self.speed * dt;
+  }
+
+  pub fn apply(&self, camera: &mut Camera) {
+    camera.position = self.position;
+    camera.target = self.position + self.forward();
+  }
+}
+
+/// A saved camera state to travel back to
+#[derive(Clone, Copy, Debug)]
+pub struct CameraKeyframe {
+  pub position: Vec3,
+  pub target: Vec3,
+  pub field_of_view: f32,
+}
+
+impl CameraKeyframe {
+  pub fn capture(camera: &Camera) -> Self {
+    CameraKeyframe {
+      position: camera.position,
+      target: camera.target,
+      field_of_view: camera.field_of_view,
+    }
+  }
+
+  /// Blend towards `other` with smoothstep easing, `t` is in `0.0..=1.0`
+  pub fn interpolate(&self, other: &CameraKeyframe, t: f32) -> CameraKeyframe {
+    let t = t.clamp(0.0, 1.0);
+    let eased = t * t * (3.0 - 2.0 * t);
+    CameraKeyframe {
+      position: glm::mix(&self.position, &other.position, eased),
+      target: glm::mix(&self.target, &other.target, eased),
+      field_of_view: self.field_of_view + (other.field_of_view - self.field_of_view) * eased,
+    }
+  }
+
+  pub fn apply(&self, camera: &mut Camera) {
+    camera.position = self.position;
+    camera.target = self.target;
+    camera.set_field_of_view(self.field_of_view);
+  }
+}
+
+struct KeyframeTransition {
+  from: CameraKeyframe,
+  to: CameraKeyframe,
+  elapsed: f32,
+  duration: f32,
+}
+
+/// The camera together with its controllers and saved keyframes
+pub struct CameraRig {
+  pub camera: Camera,
+  pub mode: CameraMode,
+  pub orbit: OrbitController,
+  pub fly: FlyController,
+  pub keyframes: Vec<CameraKeyframe>,
+  current_keyframe: usize,
+  transition: Option<KeyframeTransition>,
+}
+
+impl CameraRig {
+  pub fn new(camera: Camera) -> Self {
+    let orbit = OrbitController::from_camera(&camera);
+    let fly = FlyController::from_camera(&camera);
+    let keyframes = vec![CameraKeyframe::capture(&camera)];
+    CameraRig {
+      camera,
+      mode: CameraMode::Orbit,
+      orbit,
+      fly,
+      keyframes,
+      current_keyframe: 0,
+      transition: None,
+    }
+  }
+
+  pub fn set_mode(&mut self, mode: CameraMode) {
+    // Pick up from wherever the camera currently is so switching modes never jumps
+    self.orbit = OrbitController::from_camera(&self.camera);
+    self.fly = FlyController::from_camera(&self.camera);
+    self.mode = mode;
+  }
+
+  pub fn save_keyframe(&mut self) {
+    self.keyframes.push(CameraKeyframe::capture(&self.camera));
+    self.current_keyframe = self.keyframes.len() - 1;
+  }
+
+  /// Smoothly travel to the saved keyframe at `index`
+  pub fn go_to_keyframe(&mut self, index: usize) {
+    if let Some(to) = self.keyframes.get(index) {
+      self.transition = Some(KeyframeTransition {
+        from: CameraKeyframe::capture(&self.camera),
+        to: *to,
+        elapsed: 0.0,
+        duration: KEYFRAME_TRANSITION_SECONDS,
+      });
+      self.current_keyframe = index;
+    }
+  }
+
+  pub fn next_keyframe(&mut self) {
+    self.go_to_keyframe((self.current_keyframe + 1) % self.keyframes.len());
+  }
+
+  pub fn prev_keyframe(&mut self) {
+    let count = self.keyframes.len();
+    self.go_to_keyframe((self.current_keyframe + count - 1) % count);
+  }
+
+  /// Advance by `dt` seconds and write the result into `camera`
+  pub fn update(&mut self, dt: f32) {
+    if let Some(transition) = &mut self.transition {
+      transition.elapsed += dt;
+      let t = transition.elapsed / transition.duration;
+      transition.from.interpolate(&transition.to, t).apply(&mut self.camera);
+      if t >= 1.0 {
+        self.transition = None;
+        self.set_mode(self.mode);
+      }
+      return;
+    }
+
+    match self.mode {
+      CameraMode::Orbit => self.orbit.apply(&mut self.camera),
+      CameraMode::Fly => {
+        self.fly.update(dt, &self.camera.up);
+        self.fly.apply(&mut self.camera);
+      }
+      CameraMode::Fixed => {}
+    }
+  }
+
+  fn drag(&mut self, dx: f32, dy: f32) {
+    match self.mode {
+      CameraMode::Orbit => self.orbit.rotate(dx, dy),
+      CameraMode::Fly => self.fly.look(dx, dy),
+      CameraMode::Fixed => {}
+    }
+  }
+
+  fn zoom(&mut self, amount: f32) {
+    if self.mode == CameraMode::Orbit {
+      self.orbit.zoom(amount);
+    }
+  }
+}
+
+/// Drive the camera rig from mouse, keyboard and touch input on the canvas
+pub(crate) fn init_controls(
+  canvas: &HtmlCanvasElement,
+  rig: Rc<RefCell<CameraRig>>,
+) -> Result<(), JsValue> {
+  // Mouse drag
+  {
+    let rig = rig.clone();
+    let closure = Closure::wrap(Box::new(move |event: MouseEvent| {
+      if event.buttons() & 1 == 1 {
+        rig.borrow_mut().drag(event.movement_x() as f32, event.movement_y() as f32);
+      }
+    }) as Box<dyn FnMut(MouseEvent)>);
+    canvas.add_event_listener_with_callback("mousemove", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  // Mouse wheel zoom
+  {
+    let rig = rig.clone();
+    let closure = Closure::wrap(Box::new(move |event: WheelEvent| {
+      event.prevent_default();
+      rig.borrow_mut().zoom((event.delta_y().signum() * 0.1) as f32);
+    }) as Box<dyn FnMut(WheelEvent)>);
+    canvas.add_event_listener_with_callback("wheel", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  // One finger drags, two fingers pinch to zoom
+  {
+    let rig = rig.clone();
+    let last_touches: Rc<RefCell<Vec<(f32, f32)>>> = Rc::new(RefCell::new(Vec::new()));
+    let closure = Closure::wrap(Box::new(move |event: TouchEvent| {
+      event.prevent_default();
+      let touch_list = event.touches();
+      let touches: Vec<(f32, f32)> = (0..touch_list.length())
+        .filter_map(|i| touch_list.get(i))
+        .map(|touch| (touch.client_x() as f32, touch.client_y() as f32))
+        .collect();
+      let mut last = last_touches.borrow_mut();
+      if event.type_() == "touchmove" && touches.len() == last.len() {
+        match touches.len() {
+          1 => rig.borrow_mut().drag(touches[0].0 - last[0].0, touches[0].1 - last[0].1),
+          2 => {
+            let spread = |t: &[(f32, f32)]| (t[0].0 - t[1].0).hypot(t[0].1 - t[1].1).max(1.0);
+            rig.borrow_mut().zoom(spread(&last) / spread(&touches) - 1.0);
+          }
+          _ => {}
+        }
+      }
+      *last = touches;
+    }) as Box<dyn FnMut(TouchEvent)>);
+    for event_type in &["touchstart", "touchmove", "touchend"] {
+      canvas.add_event_listener_with_callback(event_type, closure.as_ref().unchecked_ref())?;
+    }
+    closure.forget();
+  }
+
+  // Keyboard: WASD/QE to fly, `v` to cycle modes, `m` to save a keyframe, `[`/`]` to travel
+  {
+    let rig = rig.clone();
+    let closure = Closure::wrap(Box::new(move |event: KeyboardEvent| {
+      let mut rig = rig.borrow_mut();
+      let key = event.key();
+      if event.type_() == "keyup" {
+        rig.fly.set_key(&key, false);
+        return;
+      }
+      if rig.mode == CameraMode::Fly && rig.fly.set_key(&key, true) {
+        return;
+      }
+      match RigCommand::from_key(&key) {
+        Some(RigCommand::NextMode) => {
+          let mode = rig.mode.next();
+          rig.set_mode(mode);
+        }
+        Some(RigCommand::SaveKeyframe) => rig.save_keyframe(),
+        Some(RigCommand::PrevKeyframe) => rig.prev_keyframe(),
+        Some(RigCommand::NextKeyframe) => rig.next_keyframe(),
+        None => {}
+      }
+    }) as Box<dyn FnMut(KeyboardEvent)>);
+    window().add_event_listener_with_callback("keydown", closure.as_ref().unchecked_ref())?;
+    window().add_event_listener_with_callback("keyup", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn controllers_pick_up_where_the_camera_looks() {
+    let camera = Camera::new(glm::vec3(0.0, 0.0, 5.0), glm::vec3(0.0, 0.0, 0.0));
+    let fly = FlyController::from_camera(&camera);
+    assert!(glm::distance(&fly.forward(), &glm::vec3(0.0, 0.0, -1.0)) < 1e-6);
+    let orbit = OrbitController::from_camera(&camera);
+    let mut moved = camera.clone();
+    orbit.apply(&mut moved);
+    assert!(glm::distance(&moved.position, &camera.position) < 1e-5);
+  }
+
+  #[test]
+  fn a_camera_looking_at_itself_stays_finite() {
+    let camera = Camera::new(glm::vec3(1.0, 2.0, 3.0), glm::vec3(1.0, 2.0, 3.0));
+    let fly = FlyController::from_camera(&camera);
+    assert!(fly.yaw.is_finite() && fly.pitch.is_finite());
+    let orbit = OrbitController::from_camera(&camera);
+    assert!(orbit.yaw.is_finite() && orbit.pitch.is_finite());
+  }
+}
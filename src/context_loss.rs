@@ -0,0 +1,37 @@
+use std::{cell::Cell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{Event, HtmlCanvasElement};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContextStatus {
+  Live,
+  /// Nothing can be drawn, every GPU object is gone
+  Lost,
+  /// Drawing works again, but everything has to be created anew first
+  Restored,
+}
+
+/// Follows the WebGL context of `canvas` through `webglcontextlost` and `webglcontextrestored`
+pub(crate) fn init(canvas: &HtmlCanvasElement) -> Result<Rc<Cell<ContextStatus>>, JsValue> {
+  let status = Rc::new(Cell::new(ContextStatus::Live));
+
+  let lost_status = status.clone();
+  let closure = Closure::wrap(Box::new(move |event: Event| {
+    // Without this the browser never tries to restore the context
+    event.prevent_default();
+    web_sys::console::warn_1(&"WebGL context lost, waiting for it to be restored".into());
+    lost_status.set(ContextStatus::Lost);
+  }) as Box<dyn FnMut(Event)>);
+  canvas.add_event_listener_with_callback("webglcontextlost", closure.as_ref().unchecked_ref())?;
+  closure.forget();
+
+  let restored_status = status.clone();
+  let closure = Closure::wrap(Box::new(move || {
+    restored_status.set(ContextStatus::Restored);
+  }) as Box<dyn FnMut()>);
+  canvas
+    .add_event_listener_with_callback("webglcontextrestored", closure.as_ref().unchecked_ref())?;
+  closure.forget();
+
+  Ok(status)
+}
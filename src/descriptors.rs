@@ -0,0 +1,441 @@
+use std::{collections::VecDeque, f32::consts::PI};
+
+/// Lowest frequency the log scale of `centroid` and `rolloff` starts from
+const MIN_FREQUENCY: f32 = 20.0;
+/// Share of the spectral energy below the rolloff frequency
+const ROLLOFF_FRACTION: f32 = 0.85;
+/// Crest factor read as `1.0`, well past any music but a click
+const MAX_CREST_DB: f32 = 20.0;
+/// Loudness read as `0.0`, the quietest a visual could sensibly react to
+const LOUDNESS_FLOOR: f32 = -60.0;
+/// What the meter reports for silence, the absolute gate of EBU R128
+const SILENCE_LUFS: f32 = -70.0;
+/// Seconds of a gating block step, and the blocks the momentary and short-term windows span
+const BLOCK_SECONDS: f64 = 0.1;
+const MOMENTARY_BLOCKS: usize = 4;
+const SHORT_TERM_BLOCKS: usize = 30;
+
+/// Perceptual features of the latest block, each in `0.0..=1.0` so they can go straight to a
+/// shader or a route
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Descriptors {
+  /// Brightness: where the spectrum's centre of mass lies, on a log scale from 20 Hz to Nyquist
+  pub centroid: f32,
+  /// Noisiness: `0.0` for a pure tone, towards `1.0` for white noise
+  pub flatness: f32,
+  /// Where 85 % of the energy is below, on the same scale as `centroid`
+  pub rolloff: f32,
+  /// How much of the spectrum rose since the previous block
+  pub flux: f32,
+  /// Peak over RMS from 0 dB to `MAX_CREST_DB`, high for spiky sounds, low for compressed ones
+  pub crest: f32,
+  /// EBU R128 loudness over the last 400 ms and 3 s, from `LOUDNESS_FLOOR` to 0 LUFS
+  pub momentary: f32,
+  pub short_term: f32,
+  /// The same loudness in LUFS
+  pub momentary_lufs: f32,
+  pub short_term_lufs: f32,
+}
+
+/// Magnitudes of the positive frequencies of `samples` under a Hann window, `n / 2` bins of
+/// `sample_rate / n` Hz where `n` is the largest power of two that fits
+pub fn magnitude_spectrum(samples: &[f32]) -> Vec<f32> {
+  let length = match samples.len() {
+    0 => return Vec::new(),
+    length => 1 << (usize::BITS - 1 - length.leading_zeros()),
+  };
+  let samples = &samples[samples.len() - length..];
+  let mut real: Vec<f32> = samples
+    .iter()
+    .enumerate()
+    .map(|(index, sample)| sample * (0.5 - 0.5 * (2.0 * PI * index as f32 / length as f32).cos()))
+    .collect();
+  let mut imaginary = vec![0.0; length];
+
+  // Iterative radix-2 FFT, reordered by bit reversal first
+  let mut reversed = 0;
+  for index in 1..length {
+    let mut bit = length >> 1;
+    while reversed & bit != 0 {
+      reversed ^= bit;
+      bit >>= 1;
+    }
+    reversed |= bit;
+    if index < reversed {
+      real.swap(index, reversed);
+    }
+  }
+  let mut size = 2;
+  while size <= length {
+    let angle = -2.0 * PI / size as f32;
+    for start in (0..length).step_by(size) {
+      for offset in 0..size / 2 {
+        let (sin, cos) = (angle * offset as f32).sin_cos();
+        let (even, odd) = (start + offset, start + offset + size / 2);
+        let odd_real = real[odd] * cos - imaginary[odd] * sin;
+        let odd_imaginary = real[odd] * sin + imaginary[odd] * cos;
+        real[odd] = real[even] - odd_real;
+        imaginary[odd] = imaginary[even] - odd_imaginary;
+        real[even] += odd_real;
+        imaginary[even] += odd_imaginary;
+      }
+    }
+    size <<= 1;
+  }
+
+  (0..length / 2)
+    .map(|bin| (real[bin] * real[bin] + imaginary[bin] * imaginary[bin]).sqrt())
+    .collect()
+}
+
+/// Magnitude weighted mean frequency in Hz, `0.0` for silence
+pub fn centroid(magnitudes: &[f32], bin_hz: f32) -> f32 {
+  let total: f32 = magnitudes.iter().sum();
+  if total <= 0.0 {
+    return 0.0;
+  }
+  magnitudes.iter().enumerate().map(|(bin, magnitude)| bin as f32 * bin_hz * magnitude).sum::<f32>()
+    / total
+}
+
+/// Geometric over arithmetic mean of the power spectrum, DC left out. `0.0` for silence.
+pub fn flatness(magnitudes: &[f32]) -> f32 {
+  let powers = magnitudes.iter().skip(1).map(|magnitude| magnitude * magnitude);
+  let count = magnitudes.len().saturating_sub(1) as f32;
+  let mean = powers.clone().sum::<f32>() / count;
+  if mean <= 0.0 {
+    return 0.0;
+  }
+  // In logs, a single silent bin would make the product zero
+  let log_mean =
+    powers.map(|power| (power.max(f32::MIN_POSITIVE) as f64).ln()).sum::<f64>() / count as f64;
+  (log_mean.exp() as f32 / mean).clamp(0.0, 1.0)
+}
+
+/// Frequency in Hz below which `fraction` of the spectral energy lies
+pub fn rolloff(magnitudes: &[f32], bin_hz: f32, fraction: f32) -> f32 {
+  let total: f32 = magnitudes.iter().map(|magnitude| magnitude * magnitude).sum();
+  let mut below = 0.0;
+  for (bin, magnitude) in magnitudes.iter().enumerate() {
+    below += magnitude * magnitude;
+    if below >= fraction * total {
+      return bin as f32 * bin_hz;
+    }
+  }
+  0.0
+}
+
+/// Rise of the spectrum since `previous` as a share of its magnitude now: `0.0` when nothing got
+/// louder, `1.0` when everything is new
+pub fn flux(previous: &[f32], current: &[f32]) -> f32 {
+  let total: f32 = current.iter().sum();
+  if total <= 0.0 {
+    return 0.0;
+  }
+  let rise: f32 = current
+    .iter()
+    .enumerate()
+    .map(|(bin, magnitude)| (magnitude - previous.get(bin).copied().unwrap_or(0.0)).max(0.0))
+    .sum();
+  rise / total
+}
+
+/// Peak over RMS, `1.0` for a square wave, about `1.41` for a sine and `1.0` for silence
+pub fn crest_factor(samples: &[f32]) -> f32 {
+  let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+  let power =
+    samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len().max(1) as f32;
+  if power > 0.0 {
+    peak / power.sqrt()
+  } else {
+    1.0
+  }
+}
+
+/// `hz` on a log scale from `MIN_FREQUENCY` at `0.0` to `nyquist` at `1.0`
+fn log_frequency(hz: f32, nyquist: f32) -> f32 {
+  if hz <= MIN_FREQUENCY {
+    return 0.0;
+  }
+  ((hz / MIN_FREQUENCY).log2() / (nyquist / MIN_FREQUENCY).log2()).clamp(0.0, 1.0)
+}
+
+/// A biquad in direct form I, in `f64` since the high-pass sits close to the unit circle
+#[derive(Clone, Debug)]
+struct Biquad {
+  b: [f64; 3],
+  a: [f64; 2],
+  inputs: [f64; 2],
+  outputs: [f64; 2],
+}
+
+impl Biquad {
+  fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+    Biquad { b, a, inputs: [0.0; 2], outputs: [0.0; 2] }
+  }
+
+  fn process(&mut self, input: f64) -> f64 {
+    let output = self.b[0] * input + self.b[1] * self.inputs[0] + self.b[2] * self.inputs[1]
+      - self.a[0] * self.outputs[0]
+      - self.a[1] * self.outputs[1];
+    self.inputs = [input, self.inputs[0]];
+    self.outputs = [output, self.outputs[0]];
+    output
+  }
+}
+
+/// The K-weighting of ITU-R BS.1770: a shelf boosting what the head amplifies, then a high-pass
+/// for what the ear hardly hears. The coefficients are derived for any rate, at 48 kHz they are
+/// the ones the recommendation lists.
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+  let (frequency, gain, quality) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+  let k = (std::f64::consts::PI * frequency / sample_rate).tan();
+  let high = 10f64.powf(gain / 20.0);
+  let band = high.powf(0.4996667741545416);
+  let a0 = 1.0 + k / quality + k * k;
+  let shelf = Biquad::new(
+    [
+      (high + band * k / quality + k * k) / a0,
+      2.0 * (k * k - high) / a0,
+      (high - band * k / quality + k * k) / a0,
+    ],
+    [2.0 * (k * k - 1.0) / a0, (1.0 - k / quality + k * k) / a0],
+  );
+
+  let (frequency, quality) = (38.13547087602444, 0.5003270373238773);
+  let k = (std::f64::consts::PI * frequency / sample_rate).tan();
+  let a0 = 1.0 + k / quality + k * k;
+  let high_pass =
+    Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / quality + k * k) / a0]);
+  [shelf, high_pass]
+}
+
+/// Loudness after EBU R128: K-weighted mean square summed over the channels, in 100 ms blocks.
+/// Every sample has to be pushed exactly once, in order.
+pub struct LoudnessMeter {
+  filters: Vec<[Biquad; 2]>,
+  block_length: usize,
+  /// Weighted energy and samples of the block being filled
+  block_energy: f64,
+  block_samples: usize,
+  /// Mean square of the latest `SHORT_TERM_BLOCKS`, newest last, silence before the start
+  blocks: VecDeque<f64>,
+}
+
+impl LoudnessMeter {
+  pub fn new(sample_rate: f32, channels: usize) -> Self {
+    let sample_rate = sample_rate as f64;
+    LoudnessMeter {
+      filters: (0..channels).map(|_| k_weighting(sample_rate)).collect(),
+      block_length: (sample_rate * BLOCK_SECONDS).round() as usize,
+      block_energy: 0.0,
+      block_samples: 0,
+      blocks: std::iter::repeat_n(0.0, SHORT_TERM_BLOCKS).collect(),
+    }
+  }
+
+  /// Meter the next samples of every channel, all as long as the shortest. Front channels are
+  /// weighted alike, so these are left and right, or a single mono channel.
+  pub fn push(&mut self, channels: &[&[f32]]) {
+    let length = channels.iter().map(|channel| channel.len()).min().unwrap_or(0);
+    for index in 0..length {
+      for (channel, filters) in channels.iter().zip(&mut self.filters) {
+        let [shelf, high_pass] = filters;
+        let weighted = high_pass.process(shelf.process(channel[index] as f64));
+        self.block_energy += weighted * weighted;
+      }
+      self.block_samples += 1;
+      if self.block_samples == self.block_length {
+        self.blocks.pop_front();
+        self.blocks.push_back(self.block_energy / self.block_length as f64);
+        self.block_energy = 0.0;
+        self.block_samples = 0;
+      }
+    }
+  }
+
+  /// Loudness over the last 400 ms, in LUFS
+  pub fn momentary(&self) -> f32 {
+    self.loudness(MOMENTARY_BLOCKS)
+  }
+
+  /// Loudness over the last 3 s, in LUFS
+  pub fn short_term(&self) -> f32 {
+    self.loudness(SHORT_TERM_BLOCKS)
+  }
+
+  fn loudness(&self, blocks: usize) -> f32 {
+    let power = self.blocks.iter().rev().take(blocks).sum::<f64>() / blocks as f64;
+    if power <= 0.0 {
+      return SILENCE_LUFS;
+    }
+    ((-0.691 + 10.0 * power.log10()) as f32).max(SILENCE_LUFS)
+  }
+}
+
+/// Follows the descriptors from block to block
+pub struct DescriptorTracker {
+  previous: Vec<f32>,
+  loudness: LoudnessMeter,
+}
+
+impl DescriptorTracker {
+  pub fn new(sample_rate: f32) -> Self {
+    DescriptorTracker { previous: Vec::new(), loudness: LoudnessMeter::new(sample_rate, 2) }
+  }
+
+  /// Describe the block of mono `samples`, metering the loudness of the left and right samples
+  /// that arrived since the previous call
+  pub fn update(
+    &mut self,
+    samples: &[f32],
+    fresh: [&[f32]; 2],
+    sample_rate: f32,
+    descriptors: &mut Descriptors,
+  ) {
+    let magnitudes = magnitude_spectrum(samples);
+    let bin_hz = sample_rate / (2 * magnitudes.len()).max(1) as f32;
+    let nyquist = sample_rate / 2.0;
+    descriptors.centroid = log_frequency(centroid(&magnitudes, bin_hz), nyquist);
+    descriptors.flatness = flatness(&magnitudes);
+    descriptors.rolloff = log_frequency(rolloff(&magnitudes, bin_hz, ROLLOFF_FRACTION), nyquist);
+    descriptors.flux = flux(&self.previous, &magnitudes);
+    descriptors.crest = (20.0 * crest_factor(samples).log10() / MAX_CREST_DB).clamp(0.0, 1.0);
+    self.previous = magnitudes;
+
+    self.loudness.push(&fresh);
+    descriptors.momentary_lufs = self.loudness.momentary();
+    descriptors.short_term_lufs = self.loudness.short_term();
+    let normalise = |lufs: f32| ((lufs - LOUDNESS_FLOOR) / -LOUDNESS_FLOOR).clamp(0.0, 1.0);
+    descriptors.momentary = normalise(descriptors.momentary_lufs);
+    descriptors.short_term = normalise(descriptors.short_term_lufs);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::{assert_close, noise, sine};
+
+  #[test]
+  fn k_weighting_matches_bs_1770_at_48_khz() {
+    let [shelf, high_pass] = k_weighting(48000.0);
+    let expected_shelf = [1.53512485958697, -2.69169618940638, 1.19839281085285];
+    for (actual, expected) in shelf.b.iter().zip(expected_shelf) {
+      assert_close(*actual, expected, 1e-9);
+    }
+    for (actual, expected) in shelf.a.iter().zip([-1.69065929318241, 0.73248077421585]) {
+      assert_close(*actual, expected, 1e-9);
+    }
+    assert_eq!(high_pass.b, [1.0, -2.0, 1.0]);
+    for (actual, expected) in high_pass.a.iter().zip([-1.99004745483398, 0.99007225036621]) {
+      assert_close(*actual, expected, 1e-9);
+    }
+  }
+
+  /// EBU Tech 3341 cases 1 and 2: a 1 kHz sine at -23 and -33 dBFS on both channels reads -23 and
+  /// -33 LUFS, at either common rate
+  #[test]
+  fn meters_the_tech_3341_sines() {
+    for sample_rate in [44100.0, 48000.0] {
+      for level in [-23.0, -33.0] {
+        let amplitude = 10f32.powf(level / 20.0);
+        let tone = sine(1000.0, amplitude, sample_rate, (sample_rate * 4.0) as usize);
+        let mut meter = LoudnessMeter::new(sample_rate, 2);
+        // In uneven pieces, like animation frames deliver them
+        for piece in tone.chunks(735) {
+          meter.push(&[piece, piece]);
+        }
+        assert_close(meter.momentary() as f64, level as f64, 0.1);
+        assert_close(meter.short_term() as f64, level as f64, 0.1);
+      }
+    }
+  }
+
+  #[test]
+  fn loudness_ignores_what_the_ear_does_not_hear() {
+    let sample_rate = 48000.0;
+    let meter = |frequency: f32| {
+      let mut meter = LoudnessMeter::new(sample_rate, 1);
+      meter.push(&[&sine(frequency, 0.5, sample_rate, 48000)]);
+      meter.momentary()
+    };
+    // The high-pass takes two octaves below its corner down by over 20 dB
+    assert!(meter(1000.0) - meter(10.0) > 20.0, "{} {}", meter(1000.0), meter(10.0));
+
+    let silent = LoudnessMeter::new(sample_rate, 2);
+    assert_eq!(silent.momentary(), SILENCE_LUFS);
+  }
+
+  #[test]
+  fn spectrum_finds_a_tone_in_its_bin() {
+    let sample_rate = 44100.0;
+    let bin_hz = sample_rate / 2048.0;
+    let magnitudes = magnitude_spectrum(&sine(100.0 * bin_hz, 1.0, sample_rate, 2048));
+    assert_eq!(magnitudes.len(), 1024);
+    let strongest = (0..1024).max_by(|&a, &b| magnitudes[a].total_cmp(&magnitudes[b])).unwrap();
+    assert_eq!(strongest, 100);
+    // A Hann window halves the amplitude, spread over n / 2 for a real sine
+    assert_close(magnitudes[100] as f64, 2048.0 / 4.0, 1.0);
+
+    assert_close(centroid(&magnitudes, bin_hz) as f64, (100.0 * bin_hz) as f64, bin_hz as f64);
+    assert_close(rolloff(&magnitudes, bin_hz, 0.85) as f64, (100.0 * bin_hz) as f64, bin_hz as f64);
+  }
+
+  #[test]
+  fn flatness_tells_tones_from_noise() {
+    let tone = magnitude_spectrum(&sine(1000.0, 1.0, 44100.0, 2048));
+    assert!(flatness(&tone) < 0.01, "{}", flatness(&tone));
+
+    let noise = magnitude_spectrum(&noise(2048));
+    // Gaussian-like noise has an expected power flatness of exp(-γ), about 0.56
+    assert!(flatness(&noise) > 0.4, "{}", flatness(&noise));
+    assert_eq!(flatness(&[0.0; 16]), 0.0);
+  }
+
+  #[test]
+  fn flux_only_counts_rises() {
+    let quiet = [0.0, 1.0, 1.0, 0.0];
+    let loud = [0.0, 1.0, 3.0, 4.0];
+    assert_eq!(flux(&quiet, &quiet), 0.0);
+    assert_eq!(flux(&loud, &quiet), 0.0);
+    assert_close(flux(&quiet, &loud) as f64, 6.0 / 8.0, 1e-6);
+    assert_eq!(flux(&[], &loud), 1.0);
+  }
+
+  #[test]
+  fn crest_factors_of_reference_waves() {
+    // Exactly 44 periods
+    let tone = sine(441.0, 0.5, 44100.0, 4400);
+    assert_close(crest_factor(&tone) as f64, std::f64::consts::SQRT_2, 1e-3);
+    let square: Vec<f32> = tone.iter().map(|sample| 0.5f32.copysign(*sample)).collect();
+    assert_close(crest_factor(&square) as f64, 1.0, 1e-6);
+    assert_eq!(crest_factor(&[0.0; 8]), 1.0);
+  }
+
+  #[test]
+  fn descriptors_are_normalised() {
+    let sample_rate = 48000.0;
+    let tone = sine(1000.0, 10f32.powf(-23.0 / 20.0), sample_rate, 48000);
+    let mut tracker = DescriptorTracker::new(sample_rate);
+    let mut descriptors = Descriptors::default();
+    for piece in tone.chunks(800) {
+      tracker.update(&tone[..2048], [piece, piece], sample_rate, &mut descriptors);
+    }
+    for value in [
+      descriptors.centroid,
+      descriptors.flatness,
+      descriptors.rolloff,
+      descriptors.flux,
+      descriptors.crest,
+      descriptors.momentary,
+      descriptors.short_term,
+    ] {
+      assert!((0.0..=1.0).contains(&value), "{:?}", descriptors);
+    }
+    // 1 kHz sits about 55 % up the log scale from 20 Hz to 24 kHz
+    assert_close(descriptors.centroid as f64, 0.552, 0.01);
+    assert_eq!(descriptors.flux, 0.0);
+    assert_close(descriptors.momentary as f64, (60.0 - 23.0) / 60.0, 0.01);
+  }
+}
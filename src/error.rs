@@ -0,0 +1,221 @@
+use crate::{preset::PresetError, window};
+use std::fmt;
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::WebGl2RenderingContext;
+
+/// A `<div>` laid over the canvas, added when the page does not have one
+const ERROR_PANEL: &str = "error";
+
+/// Everything that can keep the demo from running
+#[derive(Clone, Debug)]
+pub enum DemoError {
+  /// The browser supports WebGL 2 but would not hand out a context, e.g. with a blocklisted GPU
+  ContextCreation(String),
+  /// The WebGL context went away, everything on the GPU has to be made again once it is back
+  ContextLost,
+  /// `stage` is `vertex` or `fragment`, `log` what the driver reported
+  ShaderCompile {
+    stage: &'static str,
+    log: String,
+  },
+  ProgramLink(String),
+  /// An attribute the code binds that the shader does not declare, or optimised away
+  MissingAttribute(String),
+  MissingUniform(String),
+  BufferCreation(String),
+  /// WebGL returned null for a live context, like a vertex array or framebuffer
+  ObjectCreation(String),
+  /// A render target the driver will not draw into, with the framebuffer status it reported
+  IncompleteFramebuffer(u32),
+  /// Something the page was expected to have, like the canvas or the music track
+  MissingElement(String),
+  /// A name that does not refer to anything, like the mesh of a node or the program of a material
+  UnknownReference(String),
+  /// The microphone was refused, by the user or by a permissions policy
+  AudioPermissionDenied(String),
+  /// A browser API the demo needs is not there, like WebGL 2 or `getUserMedia`
+  UnsupportedFeature(String),
+  InvalidPreset(PresetError),
+  /// Anything else a browser API threw, passed through as is
+  Js(JsValue),
+}
+
+impl DemoError {
+  /// Stable name of the variant, set as `kind` on the errors handed to JS
+  pub fn kind(&self) -> &'static str {
+    match self {
+      DemoError::ContextCreation(_) => "ContextCreation",
+      DemoError::ContextLost => "ContextLost",
+      DemoError::ShaderCompile { .. } => "ShaderCompile",
+      DemoError::ProgramLink(_) => "ProgramLink",
+      DemoError::MissingAttribute(_) => "MissingAttribute",
+      DemoError::MissingUniform(_) => "MissingUniform",
+      DemoError::BufferCreation(_) => "BufferCreation",
+      DemoError::ObjectCreation(_) => "ObjectCreation",
+      DemoError::IncompleteFramebuffer(_) => "IncompleteFramebuffer",
+      DemoError::MissingElement(_) => "MissingElement",
+      DemoError::UnknownReference(_) => "UnknownReference",
+      DemoError::AudioPermissionDenied(_) => "AudioPermissionDenied",
+      DemoError::UnsupportedFeature(_) => "UnsupportedFeature",
+      DemoError::InvalidPreset(_) => "InvalidPreset",
+      DemoError::Js(_) => "Js",
+    }
+  }
+}
+
+impl fmt::Display for DemoError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DemoError::ContextCreation(reason) => {
+        write!(f, "Failed to create a WebGL 2 context: {}", reason)
+      }
+      DemoError::ContextLost => f.write_str("The WebGL context was lost"),
+      DemoError::ShaderCompile { stage, log } => {
+        write!(f, "Failed to compile the {} shader: {}", stage, log)
+      }
+      DemoError::ProgramLink(log) => write!(f, "Failed to link the shader program: {}", log),
+      DemoError::MissingAttribute(name) => write!(f, "The shader has no `{}` attribute", name),
+      DemoError::MissingUniform(name) => write!(f, "The shader has no `{}` uniform", name),
+      DemoError::BufferCreation(what) | DemoError::ObjectCreation(what) => {
+        write!(f, "Failed to create {}", what)
+      }
+      DemoError::IncompleteFramebuffer(status) => {
+        write!(f, "The render target is incomplete, status `{:#x}`", status)
+      }
+      DemoError::MissingElement(what) => write!(f, "Failed to find {}", what),
+      DemoError::UnknownReference(what) => write!(f, "Unknown {}", what),
+      DemoError::AudioPermissionDenied(reason) => {
+        write!(f, "Microphone access was denied: {}", reason)
+      }
+      DemoError::UnsupportedFeature(feature) => {
+        write!(f, "This browser does not support {}", feature)
+      }
+      DemoError::InvalidPreset(err) => err.fmt(f),
+      DemoError::Js(value) => match value.as_string() {
+        Some(message) => f.write_str(&message),
+        None => write!(f, "{:?}", value),
+      },
+    }
+  }
+}
+
+impl From<JsValue> for DemoError {
+  fn from(value: JsValue) -> Self {
+    DemoError::Js(value)
+  }
+}
+
+impl From<PresetError> for DemoError {
+  fn from(err: PresetError) -> Self {
+    DemoError::InvalidPreset(err)
+  }
+}
+
+/// What a WebGL `create_*` call returned, which is null once the context is lost and, rarely,
+/// when the driver runs out of objects
+pub(crate) fn created<T>(
+  gl_context: &WebGl2RenderingContext,
+  object: Option<T>,
+  what: &str,
+) -> Result<T, DemoError> {
+  object.ok_or_else(|| lost_or(gl_context, || DemoError::ObjectCreation(what.to_string())))
+}
+
+/// `ContextLost` if that is why a WebGL call failed, `err` otherwise
+pub(crate) fn lost_or(
+  gl_context: &WebGl2RenderingContext,
+  err: impl FnOnce() -> DemoError,
+) -> DemoError {
+  if gl_context.is_context_lost() {
+    DemoError::ContextLost
+  } else {
+    err()
+  }
+}
+
+/// An `Error` named `DemoError`, with the variant as `kind` and a `path` for preset errors, so
+/// callers can tell failures apart without parsing messages. Browser errors pass through as is.
+impl From<DemoError> for JsValue {
+  fn from(err: DemoError) -> Self {
+    if let DemoError::Js(value) = err {
+      return value;
+    }
+    let error = js_sys::Error::new(&err.to_string());
+    error.set_name("DemoError");
+    let _ = js_sys::Reflect::set(&error, &"kind".into(), &err.kind().into());
+    if let DemoError::InvalidPreset(preset_err) = &err {
+      let _ = js_sys::Reflect::set(&error, &"path".into(), &preset_err.path.as_str().into());
+    }
+    error.into()
+  }
+}
+
+/// Tell the audience why the canvas stays dark, instead of leaving them with a blank page
+pub(crate) fn show(err: &JsValue) {
+  let message = match err.dyn_ref::<js_sys::Error>() {
+    Some(error) => String::from(error.message()),
+    None => err.as_string().unwrap_or_else(|| format!("{:?}", err)),
+  };
+
+  let document = match window().document() {
+    Some(document) => document,
+    None => return,
+  };
+  let panel = match document.get_element_by_id(ERROR_PANEL) {
+    Some(panel) => panel,
+    None => {
+      let panel = match document.create_element("div") {
+        Ok(panel) => panel,
+        Err(_) => return,
+      };
+      panel.set_id(ERROR_PANEL);
+      let _ = panel.set_attribute("role", "alert");
+      match document.body() {
+        Some(body) if body.append_child(&panel).is_ok() => panel,
+        _ => return,
+      }
+    }
+  };
+  panel.set_text_content(Some(&message));
+  if let Some(kind) =
+    js_sys::Reflect::get(err, &"kind".into()).ok().and_then(|kind| kind.as_string())
+  {
+    let _ = panel.set_attribute("data-kind", &kind);
+  }
+  let _ = panel.remove_attribute("hidden");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn variants_name_what_failed() {
+    let cases = [
+      (DemoError::ContextLost, "ContextLost", "The WebGL context was lost"),
+      (
+        DemoError::ObjectCreation("a framebuffer".to_string()),
+        "ObjectCreation",
+        "Failed to create a framebuffer",
+      ),
+      (
+        DemoError::IncompleteFramebuffer(0x8cd6),
+        "IncompleteFramebuffer",
+        "The render target is incomplete, status `0x8cd6`",
+      ),
+      (
+        DemoError::MissingElement("the canvas".to_string()),
+        "MissingElement",
+        "Failed to find the canvas",
+      ),
+      (
+        DemoError::UnknownReference("mesh `quad` of node `floor`".to_string()),
+        "UnknownReference",
+        "Unknown mesh `quad` of node `floor`",
+      ),
+    ];
+    for (err, kind, message) in cases {
+      assert_eq!((err.kind(), err.to_string().as_str()), (kind, message));
+    }
+  }
+}
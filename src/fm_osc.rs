@@ -6,6 +6,17 @@ pub fn midi_to_freq(note: u8) -> f32 {
   27.5 * 2f32.powf((note as f32 - 21.0) / 12.0)
 }
 
+/// The midi note nearest to `freq` and how far off `freq` is in cents, in `-50.0..=50.0`. `None`
+/// past the notes 0 to 127.
+pub fn freq_to_midi(freq: f32) -> Option<(u8, f32)> {
+  let note = 21.0 + 12.0 * (freq / 27.5).log2();
+  if !(-0.5..127.5).contains(&note) {
+    return None;
+  }
+  let nearest = note.round();
+  Some((nearest as u8, (note - nearest) * 100.0))
+}
+
 #[wasm_bindgen]
 pub struct FmOsc {
   /// Audio context
@@ -88,14 +99,8 @@ impl FmOsc {
   }
 
   #[wasm_bindgen]
-  pub fn set_gain(&self, mut gain: f32) {
-    if gain > 1.0 {
-      gain = 1.0;
-    }
-    if gain < 0.0 {
-      gain = 0.0;
-    }
-    self.gain.gain().set_value(gain);
+  pub fn set_gain(&self, gain: f32) {
+    self.gain.gain().set_value(gain.clamp(0.0, 1.0));
   }
 
   #[wasm_bindgen]
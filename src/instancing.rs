@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use web_sys::{WebGl2RenderingContext, WebGlBuffer};
+
+/// Number of spectrum bands handed from the audio loop to the renderer
+pub(crate) const BAND_COUNT: usize = 16;
+
+const BAR_FIELD_COLUMNS: usize = 100;
+const BAR_FIELD_ROWS: usize = 100;
+pub(crate) const BAR_FIELD_INSTANCES: usize = BAR_FIELD_COLUMNS * BAR_FIELD_ROWS;
+
+/// Distance between neighbouring bars
+const BAR_SPACING: f32 = 0.12;
+
+/// Floats per instance: offset (3), scale (2), color (4) and band index (1)
+pub(crate) const INSTANCE_FLOATS: usize = 10;
+
+/// A field of bars where each column follows one spectrum band and each row is that band a
+/// frame further in the past, so the spectrum scrolls away from the camera like a waterfall
+pub struct BarField {
+  /// The most recent spectrum first
+  history: VecDeque<Vec<f32>>,
+  data: Vec<f32>,
+}
+
+impl BarField {
+  pub fn new() -> Self {
+    let mut data = vec![0.0; BAR_FIELD_INSTANCES * INSTANCE_FLOATS];
+    for row in 0..BAR_FIELD_ROWS {
+      for column in 0..BAR_FIELD_COLUMNS {
+        let instance = &mut data[(row * BAR_FIELD_COLUMNS + column) * INSTANCE_FLOATS..];
+        instance[0] = (column as f32 - BAR_FIELD_COLUMNS as f32 / 2.0) * BAR_SPACING;
+        instance[1] = -1.5;
+        instance[2] = -(row as f32) * BAR_SPACING;
+        instance[9] = band_for_column(column) as f32;
+      }
+    }
+    let history = std::iter::repeat_n(vec![0.0; BAND_COUNT], BAR_FIELD_ROWS).collect();
+    BarField { history, data }
+  }
+
+  /// Push the latest band energies and recompute every instance
+  pub fn update(&mut self, bands: &[f32]) {
+    let mut latest = self.history.pop_back().unwrap_or_default();
+    latest.clear();
+    latest.extend(bands.iter().take(BAND_COUNT));
+    latest.resize(BAND_COUNT, 0.0);
+    self.history.push_front(latest);
+
+    for (row, spectrum) in self.history.iter().enumerate() {
+      // Older rows fade out towards the back of the field
+      let fade = 1.0 - row as f32 / BAR_FIELD_ROWS as f32;
+      for column in 0..BAR_FIELD_COLUMNS {
+        let band = band_for_column(column);
+        let energy = spectrum[band];
+        let instance = &mut self.data[(row * BAR_FIELD_COLUMNS + column) * INSTANCE_FLOATS..];
+        instance[3] = BAR_SPACING * 0.4;
+        instance[4] = 0.02 + energy * 1.5;
+        let (r, g, b) = band_color(band);
+        instance[5] = r * fade;
+        instance[6] = g * fade;
+        instance[7] = b * fade;
+        instance[8] = 1.0;
+      }
+    }
+  }
+
+  pub fn data(&self) -> &[f32] {
+    &self.data
+  }
+}
+
+impl Default for BarField {
+  fn default() -> Self {
+    BarField::new()
+  }
+}
+
+fn band_for_column(column: usize) -> usize {
+  column * BAND_COUNT / BAR_FIELD_COLUMNS
+}
+
+/// Spread the bands around the hue wheel, lows are red and highs are violet
+fn band_color(band: usize) -> (f32, f32, f32) {
+  let hue = band as f32 / BAND_COUNT as f32 * 0.8;
+  let channel = |offset: f32| {
+    let k = (offset + hue * 6.0) % 6.0;
+    1.0 - (k.min(4.0 - k).clamp(0.0, 1.0))
+  };
+  (channel(5.0), channel(3.0), channel(1.0))
+}
+
+/// Overwrite the contents of a buffer made by `init_dynamic_buffer`
+pub(crate) fn upload_instances(
+  gl_context: &WebGl2RenderingContext,
+  buffer: &WebGlBuffer,
+  data: &[f32],
+) {
+  gl_context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(buffer));
+  // `view` is only valid until the next wasm allocation, which cannot happen before the upload
+  unsafe {
+    let data_array = js_sys::Float32Array::view(data);
+    gl_context.buffer_sub_data_with_i32_and_array_buffer_view(
+      WebGl2RenderingContext::ARRAY_BUFFER,
+      0,
+      &data_array,
+    );
+  }
+}
@@ -0,0 +1,158 @@
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Waveform {
+  Sine,
+  Triangle,
+  /// Ramps up and drops at the end of each cycle
+  Saw,
+  Square,
+  /// A new random value every cycle
+  SampleAndHold,
+  /// Wanders smoothly between a new random value every cycle
+  RandomWalk,
+}
+
+/// How fast an LFO cycles, either free running or locked to the tempo
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rate {
+  /// Cycles per second
+  Hertz(f32),
+  /// Beats per cycle
+  Beats(f32),
+}
+
+impl Rate {
+  /// Cycles per second at `tempo` beats per minute
+  pub fn hertz(self, tempo: f32) -> f32 {
+    match self {
+      Rate::Hertz(hertz) => hertz,
+      Rate::Beats(beats) => tempo / 60.0 / beats,
+    }
+  }
+}
+
+/// A low frequency oscillator. Its value is a pure function of the clock, so seeking or
+/// rendering offline gives exactly the same motion as playing live.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lfo {
+  pub waveform: Waveform,
+  pub rate: Rate,
+  /// Fraction of a cycle to start at
+  #[serde(default)]
+  pub phase: f32,
+  /// Output `-1.0..=1.0` instead of `0.0..=1.0`
+  #[serde(default)]
+  pub bipolar: bool,
+  /// Picks the random sequence of the random waveforms
+  #[serde(default)]
+  pub seed: u32,
+}
+
+impl Lfo {
+  /// The value at `time` seconds with the tempo at `tempo` beats per minute
+  pub fn value(&self, time: f32, tempo: f32) -> f32 {
+    // Phase in whole cycles, kept in f64 so long sets do not lose precision
+    let cycles = time as f64 * self.rate.hertz(tempo) as f64 + self.phase as f64;
+    let cycle = cycles.floor();
+    let t = (cycles - cycle) as f32;
+    let unipolar = match self.waveform {
+      Waveform::Sine => 0.5 - 0.5 * (t * std::f32::consts::TAU).cos(),
+      Waveform::Triangle => 1.0 - (2.0 * t - 1.0).abs(),
+      Waveform::Saw => t,
+      Waveform::Square => {
+        if t < 0.5 {
+          1.0
+        } else {
+          0.0
+        }
+      }
+      Waveform::SampleAndHold => random(self.seed, cycle as i64),
+      Waveform::RandomWalk => {
+        let from = random(self.seed, cycle as i64);
+        let to = random(self.seed, cycle as i64 + 1);
+        let eased = t * t * (3.0 - 2.0 * t);
+        from + (to - from) * eased
+      }
+    };
+    if self.bipolar {
+      unipolar * 2.0 - 1.0
+    } else {
+      unipolar
+    }
+  }
+}
+
+/// A repeatable random value in `0.0..1.0` for every `(seed, index)`
+fn random(seed: u32, index: i64) -> f32 {
+  // SplitMix64 finalizer
+  let mut x = (index as u64) ^ ((seed as u64) << 32);
+  x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+  x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+  x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+  x ^= x >> 31;
+  (x >> 40) as f32 / (1u64 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::assert_close;
+
+  const WAVEFORMS: [Waveform; 6] = [
+    Waveform::Sine,
+    Waveform::Triangle,
+    Waveform::Saw,
+    Waveform::Square,
+    Waveform::SampleAndHold,
+    Waveform::RandomWalk,
+  ];
+
+  fn lfo(waveform: Waveform, rate: Rate) -> Lfo {
+    Lfo { waveform, rate, phase: 0.0, bipolar: false, seed: 7 }
+  }
+
+  #[test]
+  fn the_same_time_gives_the_same_value() {
+    for waveform in WAVEFORMS {
+      let lfo = lfo(waveform, Rate::Hertz(1.3));
+      let times = [0.0, 0.37, 12.5, 3600.25];
+      let first: Vec<f32> = times.iter().map(|&time| lfo.value(time, 120.0)).collect();
+      // Sampled again in another order, like after a seek
+      let again: Vec<f32> = times.iter().rev().map(|&time| lfo.value(time, 120.0)).collect();
+      assert_eq!(first, again.into_iter().rev().collect::<Vec<_>>(), "{:?}", waveform);
+      assert!(first.iter().all(|value| (0.0..=1.0).contains(value)), "{:?}", waveform);
+    }
+  }
+
+  #[test]
+  fn random_waveforms_change_with_the_seed_and_the_cycle() {
+    let held = lfo(Waveform::SampleAndHold, Rate::Hertz(1.0));
+    assert_eq!(held.value(0.1, 120.0), held.value(0.9, 120.0));
+    assert_ne!(held.value(0.5, 120.0), held.value(1.5, 120.0));
+    let reseeded = Lfo { seed: 8, ..held.clone() };
+    assert_ne!(held.value(0.5, 120.0), reseeded.value(0.5, 120.0));
+
+    // The walk starts each cycle where the held value is and arrives at the next one
+    let walk = lfo(Waveform::RandomWalk, Rate::Hertz(1.0));
+    assert_eq!(walk.value(2.0, 120.0), held.value(2.0, 120.0));
+    assert_close(walk.value(2.999, 120.0), held.value(3.0, 120.0), 1e-4);
+  }
+
+  #[test]
+  fn beat_rates_follow_the_tempo() {
+    // One cycle every two beats, at 120 bpm a beat is half a second
+    let saw = lfo(Waveform::Saw, Rate::Beats(2.0));
+    assert_close(Rate::Beats(2.0).hertz(120.0), 1.0, 1e-6);
+    assert_close(saw.value(0.5, 120.0), 0.5, 1e-6);
+    assert_close(saw.value(0.25, 120.0), 0.25, 1e-6);
+    // Twice as fast at double the tempo
+    assert_close(saw.value(0.25, 240.0), 0.5, 1e-6);
+
+    let square = Lfo { bipolar: true, ..lfo(Waveform::Square, Rate::Beats(1.0)) };
+    assert_eq!((square.value(0.0, 120.0), square.value(0.3, 120.0)), (1.0, -1.0));
+  }
+}
@@ -1,99 +1,236 @@
-use nalgebra_glm;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 use wasm_bindgen::{prelude::*, JsCast};
-use wasm_bindgen_futures::JsFuture;
-use web_sys::{
-  console, AudioContext, EventTarget, HtmlCanvasElement, HtmlMediaElement, WebGl2RenderingContext,
-  WebGlBuffer,
-  AnalyserNode,
-};
+use web_sys::{AnalyserNode, HtmlAudioElement, HtmlCanvasElement, WebGl2RenderingContext};
 
+mod audio_input;
+mod automation;
+mod beat;
 mod buffer_attrib;
 mod buffers;
+mod camera;
+mod context_loss;
+mod descriptors;
+mod error;
+mod fm_osc;
+mod instancing;
+mod lfo;
+mod midi;
+mod modulation;
+mod offline;
+mod particles;
+mod pitch;
+mod presentation;
+mod preset;
 mod program_info;
+mod quality;
+mod recorder;
+mod render_target;
+mod ring;
+mod scene_graph;
+mod screenshot;
 mod shaders;
+mod stats;
+mod stereo;
+#[cfg(test)]
+mod test_utils;
+mod timeline;
+mod transitions;
 mod utils;
-use crate::{buffer_attrib::BufferAttrib, program_info::ProgramInfo, utils::*};
+mod vertex_arrays;
+mod worklet;
+use crate::{error::DemoError, presentation::PresentationState};
 
 pub fn window() -> web_sys::Window {
   web_sys::window().expect("Error. `window` is not in this context.")
 }
 
-pub fn request_animation_frame(f: &Closure<dyn FnMut(f32)>) {
-  window()
-    .request_animation_frame(f.as_ref().unchecked_ref())
-    .expect("Error. Did not register `RequestAnimationFrame`");
+pub(crate) fn document() -> Result<web_sys::Document, DemoError> {
+  window().document().ok_or_else(|| DemoError::MissingElement("the document".to_string()))
 }
 
-fn vec_to_js_array(vec: Vec<u8>) -> js_sys::Array {
-  vec.into_iter().map(JsValue::from).collect()
+/// Where a render loop keeps its frame callback, so the callback can schedule itself again
+pub type FrameCallback = RefCell<Option<Closure<dyn FnMut(f32)>>>;
+
+/// Schedule the frame callback kept in `slot`
+pub fn request_animation_frame(slot: &FrameCallback) -> Result<(), JsValue> {
+  let slot = slot.borrow();
+  let callback = slot.as_ref().ok_or("The frame callback is not set")?;
+  window().request_animation_frame(callback.as_ref().unchecked_ref())?;
+  Ok(())
+}
+
+/// What the audio loop measured on its latest frame
+#[derive(Default)]
+pub(crate) struct AudioFrame {
+  /// Spectrum band energies in `0.0..=1.0`
+  pub bands: Vec<f32>,
+  /// Root mean square of the latest block of samples
+  pub rms: f32,
+  pub stereo: stereo::StereoFrame,
+  /// Every render quantum since the previous frame, zero while the worklet is not running
+  pub quantum: worklet::QuantumFeatures,
+  pub harmony: pitch::HarmonyFrame,
+  pub descriptors: descriptors::Descriptors,
 }
 
-async fn audio() -> Result<(), JsValue> {
+/// Written by the audio loop and read by the renderer
+pub(crate) type SharedAudio = Rc<RefCell<AudioFrame>>;
+
+/// Start analysing audio without waiting on the microphone, which is asked for in the background
+fn audio(audio_frame: SharedAudio, track: Option<&HtmlAudioElement>) -> Result<(), JsValue> {
   let context = web_sys::AudioContext::new()?;
   let node = context.create_analyser()?;
+  // Long enough for the pitch tracker's lowest note, which keeps the bins as wide as at 48 kHz
+  node.set_fft_size(pitch::block_length(context.sample_rate()) as u32);
 
-  let navigator = &window().navigator();
-  let mut media_stream_constraints = web_sys::MediaStreamConstraints::new();
-  &media_stream_constraints.audio(&JsValue::TRUE);
-  &media_stream_constraints.video(&JsValue::FALSE);
-  let stream_promise = navigator.media_devices()?.get_user_media_with_constraints(&media_stream_constraints)?;
-  let stream: web_sys::MediaStream = JsFuture::from(stream_promise).await?.dyn_into()?;
-  // console::log_1(&stream);
+  // Every source goes through this, mixed to stereo so each channel gets its own analyser
+  let bus = context.create_gain()?;
+  bus.set_channel_count(2);
+  bus.set_channel_count_mode(web_sys::ChannelCountMode::Explicit);
+  bus.connect_with_audio_node(&node)?;
+  let mut stereo = stereo::StereoAnalyser::new(&context, &bus)?;
 
   // Buffer to hold fft data
-  let kMaxFrequency = 20000;
-  let sample_rate = context.sample_rate() as u32;
-  let fft_size = node.fft_size() / 2;
-  let buffer_size = (kMaxFrequency / sample_rate * fft_size) as usize;
-  let buffer_size: usize = 16;
-  // console::log_1(&(buffer_size as u32).into());
-  let mut buffer = vec![0; buffer_size];
-  let js_buff = js_sys::Uint8Array::new_with_length(buffer_size as u32);
-  // console::log_1(&(js_buff).into());
-  let arr = js_sys::Array::new();
-  &arr.set(4, JsValue::from_f64(1.0));
-
-
-  let audio_node = &context.create_media_stream_source(&stream)?;
-  audio_node.connect_with_audio_node(&node)?;
-
+  let buffer_size: usize = audio_frame.borrow().bands.len();
+  let buffer = vec![0; buffer_size];
+  let mut samples = vec![0.0; node.fft_size() as usize];
+  let mut harmony = pitch::HarmonyTracker::default();
+  let mut descriptors = descriptors::DescriptorTracker::new(context.sample_rate());
+  // Loudness has to see every sample once, only what played since the last frame is metered
+  let mut metered_until = context.current_time();
+
+  // Recordings get the mic and the music track, which now plays through this context
+  let recording = recorder::init_audio(&context)?;
+  let input = audio_input::init(&context, &bus, &recording, track)?;
+  wasm_bindgen_futures::spawn_local(audio_input::connect_microphone(input));
+
+  // Transients between frames only show up in the worklet, which loads in the background
+  let feed = worklet::SharedFeed::default();
+  let worklet_feed = feed.clone();
+  let (worklet_context, worklet_input) = (context.clone(), bus.clone().into());
+  wasm_bindgen_futures::spawn_local(async move {
+    if let Err(err) = worklet::start(worklet_context, worklet_input, worklet_feed).await {
+      web_sys::console::warn_2(&"Audio worklet unavailable:".into(), &err);
+    }
+  });
+
+  if let Some(track) = track {
+    // The context may start suspended until the page is interacted with, pressing play is
+    let resumed_context = context.clone();
+    let closure = Closure::wrap(Box::new(move || {
+      let _ = resumed_context.resume();
+    }) as Box<dyn FnMut()>);
+    track.add_event_listener_with_callback("play", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
 
   // Draw scene every 0.01 seconds
   let ref_count = Rc::new(RefCell::new(None));
   let ref_count_clone = ref_count.clone();
 
-  *ref_count_clone.borrow_mut() = Some(Closure::wrap(Box::new(move |t| {
-    let msg = "RACHEL audio";
-    // console::log_1(&msg.into());
+  *ref_count_clone.borrow_mut() = Some(Closure::wrap(Box::new(move |t: f32| {
     let buf = buffer.clone();
-    draw_loop(&node, buf);
-    request_animation_frame(ref_count.borrow().as_ref().unwrap());
+    if let Err(e) = draw_loop(&node, buf, &mut samples, &audio_frame) {
+      web_sys::console::error_1(&e);
+    }
+    harmony.update(
+      &samples,
+      context.sample_rate(),
+      t / 1000.0,
+      &mut audio_frame.borrow_mut().harmony,
+    );
+    stereo.update(&mut audio_frame.borrow_mut().stereo);
+    let now = context.current_time();
+    let fresh = ((now - metered_until) * context.sample_rate() as f64).round() as usize;
+    metered_until = now;
+    let [left, right] = stereo.samples();
+    // The analysers only hold their last window. Frames further apart than that, like in a
+    // background tab where animation frames are throttled, leave the samples in between
+    // unmetered, so the loudness only covers the windows that were seen.
+    let fresh = fresh.min(left.len());
+    descriptors.update(
+      &samples,
+      [&left[left.len() - fresh..], &right[right.len() - fresh..]],
+      context.sample_rate(),
+      &mut audio_frame.borrow_mut().descriptors,
+    );
+    let mut feed = feed.borrow_mut();
+    feed.read_ring(context.current_time());
+    audio_frame.borrow_mut().quantum = feed.take().unwrap_or_default();
+    if let Err(e) = request_animation_frame(&ref_count) {
+      web_sys::console::error_1(&e);
+    }
   }) as Box<dyn FnMut(f32)>));
 
-  request_animation_frame(ref_count_clone.borrow().as_ref().unwrap());
+  request_animation_frame(&ref_count_clone)
+}
 
-  Ok(())
+/// Without the constructor there is no WebGL 2 at all, otherwise it is there but unavailable
+fn context_creation_error() -> DemoError {
+  let supported = js_sys::Reflect::has(&window(), &"WebGL2RenderingContext".into());
+  if supported.unwrap_or(false) {
+    DemoError::ContextCreation("the browser refused, the GPU may be blocklisted".to_string())
+  } else {
+    DemoError::UnsupportedFeature("WebGL 2".to_string())
+  }
 }
 
 /// Audio draw loop
-fn draw_loop(node: &AnalyserNode, mut buffer: Vec<u8>) -> Result<(), JsValue> {
-  &node.get_byte_frequency_data(&mut buffer);
-  web_sys::console::log(&vec_to_js_array(buffer));
+fn draw_loop(
+  node: &AnalyserNode,
+  mut buffer: Vec<u8>,
+  samples: &mut [f32],
+  audio_frame: &SharedAudio,
+) -> Result<(), JsValue> {
+  let mut audio_frame = audio_frame.borrow_mut();
+  node.get_byte_frequency_data(&mut buffer);
+  for (band, value) in audio_frame.bands.iter_mut().zip(buffer) {
+    *band = value as f32 / 255.0;
+  }
+
+  node.get_float_time_domain_data(samples);
+  let sum_of_squares: f32 = samples.iter().map(|sample| sample * sample).sum();
+  audio_frame.rms = (sum_of_squares / samples.len().max(1) as f32).sqrt();
   Ok(())
 }
 
-
 #[wasm_bindgen(start)]
-pub async fn start() -> Result<(), JsValue> {
-  let document = window().document().expect("Error. `window` does not have a `document`.");
-  audio().await?;
+pub fn start() -> Result<(), JsValue> {
+  // Still thrown for the console, but also shown on the page
+  run().inspect_err(error::show)
+}
 
-  let canvas = document.get_element_by_id("canvas").unwrap();
+fn run() -> Result<(), JsValue> {
+  let document = document()?;
+  let audio_frame: SharedAudio = Rc::new(RefCell::new(AudioFrame {
+    bands: vec![0.0; instancing::BAND_COUNT],
+    ..AudioFrame::default()
+  }));
+  // MIDI is optional, the controls simply stay at zero without it
+  let midi = midi::init();
+
+  let canvas = document
+    .get_element_by_id("canvas")
+    .ok_or_else(|| DemoError::MissingElement("the canvas".to_string()))?;
   let canvas: HtmlCanvasElement = canvas.dyn_into::<HtmlCanvasElement>()?;
 
-  let gl_context = canvas.get_context("webgl2")?.unwrap().dyn_into::<WebGl2RenderingContext>()?;
-  shaders::do_webgl(gl_context)?;
-
-  Ok(())
+  let preset = preset::Preset::builtin().map_err(DemoError::from)?;
+  let presentation = Rc::new(RefCell::new(PresentationState::new(preset.scene_names())));
+  presentation::init(&canvas, presentation.clone())?;
+
+  // Multisampling is left to the quality governor, which can turn it off when frames drop
+  let attributes = web_sys::WebGlContextAttributes::new();
+  attributes.set_antialias(false);
+  let gl_context = canvas
+    .get_context_with_context_options("webgl2", &attributes)?
+    .ok_or_else(context_creation_error)?
+    .dyn_into::<WebGl2RenderingContext>()?;
+  let track = timeline::init_track(&document)?;
+  recorder::init_controls()?;
+
+  // Drawing starts right away, the visuals react to silence until a source is connected
+  if let Err(err) = audio(audio_frame.clone(), track.as_ref()) {
+    web_sys::console::error_1(&err);
+  }
+  shaders::do_webgl(gl_context, presentation, audio_frame, midi, track, preset)
 }
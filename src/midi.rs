@@ -0,0 +1,69 @@
+use crate::window;
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{MidiAccess, MidiInput, MidiMessageEvent};
+
+/// Number of MIDI control change controllers
+pub(crate) const CONTROLLER_COUNT: usize = 128;
+
+/// What arrived from any channel of any MIDI input
+pub(crate) struct MidiState {
+  /// Latest value of every control change number in `0.0..=1.0`
+  pub controls: Vec<f32>,
+  /// The latest program change not yet acted on, used to switch scenes
+  pub program_change: Option<u8>,
+}
+
+pub(crate) type SharedMidi = Rc<RefCell<MidiState>>;
+
+/// Start listening to every MIDI input in the background. Without Web MIDI support or permission
+/// the controls just stay at zero.
+pub(crate) fn init() -> SharedMidi {
+  let state = MidiState { controls: vec![0.0; CONTROLLER_COUNT], program_change: None };
+  let controls: SharedMidi = Rc::new(RefCell::new(state));
+  let listening = controls.clone();
+  wasm_bindgen_futures::spawn_local(async move {
+    if let Err(err) = listen(listening).await {
+      web_sys::console::warn_2(&"MIDI is unavailable:".into(), &err);
+    }
+  });
+  controls
+}
+
+async fn listen(state: SharedMidi) -> Result<(), JsValue> {
+  let access: MidiAccess =
+    JsFuture::from(window().navigator().request_midi_access()?).await?.dyn_into()?;
+
+  let on_message = Closure::wrap(Box::new(move |event: MidiMessageEvent| {
+    if let Ok(data) = event.data() {
+      // The high nibble of the status is the message, the low one the channel
+      match data[..] {
+        [status, number, value] if status & 0xF0 == 0xB0 => {
+          state.borrow_mut().controls[(number & 0x7F) as usize] = (value & 0x7F) as f32 / 127.0;
+        }
+        [status, program, ..] if status & 0xF0 == 0xC0 => {
+          state.borrow_mut().program_change = Some(program & 0x7F);
+        }
+        _ => {}
+      }
+    }
+  }) as Box<dyn FnMut(MidiMessageEvent)>);
+  let on_message = on_message.into_js_value();
+
+  // Devices plugged in later show up through `statechange`, so hook every input each time
+  let inputs = access.inputs();
+  let hook_inputs = move || {
+    for input in inputs.values() {
+      if let Ok(input) = input.and_then(|input| input.dyn_into::<MidiInput>()) {
+        input.set_onmidimessage(Some(on_message.unchecked_ref()));
+      }
+    }
+  };
+  hook_inputs();
+  let on_state_change = Closure::wrap(Box::new(hook_inputs) as Box<dyn FnMut()>);
+  access.set_onstatechange(Some(on_state_change.as_ref().unchecked_ref()));
+  on_state_change.forget();
+
+  Ok(())
+}
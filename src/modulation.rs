@@ -0,0 +1,400 @@
+use crate::{
+  camera::Camera, descriptors::Descriptors, pitch::HarmonyFrame, scene_graph::Transform,
+  stereo::StereoFrame, worklet::QuantumFeatures,
+};
+use nalgebra_glm as glm;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Steepness of the `exp` and `log` curves
+const CURVE_STEEPNESS: f32 = 4.0;
+
+/// Everything a route can read from, sampled once per frame
+pub struct ModulationInputs<'a> {
+  /// Spectrum band energies in `0.0..=1.0`
+  pub bands: &'a [f32],
+  pub rms: f32,
+  pub stereo: &'a StereoFrame,
+  /// Measured on every render quantum since the previous frame
+  pub quantum: QuantumFeatures,
+  pub harmony: &'a HarmonyFrame,
+  pub descriptors: Descriptors,
+  /// `0.0` on a beat, ramping up to `1.0` where the next beat is expected
+  pub beat_phase: f32,
+  pub lfos: &'a HashMap<String, f32>,
+  pub automation: &'a HashMap<String, f32>,
+  /// Latest value of every MIDI control change number, in `0.0..=1.0`
+  pub midi_cc: &'a [f32],
+  /// Seconds since the start
+  pub time: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+  Band(usize),
+  /// A band of just the left or right channel
+  LeftBand(usize),
+  RightBand(usize),
+  Rms,
+  /// `-1.0` left to `1.0` right
+  Balance,
+  /// `0.0` for mono, growing as the channels differ
+  Width,
+  /// `1.0` for mono down to `-1.0` for channels out of phase
+  Correlation,
+  /// Loudest sample of any render quantum since the previous frame
+  Peak,
+  /// `0.0` for steady sound, towards `1.0` on a sudden rise, caught even between frames
+  Onset,
+  /// Midi note of the latest detected pitch, cents included, divided by 127
+  Note,
+  /// Pitch class of the latest detected note over 12, from `0.0` for C, a hue around the circle
+  PitchClass,
+  /// How clearly periodic the input is, `0.0` while no pitch is detected
+  PitchClarity,
+  /// Energy of a pitch class, `0` for C to `11` for B, relative to the strongest
+  Chroma(usize),
+  /// Tonic of the estimated key over 12, like `pitch_class`
+  Key,
+  /// Brightness, the spectral centroid on a log scale up to Nyquist
+  Centroid,
+  /// `0.0` for tones, towards `1.0` for noise
+  Flatness,
+  /// Frequency below which 85 % of the energy is, like `centroid`
+  Rolloff,
+  /// Share of the spectrum that rose since the previous frame
+  Flux,
+  /// Peak over RMS, `0.0` at 0 dB to `1.0` at 20 dB
+  Crest,
+  /// EBU R128 momentary loudness, `0.0` at -60 LUFS to `1.0` at 0 LUFS
+  Loudness,
+  /// The same over 3 s instead of 400 ms
+  ShortTermLoudness,
+  BeatPhase,
+  Lfo(String),
+  Automation(String),
+  MidiCc(u8),
+  Time,
+}
+
+impl Source {
+  /// Sources that are not there, like a band past the end, read as `0.0`
+  fn sample(&self, inputs: &ModulationInputs) -> f32 {
+    match self {
+      Source::Band(band) => inputs.bands.get(*band).copied().unwrap_or(0.0),
+      Source::LeftBand(band) => inputs.stereo.left_bands.get(*band).copied().unwrap_or(0.0),
+      Source::RightBand(band) => inputs.stereo.right_bands.get(*band).copied().unwrap_or(0.0),
+      Source::Rms => inputs.rms,
+      Source::Balance => inputs.stereo.balance,
+      Source::Width => inputs.stereo.width,
+      Source::Correlation => inputs.stereo.correlation,
+      Source::Peak => inputs.quantum.peak,
+      Source::Onset => inputs.quantum.onset,
+      Source::Note => inputs.harmony.note / 127.0,
+      Source::PitchClass => (inputs.harmony.note.round() as u32 % 12) as f32 / 12.0,
+      Source::PitchClarity => inputs.harmony.pitch.map_or(0.0, |pitch| pitch.clarity),
+      Source::Chroma(class) => inputs.harmony.chroma.get(*class).copied().unwrap_or(0.0),
+      Source::Key => inputs.harmony.key.map_or(0.0, |key| key.tonic as f32 / 12.0),
+      Source::Centroid => inputs.descriptors.centroid,
+      Source::Flatness => inputs.descriptors.flatness,
+      Source::Rolloff => inputs.descriptors.rolloff,
+      Source::Flux => inputs.descriptors.flux,
+      Source::Crest => inputs.descriptors.crest,
+      Source::Loudness => inputs.descriptors.momentary,
+      Source::ShortTermLoudness => inputs.descriptors.short_term,
+      Source::BeatPhase => inputs.beat_phase,
+      Source::Lfo(name) => inputs.lfos.get(name).copied().unwrap_or(0.0),
+      Source::Automation(name) => inputs.automation.get(name).copied().unwrap_or(0.0),
+      Source::MidiCc(number) => inputs.midi_cc.get(*number as usize).copied().unwrap_or(0.0),
+      Source::Time => inputs.time,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Destination {
+  /// Added to every component of a material uniform
+  Uniform {
+    material: String,
+    uniform: String,
+  },
+  /// Added to a transform property of a node
+  Node {
+    node: String,
+    property: NodeProperty,
+  },
+  Camera(CameraParam),
+  FmOsc(FmParam),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeProperty {
+  TranslationX,
+  TranslationY,
+  TranslationZ,
+  RotationX,
+  RotationY,
+  RotationZ,
+  /// Grows the node by `1.0 + value` times
+  Scale,
+}
+
+impl NodeProperty {
+  pub fn apply(self, transform: &mut Transform, value: f32) {
+    match self {
+      NodeProperty::TranslationX => transform.translation.x += value,
+      NodeProperty::TranslationY => transform.translation.y += value,
+      NodeProperty::TranslationZ => transform.translation.z += value,
+      NodeProperty::RotationX => transform.rotation.x += value,
+      NodeProperty::RotationY => transform.rotation.y += value,
+      NodeProperty::RotationZ => transform.rotation.z += value,
+      NodeProperty::Scale => transform.scale *= 1.0 + value,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CameraParam {
+  /// Degrees added to the vertical field of view
+  FieldOfView,
+  /// Moves the position and target together, for camera shake
+  OffsetX,
+  OffsetY,
+  OffsetZ,
+}
+
+impl CameraParam {
+  pub fn apply(self, camera: &mut Camera, value: f32) {
+    let offset = match self {
+      CameraParam::FieldOfView => {
+        let field_of_view = camera.field_of_view() + value.to_radians();
+        camera.set_field_of_view(field_of_view.clamp(0.01, 3.1));
+        return;
+      }
+      CameraParam::OffsetX => glm::vec3(value, 0.0, 0.0),
+      CameraParam::OffsetY => glm::vec3(0.0, value, 0.0),
+      CameraParam::OffsetZ => glm::vec3(0.0, 0.0, value),
+    };
+    camera.position += offset;
+    camera.target += offset;
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FmParam {
+  /// Modulator frequency as a multiple of the primary frequency
+  FrequencyRatio,
+  /// Modulation depth as a multiple of the primary frequency
+  Amount,
+  Gain,
+  /// Midi note of the primary, rounded. Routing `note` with an `amount` of 127 follows the
+  /// input, an `offset` of 7 harmonizes a fifth above it.
+  Note,
+}
+
+/// Shapes the source before it is scaled, keeping its sign
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+  #[default]
+  Linear,
+  /// Slow start, most of the change near `1.0`, good for making loud parts stand out
+  Exp,
+  /// Fast start, lifts quiet parts
+  Log,
+}
+
+impl Curve {
+  pub fn shape(self, x: f32) -> f32 {
+    let magnitude = x.abs();
+    let shaped = match self {
+      Curve::Linear => magnitude,
+      Curve::Exp => (CURVE_STEEPNESS * magnitude).exp_m1() / CURVE_STEEPNESS.exp_m1(),
+      Curve::Log => (CURVE_STEEPNESS * magnitude).ln_1p() / CURVE_STEEPNESS.ln_1p(),
+    };
+    shaped.copysign(x)
+  }
+}
+
+/// One connection of the matrix: `offset + amount * curve(source)`, smoothed and clamped
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+  pub source: Source,
+  pub destination: Destination,
+  #[serde(default = "default_amount")]
+  pub amount: f32,
+  #[serde(default)]
+  pub offset: f32,
+  #[serde(default)]
+  pub curve: Curve,
+  /// Seconds to follow a rising value, `0.0` to jump
+  #[serde(default)]
+  pub attack: f32,
+  /// Seconds to follow a falling value, `0.0` to jump
+  #[serde(default)]
+  pub release: f32,
+  pub min: Option<f32>,
+  pub max: Option<f32>,
+}
+
+fn default_amount() -> f32 {
+  1.0
+}
+
+impl Route {
+  /// The value before smoothing
+  fn target(&self, inputs: &ModulationInputs) -> f32 {
+    self.offset + self.amount * self.curve.shape(self.source.sample(inputs))
+  }
+}
+
+/// Follow `target` from `current` with a one pole filter of time constant `seconds`
+fn smooth(current: f32, target: f32, seconds: f32, dt: f32) -> f32 {
+  if seconds <= 0.0 {
+    return target;
+  }
+  current + (target - current) * (1.0 - (-dt / seconds).exp())
+}
+
+/// Routes sources to destinations. Routes sharing a destination add up.
+#[derive(Default)]
+pub struct ModulationMatrix {
+  routes: Vec<Route>,
+  /// Smoothed value of every route, `None` until the first evaluation
+  values: Vec<Option<f32>>,
+}
+
+impl ModulationMatrix {
+  pub fn new(routes: Vec<Route>) -> Self {
+    let values = vec![None; routes.len()];
+    ModulationMatrix { routes, values }
+  }
+
+  /// Advance every route by `dt` seconds and return the total for each destination, in the order
+  /// the destinations first appear
+  pub fn evaluate(&mut self, inputs: &ModulationInputs, dt: f32) -> Vec<(&Destination, f32)> {
+    let mut outputs: Vec<(&Destination, f32)> = Vec::new();
+    for (route, value) in self.routes.iter().zip(self.values.iter_mut()) {
+      let target = route.target(inputs);
+      let smoothed = match *value {
+        // Start at the target instead of sweeping up from zero
+        None => target,
+        Some(current) => {
+          let seconds = if target > current { route.attack } else { route.release };
+          smooth(current, target, seconds, dt)
+        }
+      };
+      *value = Some(smoothed);
+
+      let clamped = smoothed.max(route.min.unwrap_or(f32::MIN)).min(route.max.unwrap_or(f32::MAX));
+      match outputs.iter_mut().find(|(destination, _)| *destination == &route.destination) {
+        Some((_, total)) => *total += clamped,
+        None => outputs.push((&route.destination, clamped)),
+      }
+    }
+    outputs
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn route(source: Source, destination: Destination) -> Route {
+    Route {
+      source,
+      destination,
+      amount: 1.0,
+      offset: 0.0,
+      curve: Curve::Linear,
+      attack: 0.0,
+      release: 0.0,
+      min: None,
+      max: None,
+    }
+  }
+
+  fn uniform(name: &str) -> Destination {
+    Destination::Uniform { material: "plain".to_string(), uniform: name.to_string() }
+  }
+
+  /// Evaluate `matrix` with every input at zero but `rms`
+  fn evaluate(matrix: &mut ModulationMatrix, rms: f32, dt: f32) -> Vec<(Destination, f32)> {
+    let (stereo, harmony, empty) =
+      (StereoFrame::default(), HarmonyFrame::default(), HashMap::new());
+    let inputs = ModulationInputs {
+      bands: &[],
+      rms,
+      stereo: &stereo,
+      quantum: QuantumFeatures::default(),
+      harmony: &harmony,
+      descriptors: Descriptors::default(),
+      beat_phase: 0.0,
+      lfos: &empty,
+      automation: &empty,
+      midi_cc: &[],
+      time: 0.0,
+    };
+    let outputs = matrix.evaluate(&inputs, dt);
+    outputs.into_iter().map(|(destination, value)| (destination.clone(), value)).collect()
+  }
+
+  #[test]
+  fn curves_keep_the_ends_and_the_sign() {
+    for curve in [Curve::Linear, Curve::Exp, Curve::Log] {
+      assert_eq!(curve.shape(0.0), 0.0);
+      assert!((curve.shape(1.0) - 1.0).abs() < 1e-6, "{:?}", curve);
+      assert_eq!(curve.shape(-0.3), -curve.shape(0.3), "{:?}", curve);
+    }
+    assert_eq!(Curve::Linear.shape(0.3), 0.3);
+    assert!(Curve::Exp.shape(0.3) < 0.3);
+    assert!(Curve::Log.shape(0.3) > 0.3);
+  }
+
+  #[test]
+  fn attack_and_release_follow_at_their_own_rates() {
+    let mut slow_attack = route(Source::Rms, uniform("u_level"));
+    slow_attack.attack = 1.0;
+    let mut matrix = ModulationMatrix::new(vec![slow_attack]);
+
+    // The first evaluation starts at the target, then a rise takes the attack time
+    assert_eq!(evaluate(&mut matrix, 0.0, 0.1)[0].1, 0.0);
+    let risen = evaluate(&mut matrix, 1.0, 1.0)[0].1;
+    assert!((risen - (1.0 - (-1.0f32).exp())).abs() < 1e-6, "{}", risen);
+    // Without a release the fall is immediate
+    assert_eq!(evaluate(&mut matrix, 0.0, 0.1)[0].1, 0.0);
+  }
+
+  #[test]
+  fn values_are_clamped_after_smoothing() {
+    let mut clamped = route(Source::Rms, uniform("u_level"));
+    clamped.amount = 4.0;
+    clamped.offset = -1.0;
+    clamped.min = Some(0.0);
+    clamped.max = Some(2.0);
+    let mut matrix = ModulationMatrix::new(vec![clamped]);
+    assert_eq!(evaluate(&mut matrix, 0.0, 0.1)[0].1, 0.0);
+    assert_eq!(evaluate(&mut matrix, 0.5, 0.1)[0].1, 1.0);
+    assert_eq!(evaluate(&mut matrix, 1.0, 0.1)[0].1, 2.0);
+  }
+
+  #[test]
+  fn routes_to_one_destination_add_up() {
+    let mut offset = route(Source::Time, uniform("u_level"));
+    offset.offset = 0.25;
+    let mut matrix = ModulationMatrix::new(vec![
+      route(Source::Rms, uniform("u_level")),
+      route(Source::Rms, uniform("u_other")),
+      offset,
+      route(Source::Rms, uniform("u_level")),
+    ]);
+    let outputs = evaluate(&mut matrix, 0.5, 0.1);
+    assert_eq!(outputs, vec![(uniform("u_level"), 1.25), (uniform("u_other"), 0.5)]);
+  }
+}
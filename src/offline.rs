@@ -0,0 +1,372 @@
+use crate::{
+  descriptors::DescriptorTracker,
+  document,
+  error::DemoError,
+  instancing::BAND_COUNT,
+  pitch::{self, HarmonyTracker},
+  stereo, timeline, window,
+  worklet::{QuantumAnalyser, WorkletFeed, QUANTUM},
+  AudioFrame,
+};
+use js_sys::{Function, Promise};
+use std::{
+  cell::{Cell, RefCell},
+  convert::TryFrom,
+  f32::consts::PI,
+};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{AudioBuffer, Blob, HtmlCanvasElement, Response};
+
+/// The live per-channel `AnalyserNode`s keep their default size, only the mix is sized by rate
+const STEREO_FFT_SIZE: usize = 2048;
+const SMOOTHING: f32 = 0.8;
+const MIN_DECIBELS: f32 = -100.0;
+const MAX_DECIBELS: f32 = -30.0;
+
+thread_local! {
+  /// Handed from `export_frames` to the render loop, which picks it up on its next frame
+  static PENDING_EXPORT: RefCell<Option<OfflineRender>> = const { RefCell::new(None) };
+  /// From the call to `export_frames` until its promise settles
+  static EXPORTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks an export as under way for as long as it lives
+struct ExportGuard;
+
+impl Drop for ExportGuard {
+  fn drop(&mut self) {
+    EXPORTING.with(|exporting| exporting.set(false));
+  }
+}
+
+/// Spectrum and level of a decoded track, measured the way the live `AnalyserNode` does
+pub struct OfflineAnalyser {
+  /// Mono mix of every channel, and the first two channels on their own, mono tracks copied to
+  /// both like the live bus does
+  samples: Vec<f32>,
+  left: Vec<f32>,
+  right: Vec<f32>,
+  sample_rate: f32,
+  /// Window of the mix, `pitch::block_length` at the track's rate like the live mix analyser
+  fft_size: usize,
+  /// Smoothed magnitude of every band of the mix and each channel, carried over from frame to
+  /// frame
+  magnitudes: Vec<f32>,
+  left_magnitudes: Vec<f32>,
+  right_magnitudes: Vec<f32>,
+  /// The worklet's DSP, run over every quantum up to the frame being analysed
+  quanta: QuantumAnalyser,
+  /// Start of the first quantum not analysed yet
+  next_quantum: usize,
+  harmony: HarmonyTracker,
+  descriptors: DescriptorTracker,
+  /// First sample the loudness meter has not seen
+  metered_until: usize,
+}
+
+impl OfflineAnalyser {
+  pub fn new(buffer: &AudioBuffer) -> Result<Self, JsValue> {
+    let mut samples = vec![0.0; buffer.length() as usize];
+    let channels = buffer.number_of_channels();
+    for channel in 0..channels {
+      for (mixed, sample) in samples.iter_mut().zip(buffer.get_channel_data(channel)?) {
+        *mixed += sample / channels as f32;
+      }
+    }
+    let left = buffer.get_channel_data(0)?;
+    let right = if channels > 1 { buffer.get_channel_data(1)? } else { left.clone() };
+    Ok(OfflineAnalyser::from_samples(samples, left, right, buffer.sample_rate()))
+  }
+
+  /// Analyse already decoded samples, `samples` being the mix of every channel
+  pub fn from_samples(
+    samples: Vec<f32>,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    sample_rate: f32,
+  ) -> Self {
+    OfflineAnalyser {
+      samples,
+      left,
+      right,
+      sample_rate,
+      fft_size: pitch::block_length(sample_rate),
+      magnitudes: vec![0.0; BAND_COUNT],
+      left_magnitudes: vec![0.0; BAND_COUNT],
+      right_magnitudes: vec![0.0; BAND_COUNT],
+      quanta: QuantumAnalyser::new(sample_rate),
+      next_quantum: 0,
+      harmony: HarmonyTracker::default(),
+      descriptors: DescriptorTracker::new(sample_rate),
+      metered_until: 0,
+    }
+  }
+
+  /// Analyse the window of samples leading up to `time` seconds into the track, silence before
+  /// the start and after the end
+  pub fn analyse(&mut self, time: f32, audio_frame: &mut AudioFrame) {
+    let end = (time * self.sample_rate) as isize;
+    let window_of = |samples: &[f32], length: usize| -> Vec<f32> {
+      (0..length)
+        .map(|index| {
+          let position = end - length as isize + index as isize;
+          usize::try_from(position).ok().and_then(|position| samples.get(position)).copied()
+        })
+        .map(|sample| sample.unwrap_or(0.0))
+        .collect()
+    };
+    let mixed = window_of(&self.samples, self.fft_size);
+    let (left, right) =
+      (window_of(&self.left, STEREO_FFT_SIZE), window_of(&self.right, STEREO_FFT_SIZE));
+
+    let sum_of_squares: f32 = mixed.iter().map(|sample| sample * sample).sum();
+    audio_frame.rms = (sum_of_squares / self.fft_size as f32).sqrt();
+    spectrum(&mixed, &mut self.magnitudes, &mut audio_frame.bands);
+    self.harmony.update(&mixed, self.sample_rate, time, &mut audio_frame.harmony);
+
+    let stereo = &mut audio_frame.stereo;
+    spectrum(&left, &mut self.left_magnitudes, &mut stereo.left_bands);
+    spectrum(&right, &mut self.right_magnitudes, &mut stereo.right_bands);
+    stereo::measure(&left, &right, stereo);
+
+    let mut feed = WorkletFeed::default();
+    let end = usize::try_from(end).unwrap_or(0).min(self.left.len());
+    while self.next_quantum + QUANTUM <= end {
+      let quantum = self.next_quantum..self.next_quantum + QUANTUM;
+      feed.add(&self.quanta.process(&[&self.left[quantum.clone()], &self.right[quantum]]));
+      self.next_quantum += QUANTUM;
+    }
+    audio_frame.quantum = feed.take().unwrap_or_default();
+
+    let fresh = self.metered_until.min(end)..end;
+    self.metered_until = end;
+    self.descriptors.update(
+      &mixed,
+      [&self.left[fresh.clone()], &self.right[fresh]],
+      self.sample_rate,
+      &mut audio_frame.descriptors,
+    );
+  }
+}
+
+/// Only the bands the renderer reads are transformed, a plain DFT is plenty for that few. The
+/// window's length is the FFT size.
+fn spectrum(window: &[f32], magnitudes: &mut [f32], bands: &mut Vec<f32>) {
+  let fft_size = window.len();
+  let blackman = |index: usize| {
+    let x = 2.0 * PI * index as f32 / fft_size as f32;
+    0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos()
+  };
+  bands.resize(BAND_COUNT, 0.0);
+  for (bin, (band, smoothed)) in bands.iter_mut().zip(magnitudes.iter_mut()).enumerate() {
+    let (mut real, mut imaginary) = (0.0f32, 0.0f32);
+    for (index, sample) in window.iter().enumerate() {
+      let windowed = sample * blackman(index);
+      let angle = 2.0 * PI * (bin * index % fft_size) as f32 / fft_size as f32;
+      real += windowed * angle.cos();
+      imaginary -= windowed * angle.sin();
+    }
+    let magnitude = (real * real + imaginary * imaginary).sqrt() / fft_size as f32;
+    *smoothed = SMOOTHING * *smoothed + (1.0 - SMOOTHING) * magnitude;
+
+    let decibels = 20.0 * smoothed.max(f32::MIN_POSITIVE).log10();
+    let byte =
+      (255.0 * (decibels - MIN_DECIBELS) / (MAX_DECIBELS - MIN_DECIBELS)).clamp(0.0, 255.0);
+    // Quantised like `get_byte_frequency_data`
+    *band = byte.floor() / 255.0;
+  }
+}
+
+/// A render stepping a fixed timestep through the track, one captured frame at a time
+pub struct OfflineRender {
+  pub analyser: OfflineAnalyser,
+  /// Filled from the analyser every frame, in place of the live audio
+  pub audio_frame: AudioFrame,
+  fps: f32,
+  frame: u32,
+  frame_count: u32,
+  on_frame: Function,
+  resolve: Function,
+  reject: Function,
+}
+
+impl OfflineRender {
+  /// Seconds into the track of the frame about to be drawn
+  pub fn time(&self) -> f32 {
+    self.frame as f32 / self.fps
+  }
+
+  pub fn dt(&self) -> f32 {
+    1.0 / self.fps
+  }
+
+  /// Measure the track at the current frame
+  pub fn analyse(&mut self) {
+    let time = self.time();
+    self.analyser.analyse(time, &mut self.audio_frame);
+  }
+
+  /// Hand what was just drawn to `on_frame(blob, index)` and move on to the next frame. Returns
+  /// `true` once the last frame was captured.
+  pub fn capture(&mut self, canvas: &HtmlCanvasElement) -> Result<bool, JsValue> {
+    let on_frame = self.on_frame.clone();
+    let index = self.frame;
+    // The canvas is copied when `toBlob` is called, only the encoding finishes later
+    let callback = Closure::once(move |blob: Option<Blob>| {
+      if let Err(err) = on_frame.call2(&JsValue::NULL, &blob.into(), &index.into()) {
+        web_sys::console::error_1(&err);
+      }
+    });
+    canvas.to_blob(callback.as_ref().unchecked_ref())?;
+    callback.forget();
+
+    self.frame += 1;
+    if self.frame < self.frame_count {
+      return Ok(false);
+    }
+    self.resolve.call1(&JsValue::NULL, &self.frame_count.into())?;
+    Ok(true)
+  }
+}
+
+impl Drop for OfflineRender {
+  /// A render dropped before its last frame, after a failed capture, rejects the export
+  fn drop(&mut self) {
+    if self.frame < self.frame_count {
+      let _ = self.reject.call1(&JsValue::NULL, &"The export was interrupted".into());
+    }
+  }
+}
+
+pub(crate) fn take_pending_export() -> Option<OfflineRender> {
+  PENDING_EXPORT.with(|pending| pending.borrow_mut().take())
+}
+
+/// Render the whole music track, or its first `seconds`, at `fps` frames per second of track
+/// time, however long each frame takes to draw. Every frame goes to `on_frame(blob, index)` as a
+/// PNG; the blobs can arrive out of order. Resolves with the number of frames once the last one
+/// was drawn. The timeline's loop is ignored so the export runs straight through the track.
+/// Rejects while another export is still running.
+#[wasm_bindgen]
+pub async fn export_frames(
+  fps: f32,
+  seconds: Option<f32>,
+  on_frame: Function,
+) -> Result<JsValue, JsValue> {
+  if !fps.is_finite() || fps <= 0.0 {
+    return Err(format!("Expected a positive frame rate, got {}", fps).into());
+  }
+  if EXPORTING.with(|exporting| exporting.replace(true)) {
+    return Err("An export is already running".into());
+  }
+  let _exporting = ExportGuard;
+  let document = document()?;
+  let track = timeline::find_track(&document)?
+    .ok_or_else(|| DemoError::MissingElement("the music track".to_string()))?;
+  track.pause()?;
+
+  let response: Response =
+    JsFuture::from(window().fetch_with_str(&track.current_src())).await?.dyn_into()?;
+  let data = JsFuture::from(response.array_buffer()?).await?.dyn_into()?;
+  let context = web_sys::AudioContext::new()?;
+  let decoded: AudioBuffer = JsFuture::from(context.decode_audio_data(&data)?).await?.dyn_into()?;
+  let _ = context.close();
+
+  let duration = seconds.unwrap_or(f32::MAX).min(decoded.duration() as f32);
+  let frame_count = (duration * fps).ceil() as u32;
+  if frame_count == 0 {
+    return Ok(0.into());
+  }
+  let analyser = OfflineAnalyser::new(&decoded)?;
+
+  let (mut resolve, mut reject) = (None, None);
+  let done = Promise::new(&mut |resolve_done, reject_done| {
+    resolve = Some(resolve_done);
+    reject = Some(reject_done);
+  });
+  let render = OfflineRender {
+    analyser,
+    audio_frame: AudioFrame::default(),
+    fps,
+    frame: 0,
+    frame_count,
+    on_frame,
+    resolve: resolve.ok_or("Failed to create the export promise")?,
+    reject: reject.ok_or("Failed to create the export promise")?,
+  };
+  PENDING_EXPORT.with(|pending| *pending.borrow_mut() = Some(render));
+  JsFuture::from(done).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::sine;
+
+  const RATE: f32 = 48000.0;
+
+  /// A steady sine centred on bin `bin` of the mix's spectrum at `rate`, `seconds` long
+  fn analyser_at(rate: f32, bin: usize, amplitude: f32, seconds: f32) -> OfflineAnalyser {
+    let frequency = bin as f32 * rate / pitch::block_length(rate) as f32;
+    let samples = sine(frequency, amplitude, rate, (seconds * rate) as usize);
+    OfflineAnalyser::from_samples(samples.clone(), samples.clone(), samples, rate)
+  }
+
+  fn analyser(bin: usize, amplitude: f32, seconds: f32) -> OfflineAnalyser {
+    analyser_at(RATE, bin, amplitude, seconds)
+  }
+
+  #[test]
+  fn windows_outside_the_track_are_silent() {
+    let mut analyser = analyser(10, 0.5, 1.0);
+    let mut frame = AudioFrame::default();
+    analyser.analyse(0.0, &mut frame);
+    assert_eq!(frame.rms, 0.0);
+    assert!(frame.bands.iter().all(|&band| band == 0.0), "{:?}", frame.bands);
+
+    // Half the window is before the start, the sine's RMS over the other half
+    analyser.analyse(analyser.fft_size as f32 / 2.0 / RATE, &mut frame);
+    assert!((frame.rms - 0.25).abs() < 0.01, "{}", frame.rms);
+    analyser.analyse(0.5, &mut frame);
+    assert!((frame.rms - 0.5 / 2.0f32.sqrt()).abs() < 0.01, "{}", frame.rms);
+
+    analyser.analyse(2.0, &mut frame);
+    assert_eq!(frame.rms, 0.0);
+  }
+
+  #[test]
+  fn bands_are_smoothed_and_quantised_like_the_analyser_node() {
+    // Quiet enough to stay between the decibel limits
+    let mut analyser = analyser(10, 0.001, 2.0);
+    let mut frame = AudioFrame::default();
+    let mut peaks = Vec::new();
+    for step in 1..=20 {
+      analyser.analyse(0.05 * step as f32, &mut frame);
+      assert_eq!(frame.bands.len(), BAND_COUNT);
+      for band in &frame.bands {
+        assert_eq!((band * 255.0).round(), band * 255.0, "{} is not a byte", band);
+      }
+      peaks.push(frame.bands[10]);
+    }
+    // The tone's bin is the loudest, and climbs towards its level instead of jumping there
+    let loudest = frame.bands.iter().cloned().fold(0.0, f32::max);
+    assert_eq!(frame.bands[10], loudest);
+    assert!(peaks[1] > peaks[0] && peaks[19] > peaks[1] && peaks[19] < 1.0, "{:?}", peaks);
+    assert!(peaks[19] - peaks[18] < peaks[1] - peaks[0], "{:?}", peaks);
+  }
+  #[test]
+  fn the_mix_is_windowed_like_the_live_analyser_at_studio_rates() {
+    // At 96 kHz the bins of a 4096 sample window are as wide as the live analyser's
+    let rate = 96000.0;
+    let mut analyser = analyser_at(rate, 6, 0.001, 1.0);
+    assert_eq!(analyser.fft_size, 4096);
+    let mut frame = AudioFrame::default();
+    for step in 1..=10 {
+      analyser.analyse(0.05 * step as f32, &mut frame);
+    }
+    let loudest = frame.bands.iter().cloned().fold(0.0, f32::max);
+    assert!(loudest > 0.0);
+    assert_eq!(frame.bands[6], loudest, "{:?}", frame.bands);
+  }
+}
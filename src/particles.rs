@@ -0,0 +1,447 @@
+use crate::{
+  buffer_attrib::{self, BufferAttrib},
+  error::{self, DemoError},
+  program_info::ProgramInfo,
+};
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+use web_sys::{
+  WebGl2RenderingContext, WebGlBuffer, WebGlTransformFeedback, WebGlUniformLocation,
+  WebGlVertexArrayObject,
+};
+
+/// Floats per particle: position (3), velocity (3), age (1) and lifetime (1)
+const PARTICLE_FLOATS: usize = 8;
+
+const MAX_ATTRACTORS: usize = 4;
+
+/// Integrates every particle one step and writes the result out through transform feedback
+const UPDATE_VERT_SOURCE: &str = r#"#version 300 es
+    precision highp float;
+
+    in vec3 a_position;
+    in vec3 a_velocity;
+    in float a_age;
+    in float a_life;
+
+    uniform float u_time;
+    uniform float u_dt;
+    uniform vec3 u_gravity;
+    uniform float u_curl_strength;
+    uniform float u_curl_scale;
+    // xyz is the position, w the strength
+    uniform vec4 u_attractors[4];
+    uniform float u_spawn_probability;
+    uniform float u_emit_speed;
+
+    out vec3 v_position;
+    out vec3 v_velocity;
+    out float v_age;
+    out float v_life;
+
+    float hash(float n) {
+      return fract(sin(n) * 43758.5453123);
+    }
+
+    // Curl of a sum of sines, divergence free so particles swirl instead of bunching up
+    vec3 curl(vec3 p) {
+      return vec3(
+        cos(p.y) - cos(p.z),
+        cos(p.z) - cos(p.x),
+        cos(p.x) - cos(p.y)
+      ) + 0.5 * vec3(
+        sin(p.z * 1.7) - sin(p.y * 1.3),
+        sin(p.x * 1.3) - sin(p.z * 1.7),
+        sin(p.y * 1.7) - sin(p.x * 1.3)
+      );
+    }
+
+    void main() {
+      float id = float(gl_VertexID);
+      float age = a_age + u_dt;
+
+      if (age >= a_life) {
+        if (hash(id * 0.618 + u_time) < u_spawn_probability) {
+          vec3 direction = vec3(
+            hash(id * 1.3 + u_time) - 0.5,
+            hash(id * 2.7 + u_time) - 0.5,
+            hash(id * 4.1 + u_time) - 0.5
+          );
+          v_position = vec3(0.0);
+          v_velocity = normalize(direction + 0.0001) * u_emit_speed * (0.5 + hash(id * 5.3 + u_time));
+          v_age = 0.0;
+          v_life = 1.0 + 2.0 * hash(id * 7.7 + u_time);
+        } else {
+          v_position = a_position;
+          v_velocity = vec3(0.0);
+          v_age = age;
+          v_life = a_life;
+        }
+        return;
+      }
+
+      vec3 force = u_gravity + curl(a_position * u_curl_scale + u_time * 0.1) * u_curl_strength;
+      for (int i = 0; i < 4; i++) {
+        vec3 to_attractor = u_attractors[i].xyz - a_position;
+        force += normalize(to_attractor + 0.0001) * u_attractors[i].w / (1.0 + dot(to_attractor, to_attractor));
+      }
+
+      vec3 velocity = a_velocity + force * u_dt;
+      v_position = a_position + velocity * u_dt;
+      v_velocity = velocity;
+      v_age = age;
+      v_life = a_life;
+    }
+  "#;
+
+/// Transform feedback discards the rasterizer output, but a program still needs a fragment shader
+const UPDATE_FRAG_SOURCE: &str = r#"#version 300 es
+    precision mediump float;
+    out vec4 color;
+
+    void main() {
+      color = vec4(0.0);
+    }
+"#;
+
+const RENDER_VERT_SOURCE: &str = r#"#version 300 es
+    in vec3 a_position;
+    in float a_age;
+    in float a_life;
+
+    uniform mat4 u_model_view_matrix;
+    uniform mat4 u_projection_matrix;
+    uniform float u_point_size;
+
+    out float v_fade;
+
+    void main() {
+      if (a_age >= a_life) {
+        // Dead particles are moved outside of the clip volume
+        gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+        gl_PointSize = 0.0;
+        v_fade = 0.0;
+        return;
+      }
+      vec4 position = u_model_view_matrix * vec4(a_position, 1.0);
+      gl_Position = u_projection_matrix * position;
+      gl_PointSize = u_point_size / max(-position.z, 0.1);
+      v_fade = 1.0 - a_age / a_life;
+    }
+  "#;
+
+const RENDER_FRAG_SOURCE: &str = r#"#version 300 es
+    precision mediump float;
+
+    uniform vec4 u_color;
+    in float v_fade;
+    out vec4 color;
+
+    void main() {
+      vec2 from_center = gl_PointCoord * 2.0 - 1.0;
+      if (dot(from_center, from_center) > 1.0) {
+        discard;
+      }
+      color = vec4(u_color.rgb, u_color.a * v_fade);
+    }
+"#;
+
+/// The knobs of the simulation for one frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParticleParams {
+  /// Fraction of the dead particles respawned per second
+  pub emission_rate: f32,
+  /// Units per second a new particle starts out with
+  pub speed: f32,
+  pub color: [f32; 4],
+  pub gravity: [f32; 3],
+  pub curl_strength: f32,
+  pub curl_scale: f32,
+  /// Up to `MAX_ATTRACTORS` of `[x, y, z, strength]`
+  pub attractors: Vec<[f32; 4]>,
+}
+
+impl Default for ParticleParams {
+  fn default() -> Self {
+    ParticleParams {
+      emission_rate: 0.5,
+      speed: 1.0,
+      color: [1.0, 0.8, 0.4, 0.8],
+      gravity: [0.0, -0.3, 0.0],
+      curl_strength: 0.8,
+      curl_scale: 0.7,
+      attractors: vec![[0.0, 0.0, 0.0, 0.5]],
+    }
+  }
+}
+
+/// How band energies and beats push the base `ParticleParams` around, set by the preset's
+/// `particles` field. Fields left out keep their default.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParticleAudioBinding {
+  pub emission_band: usize,
+  pub emission_amount: f32,
+  pub speed_band: usize,
+  pub speed_amount: f32,
+  /// The color is faded towards `color_target` by the energy of `color_band`
+  pub color_band: usize,
+  pub color_target: [f32; 4],
+  /// Respawn every dead particle on a beat
+  pub burst_on_beat: bool,
+}
+
+impl Default for ParticleAudioBinding {
+  fn default() -> Self {
+    ParticleAudioBinding {
+      emission_band: 2,
+      emission_amount: 4.0,
+      speed_band: 0,
+      speed_amount: 3.0,
+      color_band: 8,
+      color_target: [0.4, 0.7, 1.0, 1.0],
+      burst_on_beat: true,
+    }
+  }
+}
+
+impl ParticleAudioBinding {
+  pub fn apply(&self, base: &ParticleParams, bands: &[f32], beat: bool) -> ParticleParams {
+    let band = |index: usize| bands.get(index).copied().unwrap_or(0.0);
+    let mut params = base.clone();
+    params.emission_rate += band(self.emission_band) * self.emission_amount;
+    if beat && self.burst_on_beat {
+      params.emission_rate = f32::INFINITY;
+    }
+    params.speed += band(self.speed_band) * self.speed_amount;
+    let mix = band(self.color_band);
+    for (channel, target) in params.color.iter_mut().zip(&self.color_target) {
+      *channel += (target - *channel) * mix;
+    }
+    params
+  }
+}
+
+/// Particles simulated entirely on the GPU. The state lives in two buffers, each frame reads one
+/// and writes the other through transform feedback, then they swap.
+pub struct ParticleSystem {
+  update_program: ProgramInfo,
+  render_program: ProgramInfo,
+  buffers: [WebGlBuffer; 2],
+  /// Vertex arrays reading `buffers[i]` for the update and render programs
+  update_vertex_arrays: [WebGlVertexArrayObject; 2],
+  render_vertex_arrays: [WebGlVertexArrayObject; 2],
+  transform_feedback: WebGlTransformFeedback,
+  /// Index of the buffer holding the latest state
+  current: usize,
+  count: i32,
+  /// How many of the particles are simulated and drawn, the rest stay frozen
+  active: i32,
+  pub base: ParticleParams,
+  params: ParticleParams,
+}
+
+impl ParticleSystem {
+  pub(crate) fn new(gl_context: &WebGl2RenderingContext, count: usize) -> Result<Self, JsValue> {
+    let update_program = ProgramInfo::with_feedback_varyings(
+      gl_context,
+      UPDATE_VERT_SOURCE,
+      UPDATE_FRAG_SOURCE,
+      &["v_position", "v_velocity", "v_age", "v_life"],
+    )?;
+    let render_program = ProgramInfo::new(gl_context, RENDER_VERT_SOURCE, RENDER_FRAG_SOURCE)?;
+
+    // Every particle starts out dead, with an age past its lifetime
+    let mut initial = vec![0.0f32; count * PARTICLE_FLOATS];
+    for particle in initial.chunks_mut(PARTICLE_FLOATS) {
+      particle[6] = 1.0;
+    }
+    let buffers =
+      [init_particle_buffer(gl_context, &initial)?, init_particle_buffer(gl_context, &initial)?];
+
+    let update_vertex_arrays = [
+      create_vertex_array(gl_context, &update_program, &buffers[0])?,
+      create_vertex_array(gl_context, &update_program, &buffers[1])?,
+    ];
+    let render_vertex_arrays = [
+      create_vertex_array(gl_context, &render_program, &buffers[0])?,
+      create_vertex_array(gl_context, &render_program, &buffers[1])?,
+    ];
+
+    let transform_feedback =
+      error::created(gl_context, gl_context.create_transform_feedback(), "a transform feedback")?;
+
+    let base = ParticleParams::default();
+    Ok(ParticleSystem {
+      update_program,
+      render_program,
+      buffers,
+      update_vertex_arrays,
+      render_vertex_arrays,
+      transform_feedback,
+      current: 0,
+      count: count as i32,
+      active: count as i32,
+      params: base.clone(),
+      base,
+    })
+  }
+
+  /// Simulate and draw only `fraction` of the particles
+  pub(crate) fn set_active_fraction(&mut self, fraction: f32) {
+    self.active = (self.count as f32 * fraction.clamp(0.0, 1.0)) as i32;
+  }
+
+  /// Step the simulation by `dt` seconds, reacting to the current band energies and `beat`, the
+  /// render loop's detection for this frame, through `binding`
+  pub(crate) fn update(
+    &mut self,
+    gl_context: &WebGl2RenderingContext,
+    time: f32,
+    dt: f32,
+    bands: &[f32],
+    beat: bool,
+    binding: &ParticleAudioBinding,
+  ) {
+    self.params = binding.apply(&self.base, bands, beat);
+    let params = &self.params;
+
+    let next = 1 - self.current;
+    let program_info = &self.update_program;
+    gl_context.use_program(Some(&program_info.program));
+    let uniform = |name: &str| uniform_location(program_info, name);
+    gl_context.uniform1f(uniform("u_time"), time);
+    gl_context.uniform1f(uniform("u_dt"), dt);
+    gl_context.uniform3fv_with_f32_array(uniform("u_gravity"), &params.gravity);
+    gl_context.uniform1f(uniform("u_curl_strength"), params.curl_strength);
+    gl_context.uniform1f(uniform("u_curl_scale"), params.curl_scale);
+    gl_context.uniform1f(uniform("u_spawn_probability"), (params.emission_rate * dt).min(1.0));
+    gl_context.uniform1f(uniform("u_emit_speed"), params.speed);
+    let mut attractors = [0.0f32; MAX_ATTRACTORS * 4];
+    for (slot, attractor) in attractors.chunks_mut(4).zip(&params.attractors) {
+      slot.copy_from_slice(attractor);
+    }
+    gl_context.uniform4fv_with_f32_array(uniform("u_attractors[0]"), &attractors);
+
+    // Read the current state, capture the next one and skip rasterizing entirely
+    gl_context.bind_vertex_array(Some(&self.update_vertex_arrays[self.current]));
+    gl_context.bind_transform_feedback(
+      WebGl2RenderingContext::TRANSFORM_FEEDBACK,
+      Some(&self.transform_feedback),
+    );
+    gl_context.bind_buffer_base(
+      WebGl2RenderingContext::TRANSFORM_FEEDBACK_BUFFER,
+      0,
+      Some(&self.buffers[next]),
+    );
+    gl_context.enable(WebGl2RenderingContext::RASTERIZER_DISCARD);
+    gl_context.begin_transform_feedback(WebGl2RenderingContext::POINTS);
+    gl_context.draw_arrays(WebGl2RenderingContext::POINTS, 0, self.active);
+    gl_context.end_transform_feedback();
+    gl_context.disable(WebGl2RenderingContext::RASTERIZER_DISCARD);
+    gl_context.bind_buffer_base(WebGl2RenderingContext::TRANSFORM_FEEDBACK_BUFFER, 0, None);
+    gl_context.bind_transform_feedback(WebGl2RenderingContext::TRANSFORM_FEEDBACK, None);
+    gl_context.bind_vertex_array(None);
+
+    self.current = next;
+  }
+
+  /// Draw the latest state as additive round points
+  pub(crate) fn draw(
+    &self,
+    gl_context: &WebGl2RenderingContext,
+    model_view_matrix: &[f32; 16],
+    projection_matrix: &[f32; 16],
+  ) {
+    let program_info = &self.render_program;
+    gl_context.use_program(Some(&program_info.program));
+    let uniform = |name: &str| uniform_location(program_info, name);
+    gl_context.uniform_matrix4fv_with_f32_array(
+      uniform("u_model_view_matrix"),
+      false,
+      model_view_matrix,
+    );
+    gl_context.uniform_matrix4fv_with_f32_array(
+      uniform("u_projection_matrix"),
+      false,
+      projection_matrix,
+    );
+    gl_context.uniform1f(uniform("u_point_size"), 40.0);
+    gl_context.uniform4fv_with_f32_array(uniform("u_color"), &self.params.color);
+
+    // Particles glow on top of each other and do not hide what is behind them
+    gl_context.enable(WebGl2RenderingContext::BLEND);
+    gl_context.blend_func(WebGl2RenderingContext::SRC_ALPHA, WebGl2RenderingContext::ONE);
+    gl_context.depth_mask(false);
+
+    gl_context.bind_vertex_array(Some(&self.render_vertex_arrays[self.current]));
+    gl_context.draw_arrays(WebGl2RenderingContext::POINTS, 0, self.active);
+    gl_context.bind_vertex_array(None);
+
+    gl_context.depth_mask(true);
+    gl_context.disable(WebGl2RenderingContext::BLEND);
+  }
+}
+
+fn uniform_location<'a>(
+  program_info: &'a ProgramInfo,
+  name: &str,
+) -> Option<&'a WebGlUniformLocation> {
+  program_info.uniform_locations.get(name).and_then(|location| location.as_ref())
+}
+
+fn init_particle_buffer(
+  gl_context: &WebGl2RenderingContext,
+  data: &[f32],
+) -> Result<WebGlBuffer, JsValue> {
+  let buffer = gl_context
+    .create_buffer()
+    .ok_or_else(|| DemoError::BufferCreation("a particle buffer".to_string()))?;
+  gl_context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
+  // `view` is only valid until the next wasm allocation, which cannot happen before the upload
+  unsafe {
+    let data_array = js_sys::Float32Array::view(data);
+    gl_context.buffer_data_with_array_buffer_view(
+      WebGl2RenderingContext::ARRAY_BUFFER,
+      &data_array,
+      WebGl2RenderingContext::DYNAMIC_COPY,
+    );
+  }
+  gl_context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, None);
+  Ok(buffer)
+}
+
+/// Point every attribute `program_info` uses at its slice of the interleaved particle layout
+fn create_vertex_array(
+  gl_context: &WebGl2RenderingContext,
+  program_info: &ProgramInfo,
+  buffer: &WebGlBuffer,
+) -> Result<WebGlVertexArrayObject, JsValue> {
+  let vertex_array =
+    error::created(gl_context, gl_context.create_vertex_array(), "a vertex array object")?;
+  gl_context.bind_vertex_array(Some(&vertex_array));
+
+  let float = std::mem::size_of::<f32>() as i32;
+  let layout = [("a_position", 3, 0), ("a_velocity", 3, 3), ("a_age", 1, 6), ("a_life", 1, 7)];
+  for &(name, num_components, first_float) in &layout {
+    // The render program does not use the velocity
+    if let Some(&attribute) = program_info.attrib_locations.get(name) {
+      let buffer_attrib = BufferAttrib {
+        name: name.into(),
+        buffer,
+        target: WebGl2RenderingContext::ARRAY_BUFFER,
+        num_components,
+        buffer_type: WebGl2RenderingContext::FLOAT,
+        normalize: false,
+        stride: PARTICLE_FLOATS as i32 * float,
+        offset: first_float * float,
+        divisor: 0,
+      };
+      buffer_attrib::bind_buffer_to_attrib(gl_context, &buffer_attrib, attribute as u32)?;
+    }
+  }
+
+  gl_context.bind_vertex_array(None);
+  gl_context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, None);
+  Ok(vertex_array)
+}
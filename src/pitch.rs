@@ -0,0 +1,342 @@
+use crate::fm_osc::{freq_to_midi, midi_to_freq};
+use std::f32::consts::PI;
+
+/// Range the pitch tracker looks in, from a low male voice or a bass guitar's upper strings to
+/// the top of a soprano
+const MIN_FREQUENCY: f32 = 60.0;
+const MAX_FREQUENCY: f32 = 1500.0;
+/// How far below its average the normalised difference has to dip for a period to count
+const YIN_THRESHOLD: f32 = 0.15;
+/// Blocks quieter than this RMS are not tracked, their "pitch" would be noise
+const SILENCE: f32 = 1e-3;
+/// The notes folded into the chromagram, C3 to B6. Lower notes are closer together than a block
+/// can tell apart, higher ones are mostly overtones.
+const CHROMA_NOTES: std::ops::RangeInclusive<u8> = 48..=95;
+/// Seconds of chroma the key is estimated from, keys change far slower than chords
+const KEY_WINDOW: f32 = 8.0;
+
+/// Krumhansl and Kessler's ratings of how well each pitch class fits a major and a minor key,
+/// starting at the tonic
+const MAJOR_PROFILE: [f32; 12] =
+  [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f32; 12] =
+  [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+/// A detected fundamental
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pitch {
+  pub frequency: f32,
+  /// Nearest midi note and the deviation from it in cents
+  pub note: u8,
+  pub cents: f32,
+  /// `1.0` for a pure periodic tone, towards `0.0` as the period gets harder to make out
+  pub clarity: f32,
+}
+
+/// The most likely key, pitch classes count from `0` for C
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Key {
+  pub tonic: u8,
+  pub minor: bool,
+  /// Correlation of the chroma with the key's profile, `1.0` a perfect fit
+  pub strength: f32,
+}
+
+/// Everything harmonic the audio loop measured on its latest frame
+#[derive(Clone, Debug, Default)]
+pub struct HarmonyFrame {
+  /// `None` while nothing periodic is playing
+  pub pitch: Option<Pitch>,
+  /// Midi note of the latest pitch, with cents as the fraction, held through silence so colours
+  /// mapped from it do not jump back
+  pub note: f32,
+  /// Energy of every pitch class, the strongest at `1.0`
+  pub chroma: [f32; 12],
+  pub key: Option<Key>,
+}
+
+/// Longest lag the tracker compares, a sample past the period of `MIN_FREQUENCY` so a dip right
+/// at it still has a neighbour on either side
+fn max_lag(sample_rate: f32) -> usize {
+  (sample_rate / MIN_FREQUENCY).ceil() as usize + 1
+}
+
+/// Samples a block needs for `detect_pitch` to reach `MIN_FREQUENCY` at `sample_rate`, as a power
+/// of two for an `AnalyserNode`'s `fftSize`. 2048 up to 48 kHz, more at the rates of studio
+/// interfaces.
+pub fn block_length(sample_rate: f32) -> usize {
+  (2 * max_lag(sample_rate)).next_power_of_two().max(2048)
+}
+
+/// Find the fundamental of `samples` with YIN: the lag at which the block best matches itself
+/// is its period. `None` for silence, for sounds without a clear period, like noise or drums,
+/// and for blocks shorter than `block_length`.
+pub fn detect_pitch(samples: &[f32], sample_rate: f32) -> Option<Pitch> {
+  let min_lag = ((sample_rate / MAX_FREQUENCY) as usize).max(2);
+  let max_lag = max_lag(sample_rate);
+  // The lags are compared over what is left of the block, which has to span a period
+  if samples.len() < 2 * max_lag {
+    return None;
+  }
+  let window = samples.len() - max_lag;
+  let energy = samples[..window].iter().map(|sample| sample * sample).sum::<f32>();
+  if (energy / window as f32).sqrt() < SILENCE {
+    return None;
+  }
+
+  // Squared difference of the block and itself shifted by every lag, normalised by its running
+  // mean so the dip at the period stands out whatever the level
+  let mut normalised = vec![1.0; max_lag + 1];
+  let mut running = 0.0;
+  for lag in 1..=max_lag {
+    let difference: f32 = samples[..window]
+      .iter()
+      .zip(&samples[lag..lag + window])
+      .map(|(a, b)| (a - b) * (a - b))
+      .sum();
+    running += difference;
+    normalised[lag] = if running > 0.0 { difference * lag as f32 / running } else { 1.0 };
+  }
+
+  // The first dip under the threshold, followed to its bottom, rather than the deepest one which
+  // is often a multiple of the period
+  let mut lag = (min_lag..max_lag).find(|&lag| normalised[lag] < YIN_THRESHOLD)?;
+  while lag + 1 < max_lag && normalised[lag + 1] < normalised[lag] {
+    lag += 1;
+  }
+  // Still falling at the last lag, the period is longer than the tracker looks
+  if normalised[lag + 1] < normalised[lag] {
+    return None;
+  }
+
+  // A parabola through the dip and its neighbours finds the period between samples
+  let (before, at, after) = (normalised[lag - 1], normalised[lag], normalised[lag + 1]);
+  let curvature = before + after - 2.0 * at;
+  let shift =
+    if curvature > 0.0 { (0.5 * (before - after) / curvature).clamp(-0.5, 0.5) } else { 0.0 };
+  let frequency = sample_rate / (lag as f32 + shift);
+  let (note, cents) = freq_to_midi(frequency)?;
+  Some(Pitch { frequency, note, cents, clarity: (1.0 - at).clamp(0.0, 1.0) })
+}
+
+/// Fold the energy at every note of `CHROMA_NOTES` into its pitch class, measured one note at a
+/// time with the Goertzel algorithm instead of a whole spectrum. All zero for silence.
+pub fn chromagram(samples: &[f32], sample_rate: f32) -> [f32; 12] {
+  let hann = |index: usize| 0.5 - 0.5 * (2.0 * PI * index as f32 / samples.len() as f32).cos();
+  let windowed: Vec<f32> =
+    samples.iter().enumerate().map(|(index, sample)| sample * hann(index)).collect();
+
+  let mut chroma = [0.0; 12];
+  for note in CHROMA_NOTES {
+    let coefficient = 2.0 * (2.0 * PI * midi_to_freq(note) / sample_rate).cos();
+    let (mut previous, mut before_previous) = (0.0f32, 0.0f32);
+    for sample in &windowed {
+      let current = sample + coefficient * previous - before_previous;
+      before_previous = previous;
+      previous = current;
+    }
+    let power = previous * previous + before_previous * before_previous
+      - coefficient * previous * before_previous;
+    chroma[note as usize % 12] += power.max(0.0).sqrt();
+  }
+
+  // Normalised to the strongest class, unless that is only rounding noise
+  let strongest = chroma.iter().copied().fold(0.0, f32::max);
+  let floor = SILENCE * samples.len() as f32 / 4.0;
+  for class in &mut chroma {
+    *class = if strongest > floor { *class / strongest } else { 0.0 };
+  }
+  chroma
+}
+
+/// The key whose profile correlates best with `chroma`, `None` when all classes are equal
+pub fn estimate_key(chroma: &[f32; 12]) -> Option<Key> {
+  let mut best: Option<Key> = None;
+  for tonic in 0..12 {
+    for (minor, profile) in [(false, &MAJOR_PROFILE), (true, &MINOR_PROFILE)] {
+      let rotated: Vec<f32> = (0..12).map(|class| profile[(class + 12 - tonic) % 12]).collect();
+      let strength = correlation(chroma, &rotated)?;
+      if best.is_none_or(|best| strength > best.strength) {
+        best = Some(Key { tonic: tonic as u8, minor, strength });
+      }
+    }
+  }
+  best
+}
+
+/// Pearson correlation, `None` when either side does not vary
+fn correlation(a: &[f32], b: &[f32]) -> Option<f32> {
+  let mean = |values: &[f32]| values.iter().sum::<f32>() / values.len() as f32;
+  let (mean_a, mean_b) = (mean(a), mean(b));
+  let (mut product, mut power_a, mut power_b) = (0.0, 0.0, 0.0);
+  for (x, y) in a.iter().zip(b) {
+    product += (x - mean_a) * (y - mean_b);
+    power_a += (x - mean_a) * (x - mean_a);
+    power_b += (y - mean_b) * (y - mean_b);
+  }
+  let energy = (power_a * power_b).sqrt();
+  if energy > 0.0 {
+    Some(product / energy)
+  } else {
+    None
+  }
+}
+
+/// Follows pitch, chroma and key from block to block
+#[derive(Default)]
+pub struct HarmonyTracker {
+  /// Chroma averaged over `KEY_WINDOW`
+  key_chroma: [f32; 12],
+  last_time: Option<f32>,
+}
+
+impl HarmonyTracker {
+  /// Analyse the block of `samples` ending at `time` seconds
+  pub fn update(&mut self, samples: &[f32], sample_rate: f32, time: f32, frame: &mut HarmonyFrame) {
+    let dt = self.last_time.map_or(0.0, |last_time| (time - last_time).max(0.0));
+    self.last_time = Some(time);
+
+    frame.pitch = detect_pitch(samples, sample_rate);
+    if let Some(pitch) = frame.pitch {
+      frame.note = pitch.note as f32 + pitch.cents / 100.0;
+    }
+    frame.chroma = chromagram(samples, sample_rate);
+
+    // Silence leaves the key where it was, the first sound sets it
+    if frame.chroma.iter().any(|&class| class > 0.0) {
+      let amount = if self.key_chroma == [0.0; 12] { 1.0 } else { 1.0 - (-dt / KEY_WINDOW).exp() };
+      for (average, class) in self.key_chroma.iter_mut().zip(frame.chroma) {
+        *average += (class - *average) * amount;
+      }
+      frame.key = estimate_key(&self.key_chroma);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::noise;
+
+  const SAMPLE_RATE: f32 = 44100.0;
+
+  /// `length` samples of a tone at `frequency` with a few decaying overtones, like an instrument
+  fn tone(frequency: f32, length: usize) -> Vec<f32> {
+    tone_at(frequency, SAMPLE_RATE, length)
+  }
+
+  fn tone_at(frequency: f32, sample_rate: f32, length: usize) -> Vec<f32> {
+    (0..length)
+      .map(|index| {
+        let t = index as f32 / sample_rate;
+        (1..=4)
+          .map(|harmonic| (2.0 * PI * frequency * harmonic as f32 * t).sin() / harmonic as f32)
+          .sum::<f32>()
+          * 0.3
+      })
+      .collect()
+  }
+
+  #[test]
+  fn midi_round_trips() {
+    for note in 0..=127 {
+      let (back, cents) = freq_to_midi(midi_to_freq(note)).unwrap();
+      assert_eq!(back, note);
+      assert!(cents.abs() < 0.1, "note {} is {} cents off", note, cents);
+    }
+    // Almost a quarter tone flat of A4
+    let (note, cents) = freq_to_midi(428.0).unwrap();
+    assert_eq!(note, 69);
+    assert!((cents + 47.9).abs() < 0.1, "{}", cents);
+    assert_eq!(freq_to_midi(0.0), None);
+    assert_eq!(freq_to_midi(-440.0), None);
+    assert_eq!(freq_to_midi(20000.0), None);
+  }
+
+  #[test]
+  fn tracks_tones_across_the_range() {
+    for frequency in [82.41, 110.0, 261.63, 440.0, 466.16, 987.77] {
+      let pitch = detect_pitch(&tone(frequency, 2048), SAMPLE_RATE).unwrap();
+      let error = 1200.0 * (pitch.frequency / frequency).log2();
+      assert!(error.abs() < 5.0, "{} Hz read as {} Hz", frequency, pitch.frequency);
+      assert!(pitch.clarity > 0.9);
+    }
+    let pitch = detect_pitch(&tone(440.0, 2048), SAMPLE_RATE).unwrap();
+    assert_eq!(pitch.note, 69);
+  }
+
+  #[test]
+  fn low_notes_at_the_edge_of_the_range_do_not_panic() {
+    for sample_rate in [44100.0, 48000.0] {
+      // A1 and B♭1 are below the range, mains hum right at its edge
+      for frequency in [55.0, 58.27] {
+        assert_eq!(detect_pitch(&tone_at(frequency, sample_rate, 2048), sample_rate), None);
+      }
+      let pitch = detect_pitch(&tone_at(60.0, sample_rate, 2048), sample_rate).unwrap();
+      assert!((pitch.frequency - 60.0).abs() < 0.5, "{} Hz", pitch.frequency);
+    }
+  }
+
+  #[test]
+  fn studio_rates_get_long_enough_blocks() {
+    assert_eq!(block_length(44100.0), 2048);
+    assert_eq!(block_length(48000.0), 2048);
+    for sample_rate in [88200.0, 96000.0] {
+      let length = block_length(sample_rate);
+      assert_eq!(length, 4096);
+      assert_eq!(detect_pitch(&tone_at(110.0, sample_rate, 2048), sample_rate), None);
+      let pitch = detect_pitch(&tone_at(110.0, sample_rate, length), sample_rate).unwrap();
+      assert_eq!(pitch.note, 45);
+      assert!(detect_pitch(&tone_at(60.0, sample_rate, length), sample_rate).is_some());
+    }
+  }
+
+  #[test]
+  fn silence_and_noise_have_no_pitch() {
+    assert_eq!(detect_pitch(&[0.0; 2048], SAMPLE_RATE), None);
+    assert_eq!(detect_pitch(&noise(2048), SAMPLE_RATE), None);
+  }
+
+  #[test]
+  fn chroma_peaks_at_the_played_class() {
+    let chroma = chromagram(&tone(midi_to_freq(67), 4096), SAMPLE_RATE);
+    let strongest = (0..12).max_by(|&a, &b| chroma[a].total_cmp(&chroma[b])).unwrap();
+    assert_eq!(strongest, 7);
+    assert_eq!(chromagram(&[0.0; 4096], SAMPLE_RATE), [0.0; 12]);
+  }
+
+  #[test]
+  fn estimates_keys_from_their_triads() {
+    // C major and A minor triads, weighted like a chroma would be
+    let mut c_major = [0.0; 12];
+    for (class, weight) in [(0, 1.0), (4, 0.8), (7, 0.9)] {
+      c_major[class] = weight;
+    }
+    let key = estimate_key(&c_major).unwrap();
+    assert_eq!((key.tonic, key.minor), (0, false));
+
+    let mut a_minor = [0.0; 12];
+    for (class, weight) in [(9, 1.0), (0, 0.8), (4, 0.9)] {
+      a_minor[class] = weight;
+    }
+    let key = estimate_key(&a_minor).unwrap();
+    assert_eq!((key.tonic, key.minor), (9, true));
+
+    assert_eq!(estimate_key(&[0.5; 12]), None);
+  }
+
+  #[test]
+  fn holds_the_note_through_silence() {
+    let mut tracker = HarmonyTracker::default();
+    let mut frame = HarmonyFrame::default();
+    tracker.update(&tone(440.0, 2048), SAMPLE_RATE, 0.0, &mut frame);
+    assert!((frame.note - 69.0).abs() < 0.1);
+    let key = frame.key;
+    assert!(key.is_some());
+
+    tracker.update(&[0.0; 2048], SAMPLE_RATE, 0.05, &mut frame);
+    assert_eq!(frame.pitch, None);
+    assert!((frame.note - 69.0).abs() < 0.1);
+    assert_eq!(frame.key, key);
+  }
+}
@@ -0,0 +1,307 @@
+use crate::{document, window};
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{BroadcastChannel, HtmlCanvasElement, KeyboardEvent, MessageEvent};
+
+/// Name of the `BroadcastChannel` shared with the controls window
+pub(crate) const CONTROL_CHANNEL: &str = "democ-controls";
+
+/// Page opened in the secondary window that holds the performance controls
+const CONTROLS_URL: &str = "controls.html";
+
+/// How long the mouse has to stay still over the canvas before the cursor is hidden
+const CURSOR_HIDE_DELAY_MS: i32 = 2000;
+
+/// Class toggled on the canvas to hide the cursor while presenting
+const HIDE_CURSOR_CLASS: &str = "hide-cursor";
+
+/// Size of the stills saved with the screenshot shortcut
+const SCREENSHOT_SIZE: (u32, u32) = (3840, 2160);
+
+/// An action requested from the keyboard or from the controls window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+  NextScene,
+  PrevScene,
+  Scene(usize),
+  ToggleFullscreen,
+  ToggleBlackout,
+  OpenControls,
+  Screenshot,
+  ToggleStats,
+}
+
+impl Command {
+  /// Keyboard shortcuts, usable without any UI showing on the projector
+  pub fn from_key(key: &str) -> Option<Command> {
+    match key {
+      "ArrowRight" | " " | "PageDown" => Some(Command::NextScene),
+      "ArrowLeft" | "PageUp" => Some(Command::PrevScene),
+      "f" | "F" => Some(Command::ToggleFullscreen),
+      "b" | "B" => Some(Command::ToggleBlackout),
+      "c" | "C" => Some(Command::OpenControls),
+      "p" | "P" => Some(Command::Screenshot),
+      "i" | "I" => Some(Command::ToggleStats),
+      _ => match key.parse::<usize>() {
+        Ok(n) if n >= 1 => Some(Command::Scene(n - 1)),
+        _ => None,
+      },
+    }
+  }
+
+  /// Messages posted by the controls window, e.g. `next`, `prev`, `scene:2`, `blackout`
+  pub fn from_message(message: &str) -> Option<Command> {
+    match message {
+      "next" => Some(Command::NextScene),
+      "prev" => Some(Command::PrevScene),
+      "fullscreen" => Some(Command::ToggleFullscreen),
+      "blackout" => Some(Command::ToggleBlackout),
+      "screenshot" => Some(Command::Screenshot),
+      "stats" => Some(Command::ToggleStats),
+      _ => {
+        let index = message.strip_prefix("scene:")?;
+        index.parse().ok().map(Command::Scene)
+      }
+    }
+  }
+}
+
+/// What the render loop needs to know about the current presentation
+pub struct PresentationState {
+  pub scene: usize,
+  /// Names of the preset's scenes, empty for those without one
+  pub scene_names: Vec<String>,
+  pub blackout: bool,
+}
+
+impl PresentationState {
+  pub fn new(scene_names: Vec<String>) -> Self {
+    PresentationState { scene: 0, scene_names, blackout: false }
+  }
+
+  pub fn scene_count(&self) -> usize {
+    self.scene_names.len()
+  }
+
+  /// Start over from the first of a new preset's scenes
+  pub fn set_scenes(&mut self, scene_names: Vec<String>) {
+    self.scene_names = scene_names;
+    self.scene = 0;
+  }
+
+  /// Apply the scene related commands, the others are handled by the browser side
+  pub fn apply(&mut self, command: Command) {
+    let scene_count = self.scene_count();
+    match command {
+      Command::NextScene => self.scene = (self.scene + 1) % scene_count,
+      Command::PrevScene => self.scene = (self.scene + scene_count - 1) % scene_count,
+      Command::Scene(index) if index < scene_count => self.scene = index,
+      Command::ToggleBlackout => self.blackout = !self.blackout,
+      _ => {}
+    }
+  }
+
+  /// Status message sent back to the controls window, e.g. `status:2:0`
+  pub fn status(&self) -> String {
+    format!("status:{}:{}", self.scene, self.blackout as u8)
+  }
+
+  /// Scene list the controls window builds its buttons from, e.g. `scenes:["intro","drop"]`
+  pub fn scenes(&self) -> String {
+    format!("scenes:{}", serde_json::to_string(&self.scene_names).unwrap_or_default())
+  }
+}
+
+/// Tell an open controls window about the scenes of a newly loaded preset
+pub(crate) fn announce_scenes(state: &PresentationState) -> Result<(), JsValue> {
+  let channel = BroadcastChannel::new(CONTROL_CHANNEL)?;
+  channel.post_message(&state.scenes().into())?;
+  channel.post_message(&state.status().into())?;
+  channel.close();
+  Ok(())
+}
+
+/// Hook up fullscreen, cursor hiding, keyboard shortcuts and the controls channel
+pub(crate) fn init(
+  canvas: &HtmlCanvasElement,
+  state: Rc<RefCell<PresentationState>>,
+) -> Result<(), JsValue> {
+  let channel = Rc::new(BroadcastChannel::new(CONTROL_CHANNEL)?);
+
+  // Keyboard shortcuts
+  {
+    let canvas = canvas.clone();
+    let state = state.clone();
+    let channel = channel.clone();
+    let closure = Closure::wrap(Box::new(move |event: KeyboardEvent| {
+      if let Some(command) = Command::from_key(&event.key()) {
+        event.prevent_default();
+        handle_command(&canvas, &state, &channel, command);
+      }
+    }) as Box<dyn FnMut(KeyboardEvent)>);
+    window().add_event_listener_with_callback("keydown", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  // Commands from the controls window
+  {
+    let canvas = canvas.clone();
+    let state = state.clone();
+    let sender = channel.clone();
+    let closure = Closure::wrap(Box::new(move |event: MessageEvent| {
+      let message = event.data().as_string().unwrap_or_default();
+      if message == "hello" {
+        // A freshly opened controls window asks for the scenes and the current state
+        let state = state.borrow();
+        let _ = sender.post_message(&state.scenes().into());
+        let _ = sender.post_message(&state.status().into());
+      } else if let Some(command) = Command::from_message(&message) {
+        handle_command(&canvas, &state, &sender, command);
+      }
+    }) as Box<dyn FnMut(MessageEvent)>);
+    channel.set_onmessage(Some(closure.as_ref().unchecked_ref()));
+    closure.forget();
+  }
+
+  // The present button on the page goes straight to fullscreen
+  let document = document()?;
+  if let Some(button) = document.get_element_by_id("present") {
+    let canvas = canvas.clone();
+    let closure = Closure::wrap(Box::new(move || {
+      let _ = canvas.request_fullscreen();
+    }) as Box<dyn FnMut()>);
+    button.add_event_listener_with_callback("click", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  // Match the drawing buffer to the displayed size when entering or leaving fullscreen
+  {
+    let canvas_clone = canvas.clone();
+    let (width, height) = (canvas.width(), canvas.height());
+    let closure = Closure::wrap(Box::new(move || {
+      let document = match window().document() {
+        Some(document) => document,
+        None => return,
+      };
+      if document.fullscreen_element().is_some() {
+        let ratio = window().device_pixel_ratio();
+        let screen_width = (canvas_clone.client_width() as f64 * ratio) as u32;
+        let screen_height = (canvas_clone.client_height() as f64 * ratio) as u32;
+        canvas_clone.set_width(screen_width);
+        canvas_clone.set_height(screen_height);
+      } else {
+        canvas_clone.set_width(width);
+        canvas_clone.set_height(height);
+      }
+    }) as Box<dyn FnMut()>);
+    document
+      .add_event_listener_with_callback("fullscreenchange", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  init_cursor_hiding(canvas)
+}
+
+/// Hide the cursor after it has not moved over the canvas for `CURSOR_HIDE_DELAY_MS`
+fn init_cursor_hiding(canvas: &HtmlCanvasElement) -> Result<(), JsValue> {
+  let hide_timeout: Rc<RefCell<Option<i32>>> = Rc::new(RefCell::new(None));
+
+  let canvas_clone = canvas.clone();
+  let hide = Closure::wrap(Box::new(move || {
+    let _ = canvas_clone.class_list().add_1(HIDE_CURSOR_CLASS);
+  }) as Box<dyn FnMut()>);
+
+  let canvas_clone = canvas.clone();
+  let closure = Closure::wrap(Box::new(move || {
+    let _ = canvas_clone.class_list().remove_1(HIDE_CURSOR_CLASS);
+    if let Some(handle) = hide_timeout.borrow_mut().take() {
+      window().clear_timeout_with_handle(handle);
+    }
+    if let Ok(handle) = window().set_timeout_with_callback_and_timeout_and_arguments_0(
+      hide.as_ref().unchecked_ref(),
+      CURSOR_HIDE_DELAY_MS,
+    ) {
+      *hide_timeout.borrow_mut() = Some(handle);
+    }
+  }) as Box<dyn FnMut()>);
+  canvas.add_event_listener_with_callback("mousemove", closure.as_ref().unchecked_ref())?;
+  closure.forget();
+
+  Ok(())
+}
+
+fn handle_command(
+  canvas: &HtmlCanvasElement,
+  state: &Rc<RefCell<PresentationState>>,
+  channel: &BroadcastChannel,
+  command: Command,
+) {
+  match command {
+    Command::ToggleFullscreen => toggle_fullscreen(canvas),
+    Command::OpenControls => {
+      let _ = window().open_with_url_and_target_and_features(
+        CONTROLS_URL,
+        "democ-controls",
+        "width=360,height=480",
+      );
+    }
+    Command::ToggleStats => crate::stats::toggle_overlay(),
+    Command::Screenshot => {
+      let (width, height) = SCREENSHOT_SIZE;
+      if let Err(err) = crate::screenshot::screenshot(width, height, None) {
+        web_sys::console::error_1(&err);
+      }
+    }
+    _ => {
+      state.borrow_mut().apply(command);
+      let _ = channel.post_message(&state.borrow().status().into());
+    }
+  }
+}
+
+fn toggle_fullscreen(canvas: &HtmlCanvasElement) {
+  let document = match window().document() {
+    Some(document) => document,
+    None => return,
+  };
+  if document.fullscreen_element().is_some() {
+    document.exit_fullscreen();
+  } else if let Err(e) = canvas.request_fullscreen() {
+    web_sys::console::error_1(&e);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::camera::{Camera, FlyController, RigCommand};
+  use nalgebra_glm as glm;
+
+  #[test]
+  fn the_scene_list_follows_the_preset() {
+    let mut state = PresentationState::new(vec!["intro".to_string(), String::new()]);
+    assert_eq!(state.scenes(), r#"scenes:["intro",""]"#);
+    state.apply(Command::PrevScene);
+    assert_eq!(state.scene, 1);
+
+    state.set_scenes(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+    assert_eq!((state.scene, state.scene_count()), (0, 3));
+    state.apply(Command::Scene(2));
+    assert_eq!(state.status(), "status:2:0");
+    assert_eq!(state.scenes(), r#"scenes:["a","b","c"]"#);
+  }
+
+  #[test]
+  fn shortcuts_do_not_clash_with_the_camera_keys() {
+    let camera = Camera::new(glm::vec3(0.0, 0.0, 5.0), glm::vec3(0.0, 0.0, 0.0));
+    let mut fly = FlyController::from_camera(&camera);
+    for key in (' '..='~').map(String::from) {
+      let camera_key = fly.set_key(&key, true) || RigCommand::from_key(&key).is_some();
+      assert!(
+        !(camera_key && Command::from_key(&key).is_some()),
+        "`{}` is bound by both the camera and the presentation",
+        key
+      );
+    }
+  }
+}
@@ -0,0 +1,1178 @@
+use crate::{
+  automation::Automation,
+  buffers,
+  camera::Camera,
+  error::DemoError,
+  instancing,
+  lfo::{Lfo, Rate},
+  modulation::{Destination, ModulationInputs, ModulationMatrix, Route, Source},
+  particles::ParticleAudioBinding,
+  program_info::{self, ProgramInfo},
+  scene_graph::{
+    Drawable, Material, Mesh, MeshAttribute, NodeId, SceneGraph, Transform, UniformValue,
+  },
+  stereo,
+  timeline::{Sequencer, Timeline},
+  transitions::{Length, TransitionDesc},
+  vertex_arrays::VertexArrayCache,
+};
+use nalgebra_glm::{self as glm, Vec3};
+use serde::Deserialize;
+use std::{
+  cell::RefCell,
+  collections::{BTreeMap, HashMap, HashSet},
+  fmt,
+};
+use wasm_bindgen::prelude::*;
+use web_sys::{WebGl2RenderingContext, WebGlBuffer};
+
+/// The preset shown until another one is loaded
+const DEFAULT_PRESET: &str = include_str!("../presets/default.json");
+
+/// Name of the node whose world matrix places the particle emitter
+pub(crate) const PARTICLES_NODE: &str = "particles";
+
+thread_local! {
+  /// Set by `load_preset`, picked up by the render loop on its next frame
+  static PENDING_PRESET: RefCell<Option<Preset>> = const { RefCell::new(None) };
+}
+
+/// Parse and validate a JSON or RON preset and show it from the next frame on. A bad preset is
+/// rejected with the path to the offending field and the current one keeps playing.
+#[wasm_bindgen]
+pub fn load_preset(source: &str) -> Result<(), JsValue> {
+  let preset = Preset::parse(source).map_err(DemoError::from)?;
+  PENDING_PRESET.with(|pending| *pending.borrow_mut() = Some(preset));
+  Ok(())
+}
+
+/// The preset handed to `load_preset` since the last call, if any
+pub(crate) fn take_pending_preset() -> Option<Preset> {
+  PENDING_PRESET.with(|pending| pending.borrow_mut().take())
+}
+
+/// Why a preset was rejected. `path` points at the bad field, e.g. `meshes.square.vertex_count`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PresetError {
+  pub path: String,
+  pub message: String,
+}
+
+impl PresetError {
+  fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+    PresetError { path: path.into(), message: message.into() }
+  }
+
+  fn from_path<E: fmt::Display>(path: &serde_path_to_error::Path, message: E) -> Self {
+    // The root of the document prints as `.`, a segment that could not be told as `?`
+    let path = path.to_string();
+    let path = path.trim_end_matches(".?");
+    let path = if path == "." || path == "?" { String::new() } else { path.to_string() };
+    PresetError::new(path, message.to_string())
+  }
+}
+
+impl fmt::Display for PresetError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.path.is_empty() {
+      write!(f, "Invalid preset: {}", self.message)
+    } else {
+      write!(f, "Invalid preset at `{}`: {}", self.path, self.message)
+    }
+  }
+}
+
+/// Everything that decides what is drawn: programs, geometry, materials, the node tree, the
+/// scenes the presentation switches between and how the music moves things
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+  #[serde(default)]
+  pub camera: CameraDesc,
+  pub programs: BTreeMap<String, ProgramDesc>,
+  pub buffers: BTreeMap<String, BufferDesc>,
+  pub meshes: BTreeMap<String, MeshDesc>,
+  pub materials: BTreeMap<String, MaterialDesc>,
+  pub nodes: Vec<NodeDesc>,
+  pub scenes: Vec<SceneDesc>,
+  /// Beats per minute that tempo synced LFOs and automation in beats follow
+  #[serde(default = "default_tempo")]
+  pub tempo: f32,
+  #[serde(default)]
+  pub lfos: BTreeMap<String, Lfo>,
+  #[serde(default)]
+  pub automation: BTreeMap<String, Automation>,
+  /// Routes from audio, MIDI, LFOs, automation and time to uniforms, nodes, the camera and the
+  /// FM oscillator
+  #[serde(default)]
+  pub modulation: Vec<Route>,
+  /// How band energies and beats drive the emitter placed by the `particles` node
+  #[serde(default)]
+  pub particles: ParticleAudioBinding,
+  /// Switches scenes along with the music track
+  pub timeline: Option<Timeline>,
+  /// Used whenever the scene changes without a transition of its own, a cut when left out
+  pub transition: Option<TransitionDesc>,
+}
+
+fn default_tempo() -> f32 {
+  120.0
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDesc {
+  pub position: [f32; 3],
+  pub target: [f32; 3],
+  /// Vertical field of view in degrees
+  pub field_of_view: f32,
+}
+
+impl Default for CameraDesc {
+  fn default() -> Self {
+    CameraDesc { position: [0.0, 0.0, 6.0], target: [0.0, 0.0, 0.0], field_of_view: 45.0 }
+  }
+}
+
+/// Either one of the compiled in programs by name, or both shader sources
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProgramDesc {
+  pub builtin: Option<String>,
+  pub vertex: Option<String>,
+  pub fragment: Option<String>,
+}
+
+/// Static vertex data, or a buffer the renderer rewrites every frame
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BufferDesc {
+  pub data: Option<Vec<f32>>,
+  pub source: Option<BufferSource>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferSource {
+  /// The per instance data of the spectrum bar field
+  BarField,
+  /// `vec2` points of the goniometer, for a `points` or `line_strip` mesh
+  Goniometer,
+}
+
+impl BufferSource {
+  fn len(self) -> usize {
+    match self {
+      BufferSource::BarField => instancing::BAR_FIELD_INSTANCES * instancing::INSTANCE_FLOATS,
+      BufferSource::Goniometer => stereo::SCOPE_POINTS * 2,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrimitiveMode {
+  Points,
+  Lines,
+  LineStrip,
+  LineLoop,
+  Triangles,
+  TriangleStrip,
+  TriangleFan,
+}
+
+impl PrimitiveMode {
+  fn gl_mode(self) -> u32 {
+    match self {
+      PrimitiveMode::Points => WebGl2RenderingContext::POINTS,
+      PrimitiveMode::Lines => WebGl2RenderingContext::LINES,
+      PrimitiveMode::LineStrip => WebGl2RenderingContext::LINE_STRIP,
+      PrimitiveMode::LineLoop => WebGl2RenderingContext::LINE_LOOP,
+      PrimitiveMode::Triangles => WebGl2RenderingContext::TRIANGLES,
+      PrimitiveMode::TriangleStrip => WebGl2RenderingContext::TRIANGLE_STRIP,
+      PrimitiveMode::TriangleFan => WebGl2RenderingContext::TRIANGLE_FAN,
+    }
+  }
+}
+
+/// Non indexed geometry, see `Mesh`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshDesc {
+  pub attributes: Vec<AttributeDesc>,
+  pub mode: PrimitiveMode,
+  pub vertex_count: i32,
+  /// `0` for a regular non instanced draw
+  #[serde(default)]
+  pub instance_count: i32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttributeDesc {
+  pub name: String,
+  pub buffer: String,
+  pub components: i32,
+  /// Bytes between consecutive elements, `0` for tightly packed
+  #[serde(default)]
+  pub stride: i32,
+  /// Bytes from the start of the buffer to the first element
+  #[serde(default)]
+  pub offset: i32,
+  #[serde(default)]
+  pub divisor: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialDesc {
+  pub program: String,
+  /// One to four floats per uniform
+  #[serde(default)]
+  pub uniforms: BTreeMap<String, Vec<f32>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeDesc {
+  pub name: String,
+  /// A node draws when it has both a mesh and a material
+  pub mesh: Option<String>,
+  pub material: Option<String>,
+  #[serde(default)]
+  pub translation: [f32; 3],
+  /// Euler angles in radians
+  #[serde(default)]
+  pub rotation: [f32; 3],
+  #[serde(default = "unit_scale")]
+  pub scale: [f32; 3],
+  /// Radians per second added to the rotation
+  #[serde(default)]
+  pub spin: [f32; 3],
+  #[serde(default)]
+  pub children: Vec<NodeDesc>,
+}
+
+fn unit_scale() -> [f32; 3] {
+  [1.0, 1.0, 1.0]
+}
+
+/// One scene of the presentation, drawn as a sequence of passes
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneDesc {
+  /// Used by the timeline to cue the scene
+  #[serde(default)]
+  pub name: String,
+  pub passes: Vec<PassDesc>,
+}
+
+/// Draws the named nodes, and their children, on top of what the previous passes left
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PassDesc {
+  /// Clear color and depth before drawing, otherwise only the depth is cleared
+  pub clear_color: Option<[f32; 3]>,
+  pub nodes: Vec<String>,
+  /// Post-processing or detail that can be left out when the frame rate drops
+  #[serde(default)]
+  pub optional: bool,
+}
+
+impl Preset {
+  pub fn builtin() -> Result<Self, PresetError> {
+    Preset::from_json(DEFAULT_PRESET)
+  }
+
+  /// JSON when the source starts with `{`, RON otherwise
+  pub fn parse(source: &str) -> Result<Self, PresetError> {
+    if source.trim_start().starts_with('{') {
+      Preset::from_json(source)
+    } else {
+      Preset::from_ron(source)
+    }
+  }
+
+  pub fn from_json(source: &str) -> Result<Self, PresetError> {
+    let mut deserializer = serde_json::Deserializer::from_str(source);
+    let preset: Preset = serde_path_to_error::deserialize(&mut deserializer)
+      .map_err(|err| PresetError::from_path(err.path(), err.inner()))?;
+    deserializer.end().map_err(|err| PresetError::new("", err.to_string()))?;
+    preset.validate()?;
+    Ok(preset)
+  }
+
+  pub fn from_ron(source: &str) -> Result<Self, PresetError> {
+    let mut deserializer =
+      ron::Deserializer::from_str(source).map_err(|err| PresetError::new("", err.to_string()))?;
+    let preset: Result<Preset, _> = serde_path_to_error::deserialize(&mut deserializer);
+    let preset = match preset {
+      Ok(preset) => preset,
+      Err(err) => {
+        // Report the line and column along with the path
+        let path = err.path().clone();
+        return Err(PresetError::from_path(&path, deserializer.span_error(err.into_inner())));
+      }
+    };
+    deserializer
+      .end()
+      .map_err(|err| PresetError::new("", deserializer.span_error(err).to_string()))?;
+    preset.validate()?;
+    Ok(preset)
+  }
+
+  /// Check every name refers to something that exists and every value is in range, so a preset
+  /// that validates can always be instantiated apart from shader compile errors
+  pub fn validate(&self) -> Result<(), PresetError> {
+    let field_of_view = self.camera.field_of_view;
+    if !(field_of_view > 0.0 && field_of_view < 180.0) {
+      return Err(PresetError::new(
+        "camera.field_of_view",
+        format!("expected more than 0 and less than 180 degrees, found {}", field_of_view),
+      ));
+    }
+
+    for (name, program) in &self.programs {
+      let path = format!("programs.{}", name);
+      match (&program.builtin, &program.vertex, &program.fragment) {
+        (Some(builtin), None, None) => {
+          if builtin_program(builtin).is_none() {
+            return Err(PresetError::new(
+              format!("{}.builtin", path),
+              format!("unknown builtin program `{}`", builtin),
+            ));
+          }
+        }
+        (None, Some(_), Some(_)) => {}
+        _ => {
+          return Err(PresetError::new(
+            path,
+            "expected either `builtin` or both `vertex` and `fragment`",
+          ))
+        }
+      }
+    }
+
+    for (name, buffer) in &self.buffers {
+      let path = format!("buffers.{}", name);
+      match (&buffer.data, &buffer.source) {
+        (Some(data), None) if data.is_empty() => {
+          return Err(PresetError::new(format!("{}.data", path), "buffer data is empty"))
+        }
+        (Some(_), None) | (None, Some(_)) => {}
+        _ => return Err(PresetError::new(path, "expected exactly one of `data` and `source`")),
+      }
+    }
+
+    for (name, mesh) in &self.meshes {
+      self.validate_mesh(&format!("meshes.{}", name), mesh)?;
+    }
+
+    for (name, material) in &self.materials {
+      let path = format!("materials.{}", name);
+      if !self.programs.contains_key(&material.program) {
+        return Err(PresetError::new(
+          format!("{}.program", path),
+          format!("unknown program `{}`", material.program),
+        ));
+      }
+      for (uniform, values) in &material.uniforms {
+        if UniformValue::from_slice(values).is_none() {
+          return Err(PresetError::new(
+            format!("{}.uniforms.{}", path, uniform),
+            format!("expected 1 to 4 components, found {}", values.len()),
+          ));
+        }
+      }
+    }
+
+    let mut node_names = HashSet::new();
+    for (index, node) in self.nodes.iter().enumerate() {
+      self.validate_node(&format!("nodes[{}]", index), node, &mut node_names)?;
+    }
+
+    if self.scenes.is_empty() {
+      return Err(PresetError::new("scenes", "a preset needs at least one scene"));
+    }
+    for (index, scene) in self.scenes.iter().enumerate() {
+      if !scene.name.is_empty() && self.scenes[..index].iter().any(|other| other.name == scene.name)
+      {
+        return Err(PresetError::new(
+          format!("scenes[{}].name", index),
+          format!("scene `{}` is defined twice", scene.name),
+        ));
+      }
+      let path = format!("scenes[{}].passes", index);
+      if scene.passes.is_empty() {
+        return Err(PresetError::new(path, "a scene needs at least one pass"));
+      }
+      for (pass_index, pass) in scene.passes.iter().enumerate() {
+        for (node_index, node) in pass.nodes.iter().enumerate() {
+          if !node_names.contains(node.as_str()) {
+            return Err(PresetError::new(
+              format!("{}[{}].nodes[{}]", path, pass_index, node_index),
+              format!("unknown node `{}`", node),
+            ));
+          }
+        }
+      }
+    }
+
+    if let Some(timeline) = &self.timeline {
+      self.validate_timeline(timeline)?;
+    }
+    if let Some(transition) = &self.transition {
+      validate_transition("transition", transition)?;
+    }
+
+    if !self.tempo.is_finite() || self.tempo <= 0.0 {
+      return Err(PresetError::new("tempo", "expected a positive tempo"));
+    }
+    for (name, lfo) in &self.lfos {
+      let rate = match lfo.rate {
+        Rate::Hertz(rate) | Rate::Beats(rate) => rate,
+      };
+      if !rate.is_finite() || rate <= 0.0 {
+        return Err(PresetError::new(
+          format!("lfos.{}.rate", name),
+          format!("expected a positive rate, found {}", rate),
+        ));
+      }
+    }
+    for (name, automation) in &self.automation {
+      let path = format!("automation.{}.keyframes", name);
+      if automation.keyframes.is_empty() {
+        return Err(PresetError::new(path, "expected at least one keyframe"));
+      }
+      for (index, pair) in automation.keyframes.windows(2).enumerate() {
+        if pair[1].time < pair[0].time {
+          return Err(PresetError::new(
+            format!("{}[{}].time", path, index + 1),
+            format!("keyframes have to be sorted, {} comes after {}", pair[1].time, pair[0].time),
+          ));
+        }
+      }
+    }
+
+    for (index, route) in self.modulation.iter().enumerate() {
+      self.validate_route(&format!("modulation[{}]", index), route, &node_names)?;
+    }
+
+    let binding = &self.particles;
+    for (field, band) in [
+      ("emission_band", binding.emission_band),
+      ("speed_band", binding.speed_band),
+      ("color_band", binding.color_band),
+    ] {
+      if band >= instancing::BAND_COUNT {
+        return Err(PresetError::new(
+          format!("particles.{}", field),
+          format!("band {} is out of range, there are {}", band, instancing::BAND_COUNT),
+        ));
+      }
+    }
+
+    Ok(())
+  }
+
+  fn validate_mesh(&self, path: &str, mesh: &MeshDesc) -> Result<(), PresetError> {
+    if mesh.vertex_count <= 0 {
+      return Err(PresetError::new(
+        format!("{}.vertex_count", path),
+        "expected at least one vertex",
+      ));
+    }
+    if mesh.instance_count < 0 {
+      return Err(PresetError::new(format!("{}.instance_count", path), "must not be negative"));
+    }
+    if mesh.attributes.is_empty() {
+      return Err(PresetError::new(format!("{}.attributes", path), "expected at least one"));
+    }
+
+    for (index, attribute) in mesh.attributes.iter().enumerate() {
+      let path = format!("{}.attributes[{}]", path, index);
+      if !(1..=4).contains(&attribute.components) {
+        return Err(PresetError::new(
+          format!("{}.components", path),
+          format!("expected 1 to 4 components, found {}", attribute.components),
+        ));
+      }
+      if attribute.stride < 0 || attribute.offset < 0 {
+        return Err(PresetError::new(path, "stride and offset must not be negative"));
+      }
+      let buffer = self.buffers.get(&attribute.buffer).ok_or_else(|| {
+        PresetError::new(
+          format!("{}.buffer", path),
+          format!("unknown buffer `{}`", attribute.buffer),
+        )
+      })?;
+
+      // Per instance attributes advance once every `divisor` instances
+      let elements = if attribute.divisor == 0 {
+        mesh.vertex_count as usize
+      } else {
+        (mesh.instance_count.max(1) as usize).div_ceil(attribute.divisor as usize)
+      };
+      let float = std::mem::size_of::<f32>();
+      let element_size = attribute.components as usize * float;
+      let stride = if attribute.stride == 0 { element_size } else { attribute.stride as usize };
+      let needed = attribute.offset as usize + (elements - 1) * stride + element_size;
+      let available = match (&buffer.data, buffer.source) {
+        (Some(data), _) => data.len() * float,
+        (None, Some(source)) => source.len() * float,
+        (None, None) => 0,
+      };
+      if needed > available {
+        return Err(PresetError::new(
+          format!("{}.buffer", path),
+          format!(
+            "buffer `{}` holds {} bytes but {} elements need {}",
+            attribute.buffer, available, elements, needed
+          ),
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  fn validate_timeline(&self, timeline: &Timeline) -> Result<(), PresetError> {
+    for (index, cue) in timeline.cues.iter().enumerate() {
+      let path = format!("timeline.cues[{}]", index);
+      if !self.scenes.iter().any(|scene| scene.name == cue.scene) {
+        return Err(PresetError::new(
+          format!("{}.scene", path),
+          format!("unknown scene `{}`", cue.scene),
+        ));
+      }
+      if let Some(transition) = &cue.transition {
+        validate_transition(&format!("{}.transition", path), transition)?;
+      }
+      if index > 0 && cue.time < timeline.cues[index - 1].time {
+        return Err(PresetError::new(
+          format!("{}.time", path),
+          format!(
+            "cues have to be sorted, {} comes after {}",
+            cue.time,
+            timeline.cues[index - 1].time
+          ),
+        ));
+      }
+    }
+    if let Some(section) = timeline.loop_section {
+      if section.start < 0.0 || section.end <= section.start {
+        return Err(PresetError::new(
+          "timeline.loop",
+          format!("expected 0 <= start < end, found {} to {}", section.start, section.end),
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  fn validate_route(
+    &self,
+    path: &str,
+    route: &Route,
+    node_names: &HashSet<&str>,
+  ) -> Result<(), PresetError> {
+    match route.source {
+      Source::Band(band) | Source::LeftBand(band) | Source::RightBand(band)
+        if band >= instancing::BAND_COUNT =>
+      {
+        let field = match route.source {
+          Source::LeftBand(_) => "left_band",
+          Source::RightBand(_) => "right_band",
+          _ => "band",
+        };
+        return Err(PresetError::new(
+          format!("{}.source.{}", path, field),
+          format!("band {} is out of range, there are {}", band, instancing::BAND_COUNT),
+        ));
+      }
+      Source::Chroma(class) if class >= 12 => {
+        return Err(PresetError::new(
+          format!("{}.source.chroma", path),
+          format!("pitch class {} is out of range, the last one is 11", class),
+        ))
+      }
+      Source::Lfo(ref name) if !self.lfos.contains_key(name) => {
+        return Err(PresetError::new(
+          format!("{}.source.lfo", path),
+          format!("unknown lfo `{}`", name),
+        ))
+      }
+      Source::Automation(ref name) if !self.automation.contains_key(name) => {
+        return Err(PresetError::new(
+          format!("{}.source.automation", path),
+          format!("unknown automation `{}`", name),
+        ))
+      }
+      Source::MidiCc(number) if number > 127 => {
+        return Err(PresetError::new(
+          format!("{}.source.midi_cc", path),
+          format!("control change {} is out of range, the last one is 127", number),
+        ))
+      }
+      _ => {}
+    }
+
+    match &route.destination {
+      Destination::Node { node, .. } => {
+        if !node_names.contains(node.as_str()) {
+          return Err(PresetError::new(
+            format!("{}.destination.node.node", path),
+            format!("unknown node `{}`", node),
+          ));
+        }
+      }
+      Destination::Uniform { material, uniform } => {
+        let desc = self.materials.get(material).ok_or_else(|| {
+          PresetError::new(
+            format!("{}.destination.uniform.material", path),
+            format!("unknown material `{}`", material),
+          )
+        })?;
+        if !desc.uniforms.contains_key(uniform) {
+          return Err(PresetError::new(
+            format!("{}.destination.uniform.uniform", path),
+            format!("material `{}` does not set `{}`", material, uniform),
+          ));
+        }
+      }
+      Destination::Camera(_) | Destination::FmOsc(_) => {}
+    }
+
+    if let (Some(min), Some(max)) = (route.min, route.max) {
+      if min > max {
+        return Err(PresetError::new(
+          format!("{}.min", path),
+          format!("min {} is above max {}", min, max),
+        ));
+      }
+    }
+    if route.attack < 0.0 || route.release < 0.0 {
+      return Err(PresetError::new(path, "attack and release must not be negative"));
+    }
+    Ok(())
+  }
+
+  fn validate_node<'a>(
+    &self,
+    path: &str,
+    node: &'a NodeDesc,
+    node_names: &mut HashSet<&'a str>,
+  ) -> Result<(), PresetError> {
+    if node.name == SceneGraph::ROOT_NAME {
+      return Err(PresetError::new(
+        format!("{}.name", path),
+        format!("`{}` is reserved for the root of the scene graph", node.name),
+      ));
+    }
+    if !node_names.insert(&node.name) {
+      return Err(PresetError::new(
+        format!("{}.name", path),
+        format!("node `{}` is defined twice", node.name),
+      ));
+    }
+    match (&node.mesh, &node.material) {
+      (Some(mesh), Some(material)) => {
+        if !self.meshes.contains_key(mesh) {
+          return Err(PresetError::new(
+            format!("{}.mesh", path),
+            format!("unknown mesh `{}`", mesh),
+          ));
+        }
+        if !self.materials.contains_key(material) {
+          return Err(PresetError::new(
+            format!("{}.material", path),
+            format!("unknown material `{}`", material),
+          ));
+        }
+      }
+      (None, None) => {}
+      _ => return Err(PresetError::new(path, "expected both `mesh` and `material` or neither")),
+    }
+    for (index, child) in node.children.iter().enumerate() {
+      self.validate_node(&format!("{}.children[{}]", path, index), child, node_names)?;
+    }
+    Ok(())
+  }
+
+  /// Names of the scenes in order, empty for those without one
+  pub fn scene_names(&self) -> Vec<String> {
+    self.scenes.iter().map(|scene| scene.name.clone()).collect()
+  }
+
+  pub fn camera(&self) -> Camera {
+    let mut camera = Camera::new(self.camera.position.into(), self.camera.target.into());
+    camera.set_field_of_view(self.camera.field_of_view.to_radians());
+    camera
+  }
+
+  /// The scene graph described by `meshes`, `materials` and `nodes`
+  pub fn scene_graph(&self) -> SceneGraph {
+    let mut scene_graph = SceneGraph::new();
+    for (name, mesh) in &self.meshes {
+      let attributes = mesh
+        .attributes
+        .iter()
+        .map(|attribute| MeshAttribute {
+          attrib_name: attribute.name.clone(),
+          buffer_name: attribute.buffer.clone(),
+          num_components: attribute.components,
+          stride: attribute.stride,
+          offset: attribute.offset,
+          divisor: attribute.divisor,
+        })
+        .collect();
+      scene_graph.meshes.insert(
+        name.clone(),
+        Mesh {
+          attributes,
+          mode: mesh.mode.gl_mode(),
+          vertex_count: mesh.vertex_count,
+          instance_count: mesh.instance_count,
+        },
+      );
+    }
+    for (name, material) in &self.materials {
+      let uniforms = material
+        .uniforms
+        .iter()
+        .filter_map(|(uniform, values)| {
+          UniformValue::from_slice(values).map(|value| (uniform.clone(), value))
+        })
+        .collect();
+      scene_graph
+        .materials
+        .insert(name.clone(), Material { program: material.program.clone(), uniforms });
+    }
+    for node in &self.nodes {
+      add_node(&mut scene_graph, SceneGraph::ROOT, node);
+    }
+    scene_graph
+  }
+}
+
+fn validate_transition(path: &str, transition: &TransitionDesc) -> Result<(), PresetError> {
+  let length = match transition.length {
+    Length::Seconds(length) | Length::Beats(length) => length,
+  };
+  if !length.is_finite() || length < 0.0 {
+    return Err(PresetError::new(
+      format!("{}.length", path),
+      format!("expected a length of zero or more, found {}", length),
+    ));
+  }
+  Ok(())
+}
+
+fn add_node(scene_graph: &mut SceneGraph, parent: NodeId, node: &NodeDesc) {
+  let transform = Transform {
+    translation: node.translation.into(),
+    rotation: node.rotation.into(),
+    scale: node.scale.into(),
+  };
+  let drawable = match (&node.mesh, &node.material) {
+    (Some(mesh), Some(material)) => {
+      Some(Drawable { mesh: mesh.clone(), material: material.clone() })
+    }
+    _ => None,
+  };
+  if let Some(id) = scene_graph.add_node(parent, &node.name, transform, drawable) {
+    for child in &node.children {
+      add_node(scene_graph, id, child);
+    }
+  }
+}
+
+/// Vertex and fragment source of a compiled in program
+fn builtin_program(name: &str) -> Option<(&'static str, &'static str)> {
+  match name {
+    "default" => Some((program_info::VERT_SOURCE, program_info::FRAG_SOURCE)),
+    "instanced" => Some((program_info::INSTANCED_VERT_SOURCE, program_info::INSTANCED_FRAG_SOURCE)),
+    _ => None,
+  }
+}
+
+/// A node whose transform is recomputed every frame from its preset value
+struct AnimatedNode {
+  id: NodeId,
+  base: Transform,
+  spin: Vec3,
+}
+
+/// A preset turned into GPU objects and a scene graph
+pub struct PresetScene {
+  pub programs: HashMap<String, ProgramInfo>,
+  pub buffers: HashMap<String, WebGlBuffer>,
+  pub scene_graph: SceneGraph,
+  pub scenes: Vec<SceneDesc>,
+  /// Buffers the renderer fills every frame, and what with
+  pub source_buffers: Vec<(String, BufferSource)>,
+  pub sequencer: Option<Sequencer>,
+  pub transition: Option<TransitionDesc>,
+  pub tempo: f32,
+  pub particles: ParticleAudioBinding,
+  /// Built lazily by the renderer, owned here because they refer to this preset's buffers
+  pub vertex_arrays: VertexArrayCache,
+  animated: Vec<AnimatedNode>,
+  modulation: ModulationMatrix,
+  lfos: BTreeMap<String, Lfo>,
+  automation: BTreeMap<String, Automation>,
+  /// Preset values of the uniforms the modulation adds to
+  base_uniforms: HashMap<(String, String), UniformValue>,
+}
+
+impl PresetScene {
+  pub(crate) fn instantiate(
+    gl_context: &WebGl2RenderingContext,
+    preset: &Preset,
+  ) -> Result<Self, JsValue> {
+    let scene_graph = preset.scene_graph();
+
+    let mut base_uniforms = HashMap::new();
+    let mut modulated_nodes = HashSet::new();
+    for route in &preset.modulation {
+      match &route.destination {
+        Destination::Node { node, .. } => {
+          modulated_nodes.insert(node.as_str());
+        }
+        Destination::Uniform { material, uniform } => {
+          let base = scene_graph
+            .materials
+            .get(material)
+            .and_then(|material| material.uniforms.get(uniform))
+            .copied()
+            .ok_or_else(|| {
+              DemoError::UnknownReference(format!(
+                "uniform `{}` of material `{}`",
+                uniform, material
+              ))
+            })?;
+          base_uniforms.insert((material.clone(), uniform.clone()), base);
+        }
+        Destination::Camera(_) | Destination::FmOsc(_) => {}
+      }
+    }
+
+    let mut animated = Vec::new();
+    let mut stack: Vec<&NodeDesc> = preset.nodes.iter().collect();
+    while let Some(node) = stack.pop() {
+      stack.extend(node.children.iter());
+      let (id, base) = match scene_graph.find(&node.name) {
+        Some(id) => (id, scene_graph.node(id).map(|node| node.transform)),
+        None => continue,
+      };
+      let spin: Vec3 = node.spin.into();
+      if spin != glm::zero::<Vec3>() || modulated_nodes.contains(node.name.as_str()) {
+        let base = base.unwrap_or_else(Transform::identity);
+        animated.push(AnimatedNode { id, base, spin });
+      }
+    }
+
+    let mut preset_scene = PresetScene {
+      programs: HashMap::new(),
+      buffers: HashMap::new(),
+      scene_graph,
+      scenes: preset.scenes.clone(),
+      source_buffers: Vec::new(),
+      sequencer: preset.timeline.as_ref().map(|timeline| {
+        let names: Vec<&str> = preset.scenes.iter().map(|scene| scene.name.as_str()).collect();
+        Sequencer::new(timeline, &names, preset.tempo)
+      }),
+      transition: preset.transition,
+      tempo: preset.tempo,
+      particles: preset.particles.clone(),
+      vertex_arrays: VertexArrayCache::new(),
+      animated,
+      modulation: ModulationMatrix::new(preset.modulation.clone()),
+      lfos: preset.lfos.clone(),
+      automation: preset.automation.clone(),
+      base_uniforms,
+    };
+    // A failure part way through deletes what was already created, nothing refers to it yet
+    if let Err(err) = preset_scene.create_gpu_objects(gl_context, preset) {
+      preset_scene.delete(gl_context);
+      return Err(err);
+    }
+    Ok(preset_scene)
+  }
+
+  /// Compile the programs and fill the buffers of `preset`
+  fn create_gpu_objects(
+    &mut self,
+    gl_context: &WebGl2RenderingContext,
+    preset: &Preset,
+  ) -> Result<(), JsValue> {
+    for (name, program) in &preset.programs {
+      let (vert_source, frag_source) = match (&program.builtin, &program.vertex, &program.fragment)
+      {
+        (Some(builtin), _, _) => builtin_program(builtin).ok_or_else(|| {
+          DemoError::from(PresetError::new(
+            format!("programs.{}.builtin", name),
+            format!("unknown builtin program `{}`", builtin),
+          ))
+        })?,
+        (None, Some(vertex), Some(fragment)) => (vertex.as_str(), fragment.as_str()),
+        _ => {
+          let path = format!("programs.{}", name);
+          let message = "expected either `builtin` or both `vertex` and `fragment`";
+          return Err(DemoError::from(PresetError::new(path, message)).into());
+        }
+      };
+      self.programs.insert(name.clone(), ProgramInfo::new(gl_context, vert_source, frag_source)?);
+    }
+
+    let target = WebGl2RenderingContext::ARRAY_BUFFER;
+    for (name, buffer) in &preset.buffers {
+      let buffer = match (&buffer.data, buffer.source) {
+        (Some(data), _) => {
+          buffers::init_buffer(gl_context, data, target, WebGl2RenderingContext::STATIC_DRAW)?
+        }
+        (None, Some(source)) => {
+          self.source_buffers.push((name.clone(), source));
+          buffers::init_dynamic_buffer(gl_context, source.len(), target)?
+        }
+        (None, None) => {
+          let path = format!("buffers.{}", name);
+          let message = "expected exactly one of `data` and `source`";
+          return Err(DemoError::from(PresetError::new(path, message)).into());
+        }
+      };
+      self.buffers.insert(name.clone(), buffer);
+    }
+    Ok(())
+  }
+
+  /// Spin the nodes for the frame at `time`, undoing last frame's modulation
+  pub fn animate(&mut self, time: f32) {
+    for node in &self.animated {
+      if let Some(animated) = self.scene_graph.node_mut(node.id) {
+        animated.transform = node.base;
+        animated.transform.rotation += node.spin * time;
+      }
+    }
+  }
+
+  /// Value of every LFO at `time` seconds
+  pub fn sample_lfos(&self, time: f32) -> HashMap<String, f32> {
+    self.lfos.iter().map(|(name, lfo)| (name.clone(), lfo.value(time, self.tempo))).collect()
+  }
+
+  /// Value of every automation curve at `time` seconds
+  pub fn sample_automation(&self, time: f32) -> HashMap<String, f32> {
+    let tempo = self.tempo;
+    self.automation.iter().map(|(name, curve)| (name.clone(), curve.value(time, tempo))).collect()
+  }
+
+  /// Evaluate the modulation matrix and apply it to the nodes and uniforms. The camera and FM
+  /// oscillator outputs are returned for the render loop, which owns those.
+  pub fn modulate(&mut self, inputs: &ModulationInputs, dt: f32) -> Vec<(Destination, f32)> {
+    let mut external = Vec::new();
+    for (destination, value) in self.modulation.evaluate(inputs, dt) {
+      match destination {
+        Destination::Node { node, property } => {
+          let scene_graph = &mut self.scene_graph;
+          if let Some(node) = scene_graph.find(node).and_then(|id| scene_graph.node_mut(id)) {
+            property.apply(&mut node.transform, value);
+          }
+        }
+        Destination::Uniform { material, uniform } => {
+          let base = self.base_uniforms.get(&(material.clone(), uniform.clone()));
+          let material = self.scene_graph.materials.get_mut(material);
+          if let (Some(base), Some(material)) = (base, material) {
+            material.uniforms.insert(uniform.clone(), base.offset(value));
+          }
+        }
+        Destination::Camera(_) | Destination::FmOsc(_) => {
+          external.push((destination.clone(), value))
+        }
+      }
+    }
+    external
+  }
+
+  /// Show only the nodes drawn by the given pass of `scene`, out of those used by any pass
+  pub fn show_pass(&mut self, scene: usize, pass: usize) {
+    let shown = &self.scenes[scene].passes[pass].nodes;
+    let visibility: Vec<(NodeId, bool)> = self
+      .scenes
+      .iter()
+      .flat_map(|scene| scene.passes.iter().flat_map(|pass| pass.nodes.iter()))
+      .filter_map(|name| self.scene_graph.find(name).map(|id| (id, shown.contains(name))))
+      .collect();
+    for (id, visible) in visibility {
+      if let Some(node) = self.scene_graph.node_mut(id) {
+        node.visible = visible;
+      }
+    }
+  }
+
+  /// Whether any pass of `scene` draws `node`
+  pub fn scene_shows(&self, scene: usize, node: &str) -> bool {
+    self.scenes[scene].passes.iter().any(|pass| pass.nodes.iter().any(|name| name == node))
+  }
+
+  /// Free the GPU objects
+  pub(crate) fn delete(&mut self, gl_context: &WebGl2RenderingContext) {
+    self.vertex_arrays.clear(gl_context);
+    for program_info in self.programs.values() {
+      gl_context.delete_program(Some(&program_info.program));
+    }
+    for buffer in self.buffers.values() {
+      gl_context.delete_buffer(Some(buffer));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::{json, Value};
+
+  /// The smallest preset that validates: one square, one material, one scene
+  fn minimal() -> Value {
+    json!({
+      "programs": { "plain": { "builtin": "default" } },
+      "buffers": { "square": { "data": [-1.0, 1.0, 1.0, 1.0, -1.0, -1.0, 1.0, -1.0] } },
+      "meshes": {
+        "square": {
+          "attributes": [{ "name": "a_vertex_position", "buffer": "square", "components": 2 }],
+          "mode": "triangle_strip",
+          "vertex_count": 4
+        }
+      },
+      "materials": { "plain": { "program": "plain", "uniforms": { "u_dim": [0.0] } } },
+      "nodes": [{ "name": "square", "mesh": "square", "material": "plain" }],
+      "scenes": [{ "name": "only", "passes": [{ "nodes": ["square"] }] }],
+      "modulation": [{
+        "source": "rms",
+        "destination": { "uniform": { "material": "plain", "uniform": "u_dim" } }
+      }]
+    })
+  }
+
+  /// Parse `minimal` after `edit`, expecting it to be rejected
+  fn rejected(edit: impl FnOnce(&mut Value)) -> PresetError {
+    let mut preset = minimal();
+    edit(&mut preset);
+    Preset::parse(&preset.to_string()).expect_err("the preset should be rejected")
+  }
+
+  #[test]
+  fn parses_json_and_ron() {
+    Preset::builtin().unwrap();
+    let preset = Preset::parse(&minimal().to_string()).unwrap();
+    assert_eq!(preset.meshes["square"].vertex_count, 4);
+    assert_eq!(preset.tempo, 120.0);
+    assert!(preset.particles.burst_on_beat);
+
+    let ron = r#"(
+      programs: { "plain": (builtin: Some("default")) },
+      buffers: { "square": (data: Some([-1.0, 1.0, 1.0, 1.0, -1.0, -1.0, 1.0, -1.0])) },
+      meshes: {
+        "square": (
+          attributes: [(name: "a_vertex_position", buffer: "square", components: 2)],
+          mode: triangle_strip,
+          vertex_count: 4,
+        ),
+      },
+      materials: { "plain": (program: "plain") },
+      nodes: [(name: "square", mesh: Some("square"), material: Some("plain"))],
+      scenes: [(name: "only", passes: [(nodes: ["square"])])],
+      tempo: 90.0,
+      particles: (color_band: 3, burst_on_beat: false),
+    )"#;
+    let preset = Preset::parse(ron).unwrap();
+    assert_eq!(preset.tempo, 90.0);
+    assert_eq!(preset.scenes[0].passes[0].nodes, ["square"]);
+    assert_eq!((preset.particles.color_band, preset.particles.burst_on_beat), (3, false));
+    assert_eq!(preset.particles.emission_band, ParticleAudioBinding::default().emission_band);
+  }
+
+  #[test]
+  fn points_at_bad_values() {
+    let err = rejected(|preset| preset["meshes"]["square"]["vertex_count"] = json!(0));
+    assert_eq!(err.path, "meshes.square.vertex_count");
+    assert_eq!(err.message, "expected at least one vertex");
+
+    let err = rejected(|preset| preset["meshes"]["square"]["vertex_count"] = json!(40));
+    assert_eq!(err.path, "meshes.square.attributes[0].buffer");
+    assert_eq!(err.message, "buffer `square` holds 32 bytes but 40 elements need 320");
+
+    let err = rejected(|preset| preset["modulation"][0]["source"] = json!({ "band": 16 }));
+    assert_eq!(err.path, "modulation[0].source.band");
+    assert_eq!(err.message, "band 16 is out of range, there are 16");
+
+    let err = rejected(|preset| preset["modulation"][0]["source"] = json!({ "chroma": 12 }));
+    assert_eq!(err.path, "modulation[0].source.chroma");
+    assert_eq!(err.message, "pitch class 12 is out of range, the last one is 11");
+
+    let err = rejected(|preset| preset["particles"] = json!({ "speed_band": 16 }));
+    assert_eq!(err.path, "particles.speed_band");
+    assert_eq!(err.message, "band 16 is out of range, there are 16");
+  }
+
+  #[test]
+  fn rejects_the_root_name() {
+    let err = rejected(|preset| preset["nodes"][0]["children"] = json!([{ "name": "root" }]));
+    assert_eq!(err.path, "nodes[0].children[0].name");
+    assert_eq!(err.message, "`root` is reserved for the root of the scene graph");
+  }
+
+  #[test]
+  fn rejects_degenerate_fields_of_view() {
+    for field_of_view in [0.0, -30.0, 180.0, 270.0] {
+      let camera = json!({ "position": [0.0, 0.0, 6.0], "target": [0.0, 0.0, 0.0] });
+      let err = rejected(|preset| {
+        preset["camera"] = camera;
+        preset["camera"]["field_of_view"] = json!(field_of_view);
+      });
+      assert_eq!(err.path, "camera.field_of_view");
+    }
+  }
+
+  #[test]
+  fn points_at_unknown_references() {
+    let err = rejected(|preset| preset["scenes"][0]["passes"][0]["nodes"][0] = json!("ghost"));
+    assert_eq!(err.path, "scenes[0].passes[0].nodes[0]");
+    assert_eq!(err.message, "unknown node `ghost`");
+
+    let err = rejected(|preset| preset["materials"]["plain"]["program"] = json!("missing"));
+    assert_eq!(err.path, "materials.plain.program");
+    assert_eq!(err.message, "unknown program `missing`");
+
+    let err = rejected(|preset| preset["programs"]["plain"]["builtin"] = json!("missing"));
+    assert_eq!(err.path, "programs.plain.builtin");
+    assert_eq!(err.message, "unknown builtin program `missing`");
+
+    let err = rejected(|preset| {
+      preset["modulation"][0]["destination"] =
+        json!({ "node": { "node": "ghost", "property": "scale" } })
+    });
+    assert_eq!(err.path, "modulation[0].destination.node.node");
+    assert_eq!(err.message, "unknown node `ghost`");
+  }
+
+  #[test]
+  fn points_at_fields_that_do_not_deserialize() {
+    let err = rejected(|preset| preset["meshes"]["square"]["vertex_count"] = json!("four"));
+    assert_eq!(err.path, "meshes.square.vertex_count");
+    assert!(err.message.starts_with("invalid type: string \"four\""), "{}", err.message);
+
+    let err = rejected(|preset| preset["nodes"][0]["colour"] = json!([1.0, 0.0, 0.0]));
+    assert_eq!(err.path, "nodes[0].colour");
+    assert!(err.message.starts_with("unknown field `colour`"), "{}", err.message);
+    assert_eq!(err.to_string(), format!("Invalid preset at `nodes[0].colour`: {}", err.message));
+  }
+
+  #[test]
+  fn ron_syntax_errors_carry_their_span() {
+    let err =
+      Preset::parse("(\n  programs: {\n    \"plain\": (builtin: Some(\"default\")\n  },\n)")
+        .expect_err("the preset should be rejected");
+    assert_eq!(err.path, "programs.plain");
+    assert_eq!(err.message, "4:3: Expected comma");
+  }
+}
@@ -1,8 +1,9 @@
+use crate::error::{self, DemoError};
 use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
-use web_sys::{console, WebGl2RenderingContext, WebGlProgram, WebGlShader, WebGlUniformLocation};
+use web_sys::{WebGl2RenderingContext, WebGlProgram, WebGlShader, WebGlUniformLocation};
 
-const VERT_SOURCE: &str = r#"
+pub(crate) const VERT_SOURCE: &str = r#"
     attribute vec4 a_vertex_position;
     attribute vec4 a_vertex_color;
 
@@ -17,12 +18,48 @@ const VERT_SOURCE: &str = r#"
     }
   "#;
 
-const FRAG_SOURCE: &str = r#"
+/// `u_dim` darkens towards black. Uniforms keep their value on the program from one draw to the
+/// next, so a material that does not set it gets whatever the last material drawn with this
+/// program set, `0.0` only until one does. Materials sharing the program should all set it.
+pub(crate) const FRAG_SOURCE: &str = r#"
     varying lowp vec4 v_color;
-    uniform lowp float u_time;
+    uniform lowp vec4 u_tint;
+    uniform lowp float u_dim;
 
     void main() {
-      gl_FragColor = v_color * vec4(vec3(abs(sin(u_time * 0.5))), 1.0);
+      gl_FragColor = v_color * u_tint * vec4(vec3(1.0 - u_dim), 1.0);
+    }
+"#;
+
+/// Draws one bar per instance, placed and colored by the per instance attributes
+pub(crate) const INSTANCED_VERT_SOURCE: &str = r#"
+    attribute vec2 a_vertex_position;
+    attribute vec3 a_instance_offset;
+    attribute vec2 a_instance_scale;
+    attribute vec4 a_instance_color;
+    attribute float a_instance_band;
+
+    uniform mat4 u_model_view_matrix;
+    uniform mat4 u_projection_matrix;
+    uniform float u_time;
+
+    varying lowp vec4 v_color;
+
+    void main(void) {
+      // Bars grow upwards from their offset
+      vec2 position = (a_vertex_position + vec2(0.0, 1.0)) * a_instance_scale;
+      gl_Position = u_projection_matrix * u_model_view_matrix
+        * vec4(a_instance_offset + vec3(position, 0.0), 1.0);
+      float shimmer = 0.85 + 0.15 * sin(u_time * 2.0 + a_instance_band);
+      v_color = vec4(a_instance_color.rgb * shimmer, a_instance_color.a);
+    }
+  "#;
+
+pub(crate) const INSTANCED_FRAG_SOURCE: &str = r#"
+    varying lowp vec4 v_color;
+
+    void main() {
+      gl_FragColor = v_color;
     }
 "#;
 
@@ -34,49 +71,116 @@ pub struct ProgramInfo {
 }
 
 impl ProgramInfo {
-  pub(crate) fn new(gl_context: &WebGl2RenderingContext) -> Result<Self, JsValue> {
-    let shader_program = init_shader_program(&gl_context)?;
-    let mut attrib_locations: HashMap<String, i32> = HashMap::new();
+  pub(crate) fn new(
+    gl_context: &WebGl2RenderingContext,
+    vert_source: &str,
+    frag_source: &str,
+  ) -> Result<Self, DemoError> {
+    ProgramInfo::with_feedback_varyings(gl_context, vert_source, frag_source, &[])
+  }
 
-    attrib_locations.insert(
-      "a_vertex_position".into(),
-      gl_context.get_attrib_location(&shader_program, "a_vertex_position"),
-    );
+  /// Like `new`, but the listed vertex shader outputs are captured with transform feedback
+  pub(crate) fn with_feedback_varyings(
+    gl_context: &WebGl2RenderingContext,
+    vert_source: &str,
+    frag_source: &str,
+    feedback_varyings: &[&str],
+  ) -> Result<Self, DemoError> {
+    let shader_program =
+      init_shader_program(gl_context, vert_source, frag_source, feedback_varyings)?;
+    let mut attrib_locations: HashMap<String, i32> = HashMap::new();
 
-    attrib_locations.insert(
-      "a_vertex_color".into(),
-      gl_context.get_attrib_location(&shader_program, "a_vertex_color"),
-    );
+    // Look up every active attribute and uniform so materials can set any of them by name
+    let attrib_count = gl_context
+      .get_program_parameter(&shader_program, WebGl2RenderingContext::ACTIVE_ATTRIBUTES)
+      .as_f64()
+      .unwrap_or(0.0) as u32;
+    for index in 0..attrib_count {
+      if let Some(info) = gl_context.get_active_attrib(&shader_program, index) {
+        let name = info.name();
+        let location = gl_context.get_attrib_location(&shader_program, &name);
+        attrib_locations.insert(name, location);
+      }
+    }
 
     let mut uniform_locations: HashMap<String, Option<WebGlUniformLocation>> = HashMap::new();
-    uniform_locations.insert(
-      "u_model_view_matrix".into(),
-      gl_context.get_uniform_location(&shader_program, "u_model_view_matrix"),
-    );
-    uniform_locations.insert(
-      "u_projection_matrix".into(),
-      gl_context.get_uniform_location(&shader_program, "u_projection_matrix"),
-    );
-    uniform_locations
-      .insert("u_time".into(), gl_context.get_uniform_location(&shader_program, "u_time"));
+    let uniform_count = gl_context
+      .get_program_parameter(&shader_program, WebGl2RenderingContext::ACTIVE_UNIFORMS)
+      .as_f64()
+      .unwrap_or(0.0) as u32;
+    for index in 0..uniform_count {
+      if let Some(info) = gl_context.get_active_uniform(&shader_program, index) {
+        let name = info.name();
+        let location = gl_context.get_uniform_location(&shader_program, &name);
+        uniform_locations.insert(name, location);
+      }
+    }
 
     Ok(ProgramInfo { program: shader_program, attrib_locations, uniform_locations })
   }
+
+  /// Location of an attribute the caller cannot do without
+  pub(crate) fn attrib(&self, name: &str) -> Result<u32, DemoError> {
+    match self.attrib_locations.get(name) {
+      Some(&location) if location >= 0 => Ok(location as u32),
+      _ => Err(DemoError::MissingAttribute(name.to_string())),
+    }
+  }
+
+  /// Fail early when a uniform the caller sets every frame is not there
+  pub(crate) fn require_uniforms(&self, names: &[&str]) -> Result<(), DemoError> {
+    for &name in names {
+      if self.uniform_locations.get(name).is_none_or(|location| location.is_none()) {
+        return Err(DemoError::MissingUniform(name.to_string()));
+      }
+    }
+    Ok(())
+  }
 }
 
-fn init_shader_program(gl_context: &WebGl2RenderingContext) -> Result<WebGlProgram, String> {
+fn init_shader_program(
+  gl_context: &WebGl2RenderingContext,
+  vert_source: &str,
+  frag_source: &str,
+  feedback_varyings: &[&str],
+) -> Result<WebGlProgram, DemoError> {
   // Load shaders
-  let vert_shader = load_shader(gl_context, VERT_SOURCE, WebGl2RenderingContext::VERTEX_SHADER)?;
-  let frag_shader = load_shader(gl_context, FRAG_SOURCE, WebGl2RenderingContext::FRAGMENT_SHADER)?;
+  let vert_shader = load_shader(gl_context, vert_source, WebGl2RenderingContext::VERTEX_SHADER)?;
+  let frag_shader =
+    match load_shader(gl_context, frag_source, WebGl2RenderingContext::FRAGMENT_SHADER) {
+      Ok(frag_shader) => frag_shader,
+      Err(err) => {
+        gl_context.delete_shader(Some(&vert_shader));
+        return Err(err);
+      }
+    };
 
   // Create the shader program
-  let shader_program = gl_context.create_program().ok_or_else(|| {
-    let msg = "Unable to create shader object";
-    console::log_1(&msg.into());
-    msg.to_string()
-  })?;
+  let shader_program = error::created(gl_context, gl_context.create_program(), "a program");
+  let shader_program = match shader_program {
+    Ok(shader_program) => shader_program,
+    Err(err) => {
+      gl_context.delete_shader(Some(&vert_shader));
+      gl_context.delete_shader(Some(&frag_shader));
+      return Err(err);
+    }
+  };
   gl_context.attach_shader(&shader_program, &vert_shader);
   gl_context.attach_shader(&shader_program, &frag_shader);
+  // Attached shaders are only flagged, they go along with the program
+  gl_context.delete_shader(Some(&vert_shader));
+  gl_context.delete_shader(Some(&frag_shader));
+
+  // Varyings to capture have to be declared before linking
+  if !feedback_varyings.is_empty() {
+    let varyings: js_sys::Array =
+      feedback_varyings.iter().map(|&name| JsValue::from(name)).collect();
+    gl_context.transform_feedback_varyings(
+      &shader_program,
+      &varyings,
+      WebGl2RenderingContext::INTERLEAVED_ATTRIBS,
+    );
+  }
   gl_context.link_program(&shader_program);
 
   if gl_context
@@ -86,10 +190,10 @@ fn init_shader_program(gl_context: &WebGl2RenderingContext) -> Result<WebGlProgr
   {
     Ok(shader_program)
   } else {
-    Err(gl_context.get_program_info_log(&shader_program).unwrap_or_else(|| {
-      let msg = "Unknown error creating program object";
-      console::log_1(&msg.into());
-      msg.to_string()
+    let log = gl_context.get_program_info_log(&shader_program);
+    gl_context.delete_program(Some(&shader_program));
+    Err(error::lost_or(gl_context, || {
+      DemoError::ProgramLink(log.unwrap_or_else(|| "Unknown error".to_string()))
     }))
   }
 }
@@ -98,12 +202,16 @@ fn load_shader(
   gl_context: &WebGl2RenderingContext,
   shader_source: &str,
   shader_type: u32,
-) -> Result<WebGlShader, String> {
-  let shader = gl_context.create_shader(shader_type).ok_or_else(|| {
-    let msg = "Unable to create shader object";
-    console::log_1(&msg.into());
-    msg.to_string()
-  })?;
+) -> Result<WebGlShader, DemoError> {
+  let stage = match shader_type {
+    WebGl2RenderingContext::VERTEX_SHADER => "vertex",
+    _ => "fragment",
+  };
+  let shader = error::created(
+    gl_context,
+    gl_context.create_shader(shader_type),
+    &format!("a {} shader", stage),
+  )?;
   gl_context.shader_source(&shader, shader_source);
   gl_context.compile_shader(&shader);
 
@@ -114,10 +222,11 @@ fn load_shader(
   {
     Ok(shader)
   } else {
-    Err(gl_context.get_shader_info_log(&shader).unwrap_or_else(|| {
-      let msg = "Unknown error creating shader";
-      console::log_1(&msg.into());
-      msg.to_string()
+    let log = gl_context.get_shader_info_log(&shader);
+    gl_context.delete_shader(Some(&shader));
+    Err(error::lost_or(gl_context, || DemoError::ShaderCompile {
+      stage,
+      log: log.unwrap_or_else(|| "Unknown error".to_string()),
     }))
   }
 }
@@ -0,0 +1,231 @@
+use std::cell::RefCell;
+use wasm_bindgen::prelude::*;
+
+/// From best to cheapest, the governor steps through these one at a time
+pub const LEVELS: [QualityLevel; 5] = [
+  QualityLevel { resolution_scale: 1.0, msaa_samples: 4, optional_passes: true, particles: 1.0 },
+  QualityLevel { resolution_scale: 1.0, msaa_samples: 0, optional_passes: true, particles: 1.0 },
+  QualityLevel { resolution_scale: 1.0, msaa_samples: 0, optional_passes: true, particles: 0.5 },
+  QualityLevel { resolution_scale: 0.75, msaa_samples: 0, optional_passes: false, particles: 0.5 },
+  QualityLevel { resolution_scale: 0.5, msaa_samples: 0, optional_passes: false, particles: 0.25 },
+];
+
+/// Frames slower than the target by this factor count as too slow
+const SLOW_FACTOR: f32 = 1.15;
+/// Seconds of slow frames before stepping down
+const STEP_DOWN_AFTER: f32 = 1.0;
+/// A slow frame counts for at most this many frame budgets, so one long stall, like a tab
+/// switch or a shader compile, cannot add up to a step down by itself
+const MAX_SLOW_FRAME: f32 = 2.0;
+/// The CPU and GPU must be busy for less than this share of the frame budget to step up
+const HEADROOM: f32 = 0.6;
+/// Seconds of comfortable frames before stepping up, doubled every time a step up had to be
+/// taken back, so a level that cannot be held is not retried over and over
+const STEP_UP_AFTER: f32 = 4.0;
+const MAX_STEP_UP_AFTER: f32 = 60.0;
+/// A step down this soon after a step up means the step up was a mistake
+const REGRET_WINDOW: f32 = 3.0;
+
+thread_local! {
+  /// Settings from `set_target_fps` and `set_adaptive_quality`, read by the render loop
+  static SETTINGS: RefCell<QualitySettings> = const {
+    RefCell::new(QualitySettings { target_fps: 60.0, adaptive: true })
+  };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QualitySettings {
+  pub target_fps: f32,
+  /// When off, the best level is always used
+  pub adaptive: bool,
+}
+
+/// How much work a frame is allowed to take
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QualityLevel {
+  /// Size of the rendered image relative to the canvas
+  pub resolution_scale: f32,
+  /// `0` to render without multisampling
+  pub msaa_samples: i32,
+  /// Whether scene passes marked `optional`, like post-processing, are drawn
+  pub optional_passes: bool,
+  /// Share of the particles that are simulated and drawn
+  pub particles: f32,
+}
+
+/// The frame rate to hold, 60 by default
+#[wasm_bindgen]
+pub fn set_target_fps(fps: f32) -> Result<(), JsValue> {
+  if !fps.is_finite() || fps <= 0.0 {
+    return Err(format!("Expected a positive frame rate, got {}", fps).into());
+  }
+  SETTINGS.with(|settings| settings.borrow_mut().target_fps = fps);
+  Ok(())
+}
+
+/// Turn the quality governor on or off, off renders everything at the best quality
+#[wasm_bindgen]
+pub fn set_adaptive_quality(enabled: bool) {
+  SETTINGS.with(|settings| settings.borrow_mut().adaptive = enabled);
+}
+
+pub(crate) fn settings() -> QualitySettings {
+  SETTINGS.with(|settings| *settings.borrow())
+}
+
+/// Steps through `LEVELS` to hold a target frame rate. Stepping down is quick and stepping up
+/// is slow, and only happens with time to spare, so the level does not flip back and forth.
+pub struct QualityGovernor {
+  level: usize,
+  /// Seconds the frames have been too slow, or comfortably fast, in a row
+  slow_for: f32,
+  fast_for: f32,
+  step_up_after: f32,
+  /// Seconds since the last step up
+  since_step_up: f32,
+}
+
+impl QualityGovernor {
+  pub fn new() -> Self {
+    QualityGovernor {
+      level: 0,
+      slow_for: 0.0,
+      fast_for: 0.0,
+      step_up_after: STEP_UP_AFTER,
+      since_step_up: f32::MAX,
+    }
+  }
+
+  /// Index into `LEVELS`, `0` being the best
+  pub fn level(&self) -> usize {
+    self.level
+  }
+
+  pub fn quality(&self) -> QualityLevel {
+    LEVELS[self.level]
+  }
+
+  /// Feed one frame: the milliseconds since the previous frame, and how many of them the CPU or
+  /// GPU were busy when that is known. Returns `true` when the level changed.
+  pub fn update(&mut self, target_fps: f32, interval_ms: f32, busy_ms: Option<f32>) -> bool {
+    let budget_ms = 1000.0 / target_fps;
+    let seconds = interval_ms / 1000.0;
+    self.since_step_up += seconds;
+
+    if interval_ms > budget_ms * SLOW_FACTOR {
+      self.slow_for += seconds.min(budget_ms * MAX_SLOW_FRAME / 1000.0);
+      self.fast_for = 0.0;
+    } else if busy_ms.is_none_or(|busy_ms| busy_ms < budget_ms * HEADROOM) {
+      self.fast_for += seconds;
+      self.slow_for = 0.0;
+    } else {
+      // On target but without room to spare, stay put
+      self.slow_for = 0.0;
+      self.fast_for = 0.0;
+    }
+
+    if self.slow_for >= STEP_DOWN_AFTER && self.level + 1 < LEVELS.len() {
+      if self.since_step_up < REGRET_WINDOW {
+        self.step_up_after = (self.step_up_after * 2.0).min(MAX_STEP_UP_AFTER);
+      }
+      self.change(self.level + 1)
+    } else if self.fast_for >= self.step_up_after && self.level > 0 {
+      self.since_step_up = 0.0;
+      self.change(self.level - 1)
+    } else {
+      false
+    }
+  }
+
+  fn change(&mut self, level: usize) -> bool {
+    self.level = level;
+    self.slow_for = 0.0;
+    self.fast_for = 0.0;
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Run `seconds` of frames `interval_ms` apart, returning every level change
+  fn run(
+    governor: &mut QualityGovernor,
+    seconds: f32,
+    interval_ms: f32,
+    busy_ms: Option<f32>,
+  ) -> Vec<usize> {
+    let frames = (seconds * 1000.0 / interval_ms).round() as usize;
+    let mut changes = Vec::new();
+    for _ in 0..frames {
+      if governor.update(60.0, interval_ms, busy_ms) {
+        changes.push(governor.level());
+      }
+    }
+    changes
+  }
+
+  #[test]
+  fn holds_the_best_level_at_the_target() {
+    let mut governor = QualityGovernor::new();
+    assert!(run(&mut governor, 30.0, 16.7, Some(8.0)).is_empty());
+    assert_eq!(governor.level(), 0);
+  }
+
+  #[test]
+  fn steps_down_one_level_per_second_of_slow_frames() {
+    let mut governor = QualityGovernor::new();
+    assert_eq!(run(&mut governor, 2.1, 33.3, None), vec![1, 2]);
+    assert_eq!(run(&mut governor, 10.0, 33.3, None), vec![3, 4]);
+    assert_eq!(governor.quality(), LEVELS[LEVELS.len() - 1]);
+  }
+
+  #[test]
+  fn a_single_hitch_does_not_step_down() {
+    let mut governor = QualityGovernor::new();
+    let mut changes = run(&mut governor, 5.0, 16.7, Some(8.0));
+    changes.extend(run(&mut governor, 0.5, 100.0, None));
+    changes.extend(run(&mut governor, 5.0, 16.7, Some(8.0)));
+    assert!(changes.is_empty());
+  }
+
+  #[test]
+  fn a_long_stall_does_not_step_down() {
+    let mut governor = QualityGovernor::new();
+    let mut changes = run(&mut governor, 5.0, 16.7, Some(8.0));
+    changes.extend(run(&mut governor, 2.0, 2000.0, None));
+    changes.extend(run(&mut governor, 5.0, 16.7, Some(8.0)));
+    assert!(changes.is_empty());
+  }
+
+  #[test]
+  fn steps_up_only_with_headroom() {
+    let mut governor = QualityGovernor::new();
+    run(&mut governor, 1.1, 33.3, None);
+    assert_eq!(governor.level(), 1);
+
+    // On target but busy for most of the frame, stepping up would just drop frames again
+    assert!(run(&mut governor, 20.0, 16.7, Some(14.0)).is_empty());
+    assert_eq!(run(&mut governor, STEP_UP_AFTER + 0.1, 16.7, Some(5.0)), vec![0]);
+  }
+
+  #[test]
+  fn backs_off_after_a_step_up_that_cannot_be_held() {
+    let mut governor = QualityGovernor::new();
+    run(&mut governor, 1.1, 33.3, None);
+
+    // Every step up is followed by slow frames, each retry waits twice as long
+    let mut waits = Vec::new();
+    for _ in 0..3 {
+      let mut waited = 0.0;
+      while !governor.update(60.0, 16.7, Some(5.0)) {
+        waited += 0.0167;
+        assert!(waited < 100.0, "never stepped up");
+      }
+      waits.push(waited);
+      assert_eq!(run(&mut governor, 1.1, 33.3, None), vec![1]);
+    }
+    assert!(waits[1] > waits[0] * 1.9 && waits[2] > waits[1] * 1.9, "{:?}", waits);
+  }
+}
@@ -0,0 +1,191 @@
+use crate::{document, error::DemoError, utils, window};
+use js_sys::Array;
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{
+  AudioContext, Blob, BlobEvent, BlobPropertyBag, HtmlCanvasElement, HtmlSelectElement,
+  MediaRecorder, MediaRecorderOptions, MediaStream, MediaStreamAudioDestinationNode,
+  RecordingState,
+};
+
+const RECORD_BUTTON: &str = "record";
+const DOWNLOAD_BUTTON: &str = "download-recording";
+/// A `<select>` whose values are video bitrates in bits per second
+const BITRATE_SELECT: &str = "record-bitrate";
+const DEFAULT_BITRATE: u32 = 8_000_000;
+/// How often the recorder hands over what it has so far, in milliseconds
+const TIME_SLICE_MS: i32 = 1000;
+/// Tried in order, the first one the browser can record is used
+const MIME_TYPES: [&str; 3] =
+  ["video/webm;codecs=vp9,opus", "video/webm;codecs=vp8,opus", "video/webm"];
+
+thread_local! {
+  static RECORDER: RefCell<Recorder> = RefCell::new(Recorder::default());
+}
+
+#[derive(Default)]
+struct Recorder {
+  /// Where everything the audio context plays is mirrored to, set up by the audio loop
+  audio: Option<(AudioContext, MediaStreamAudioDestinationNode)>,
+  media_recorder: Option<MediaRecorder>,
+  mime_type: String,
+  /// Recorded so far, kept after stopping until the next recording starts
+  chunks: Rc<RefCell<Vec<Blob>>>,
+}
+
+/// The node to connect whatever should end up in recordings to
+pub(crate) fn init_audio(
+  context: &AudioContext,
+) -> Result<MediaStreamAudioDestinationNode, JsValue> {
+  let destination = context.create_media_stream_destination()?;
+  RECORDER.with(|recorder| {
+    recorder.borrow_mut().audio = Some((context.clone(), destination.clone()));
+  });
+  Ok(destination)
+}
+
+/// Hook up the record and download buttons when the page has them
+pub(crate) fn init_controls() -> Result<(), JsValue> {
+  let document = document()?;
+
+  if let Some(button) = document.get_element_by_id(RECORD_BUTTON) {
+    let closure = Closure::wrap(Box::new(move || {
+      let result = if is_recording() {
+        stop_recording()
+      } else {
+        let bitrate = window()
+          .document()
+          .and_then(|document| document.get_element_by_id(BITRATE_SELECT))
+          .and_then(|select| select.dyn_into::<HtmlSelectElement>().ok())
+          .and_then(|select| select.value().parse().ok());
+        start_recording(bitrate)
+      };
+      if let Err(err) = result {
+        web_sys::console::error_1(&err);
+      }
+    }) as Box<dyn FnMut()>);
+    button.add_event_listener_with_callback("click", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  if let Some(button) = document.get_element_by_id(DOWNLOAD_BUTTON) {
+    let closure = Closure::wrap(Box::new(move || {
+      if let Err(err) = download_recording(None) {
+        web_sys::console::error_1(&err);
+      }
+    }) as Box<dyn FnMut()>);
+    button.add_event_listener_with_callback("click", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  Ok(())
+}
+
+/// Start recording the canvas together with the audio, at `video_bits_per_second` or 8 Mbit/s.
+/// Any previous recording is thrown away.
+#[wasm_bindgen]
+pub fn start_recording(video_bits_per_second: Option<u32>) -> Result<(), JsValue> {
+  if is_recording() {
+    return Err("Already recording".into());
+  }
+  let document = document()?;
+  let canvas = document
+    .get_element_by_id("canvas")
+    .ok_or_else(|| DemoError::MissingElement("the canvas".to_string()))?
+    .dyn_into::<HtmlCanvasElement>()?;
+
+  RECORDER.with(|recorder| {
+    let mut recorder = recorder.borrow_mut();
+
+    // One stream carrying the canvas frames and the mixed audio
+    let tracks = canvas.capture_stream()?.get_video_tracks();
+    if let Some((context, destination)) = &recorder.audio {
+      // A suspended context would record silence
+      let _ = context.resume();
+      for track in destination.stream().get_audio_tracks().iter() {
+        tracks.push(&track);
+      }
+    }
+    let stream = MediaStream::new_with_tracks(&tracks)?;
+
+    let mime_type = MIME_TYPES
+      .iter()
+      .find(|mime_type| MediaRecorder::is_type_supported(mime_type))
+      .ok_or_else(|| DemoError::UnsupportedFeature("WebM recording".to_string()))?;
+    let options = MediaRecorderOptions::new();
+    options.set_mime_type(mime_type);
+    options.set_video_bits_per_second(video_bits_per_second.unwrap_or(DEFAULT_BITRATE));
+    let media_recorder =
+      MediaRecorder::new_with_media_stream_and_media_recorder_options(&stream, &options)?;
+
+    recorder.chunks.borrow_mut().clear();
+    let chunks = recorder.chunks.clone();
+    let on_data = Closure::wrap(Box::new(move |event: BlobEvent| {
+      if let Some(data) = event.data() {
+        chunks.borrow_mut().push(data);
+      }
+    }) as Box<dyn FnMut(BlobEvent)>);
+    media_recorder.set_ondataavailable(Some(on_data.as_ref().unchecked_ref()));
+    on_data.forget();
+
+    let on_state = Closure::wrap(Box::new(move || show_status(is_recording())) as Box<dyn FnMut()>);
+    media_recorder.set_onstart(Some(on_state.as_ref().unchecked_ref()));
+    media_recorder.set_onstop(Some(on_state.as_ref().unchecked_ref()));
+    on_state.forget();
+
+    media_recorder.start_with_time_slice(TIME_SLICE_MS)?;
+    recorder.media_recorder = Some(media_recorder);
+    recorder.mime_type = mime_type.to_string();
+    Ok(())
+  })
+}
+
+/// Stop recording, what was recorded stays available to `download_recording`
+#[wasm_bindgen]
+pub fn stop_recording() -> Result<(), JsValue> {
+  RECORDER.with(|recorder| match &recorder.borrow().media_recorder {
+    Some(media_recorder) if media_recorder.state() != RecordingState::Inactive => {
+      media_recorder.stop()
+    }
+    _ => Err("Not recording".into()),
+  })
+}
+
+#[wasm_bindgen]
+pub fn is_recording() -> bool {
+  RECORDER.with(|recorder| {
+    let recorder = recorder.borrow();
+    let media_recorder = recorder.media_recorder.as_ref();
+    media_recorder.is_some_and(|media_recorder| media_recorder.state() == RecordingState::Recording)
+  })
+}
+
+/// Everything recorded so far as a single WebM blob
+#[wasm_bindgen]
+pub fn recording() -> Result<Blob, JsValue> {
+  RECORDER.with(|recorder| {
+    let recorder = recorder.borrow();
+    if recorder.chunks.borrow().is_empty() {
+      return Err("Nothing has been recorded".into());
+    }
+    let parts: Array = recorder.chunks.borrow().iter().collect();
+    let properties = BlobPropertyBag::new();
+    properties.set_type(&recorder.mime_type);
+    Blob::new_with_blob_sequence_and_options(&parts, &properties)
+  })
+}
+
+/// Save the recording through the browser's downloads, as `file_name` or `democ.webm`
+#[wasm_bindgen]
+pub fn download_recording(file_name: Option<String>) -> Result<(), JsValue> {
+  utils::download(&recording()?, file_name.as_deref().unwrap_or("democ.webm"))
+}
+
+/// Reflect the recording state on the record button, like the play/pause button does
+fn show_status(recording: bool) {
+  let button = window().document().and_then(|document| document.get_element_by_id(RECORD_BUTTON));
+  if let Some(button) = button {
+    let _ = button.set_attribute("data-recording", &recording.to_string());
+    let _ = button.set_attribute("aria-checked", &recording.to_string());
+  }
+}
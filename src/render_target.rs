@@ -0,0 +1,301 @@
+use crate::error::{self, DemoError};
+use wasm_bindgen::prelude::*;
+use web_sys::{WebGl2RenderingContext, WebGlFramebuffer, WebGlRenderbuffer, WebGlTexture};
+
+/// An offscreen framebuffer with a color texture that can be sampled afterwards and a depth buffer
+pub struct RenderTarget {
+  pub framebuffer: WebGlFramebuffer,
+  pub texture: WebGlTexture,
+  depth: WebGlRenderbuffer,
+  width: i32,
+  height: i32,
+}
+
+impl RenderTarget {
+  /// An empty target, `resize` allocates the storage
+  pub(crate) fn new(gl_context: &WebGl2RenderingContext) -> Result<Self, JsValue> {
+    let framebuffer = error::created(gl_context, gl_context.create_framebuffer(), "a framebuffer")?;
+    let texture = error::created(gl_context, gl_context.create_texture(), "a render texture")?;
+    let depth =
+      error::created(gl_context, gl_context.create_renderbuffer(), "a depth renderbuffer")?;
+    Ok(RenderTarget { framebuffer, texture, depth, width: 0, height: 0 })
+  }
+
+  /// Reallocate the storage when the size changed, the contents are lost when it does
+  pub(crate) fn resize(
+    &mut self,
+    gl_context: &WebGl2RenderingContext,
+    width: i32,
+    height: i32,
+  ) -> Result<(), JsValue> {
+    if (width, height) == (self.width, self.height) {
+      return Ok(());
+    }
+
+    gl_context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&self.texture));
+    gl_context.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+      WebGl2RenderingContext::TEXTURE_2D,
+      0,
+      WebGl2RenderingContext::RGBA8 as i32,
+      width,
+      height,
+      0,
+      WebGl2RenderingContext::RGBA,
+      WebGl2RenderingContext::UNSIGNED_BYTE,
+      None,
+    )?;
+    // No mipmaps, and the edges must not wrap around
+    for (parameter, value) in [
+      (WebGl2RenderingContext::TEXTURE_MIN_FILTER, WebGl2RenderingContext::LINEAR),
+      (WebGl2RenderingContext::TEXTURE_MAG_FILTER, WebGl2RenderingContext::LINEAR),
+      (WebGl2RenderingContext::TEXTURE_WRAP_S, WebGl2RenderingContext::CLAMP_TO_EDGE),
+      (WebGl2RenderingContext::TEXTURE_WRAP_T, WebGl2RenderingContext::CLAMP_TO_EDGE),
+    ] {
+      gl_context.tex_parameteri(WebGl2RenderingContext::TEXTURE_2D, parameter, value as i32);
+    }
+    gl_context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, None);
+
+    gl_context.bind_renderbuffer(WebGl2RenderingContext::RENDERBUFFER, Some(&self.depth));
+    gl_context.renderbuffer_storage(
+      WebGl2RenderingContext::RENDERBUFFER,
+      WebGl2RenderingContext::DEPTH_COMPONENT24,
+      width,
+      height,
+    );
+    gl_context.bind_renderbuffer(WebGl2RenderingContext::RENDERBUFFER, None);
+
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, Some(&self.framebuffer));
+    gl_context.framebuffer_texture_2d(
+      WebGl2RenderingContext::FRAMEBUFFER,
+      WebGl2RenderingContext::COLOR_ATTACHMENT0,
+      WebGl2RenderingContext::TEXTURE_2D,
+      Some(&self.texture),
+      0,
+    );
+    gl_context.framebuffer_renderbuffer(
+      WebGl2RenderingContext::FRAMEBUFFER,
+      WebGl2RenderingContext::DEPTH_ATTACHMENT,
+      WebGl2RenderingContext::RENDERBUFFER,
+      Some(&self.depth),
+    );
+    let status = gl_context.check_framebuffer_status(WebGl2RenderingContext::FRAMEBUFFER);
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+    if status != WebGl2RenderingContext::FRAMEBUFFER_COMPLETE {
+      return Err(error::lost_or(gl_context, || DemoError::IncompleteFramebuffer(status)).into());
+    }
+
+    self.width = width;
+    self.height = height;
+    Ok(())
+  }
+
+  /// Draw into this target instead of the canvas until the default framebuffer is bound again
+  pub(crate) fn bind(&self, gl_context: &WebGl2RenderingContext) {
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, Some(&self.framebuffer));
+    gl_context.viewport(0, 0, self.width, self.height);
+  }
+
+  /// Copy the color onto `destination`, the canvas when `None`, stretched to `width` × `height`
+  pub(crate) fn blit(
+    &self,
+    gl_context: &WebGl2RenderingContext,
+    destination: Option<&WebGlFramebuffer>,
+    width: i32,
+    height: i32,
+  ) {
+    gl_context.bind_framebuffer(WebGl2RenderingContext::READ_FRAMEBUFFER, Some(&self.framebuffer));
+    gl_context.bind_framebuffer(WebGl2RenderingContext::DRAW_FRAMEBUFFER, destination);
+    gl_context.blit_framebuffer(
+      0,
+      0,
+      self.width,
+      self.height,
+      0,
+      0,
+      width,
+      height,
+      WebGl2RenderingContext::COLOR_BUFFER_BIT,
+      WebGl2RenderingContext::LINEAR,
+    );
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+  }
+
+  pub(crate) fn delete(&self, gl_context: &WebGl2RenderingContext) {
+    gl_context.delete_framebuffer(Some(&self.framebuffer));
+    gl_context.delete_texture(Some(&self.texture));
+    gl_context.delete_renderbuffer(Some(&self.depth));
+  }
+}
+
+/// Multisampled color and depth, which cannot be sampled and has to be resolved into a
+/// `RenderTarget` once drawn
+pub struct MultisampleTarget {
+  framebuffer: WebGlFramebuffer,
+  color: WebGlRenderbuffer,
+  depth: WebGlRenderbuffer,
+  width: i32,
+  height: i32,
+  samples: i32,
+}
+
+impl MultisampleTarget {
+  pub(crate) fn new(gl_context: &WebGl2RenderingContext) -> Result<Self, JsValue> {
+    Ok(MultisampleTarget {
+      framebuffer: error::created(gl_context, gl_context.create_framebuffer(), "a framebuffer")?,
+      color: error::created(gl_context, gl_context.create_renderbuffer(), "a color renderbuffer")?,
+      depth: error::created(gl_context, gl_context.create_renderbuffer(), "a depth renderbuffer")?,
+      width: 0,
+      height: 0,
+      samples: 0,
+    })
+  }
+
+  /// Reallocate the storage when the size or sample count changed. More samples than the GPU
+  /// supports are reduced to its maximum.
+  pub(crate) fn resize(
+    &mut self,
+    gl_context: &WebGl2RenderingContext,
+    width: i32,
+    height: i32,
+    samples: i32,
+  ) -> Result<(), JsValue> {
+    let max_samples = gl_context
+      .get_parameter(WebGl2RenderingContext::MAX_SAMPLES)?
+      .as_f64()
+      .map_or(0, |max_samples| max_samples as i32);
+    let samples = samples.min(max_samples);
+    if (width, height, samples) == (self.width, self.height, self.samples) {
+      return Ok(());
+    }
+
+    for (renderbuffer, format) in [
+      (&self.color, WebGl2RenderingContext::RGBA8),
+      (&self.depth, WebGl2RenderingContext::DEPTH_COMPONENT24),
+    ] {
+      gl_context.bind_renderbuffer(WebGl2RenderingContext::RENDERBUFFER, Some(renderbuffer));
+      gl_context.renderbuffer_storage_multisample(
+        WebGl2RenderingContext::RENDERBUFFER,
+        samples,
+        format,
+        width,
+        height,
+      );
+    }
+    gl_context.bind_renderbuffer(WebGl2RenderingContext::RENDERBUFFER, None);
+
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, Some(&self.framebuffer));
+    for (attachment, renderbuffer) in [
+      (WebGl2RenderingContext::COLOR_ATTACHMENT0, &self.color),
+      (WebGl2RenderingContext::DEPTH_ATTACHMENT, &self.depth),
+    ] {
+      gl_context.framebuffer_renderbuffer(
+        WebGl2RenderingContext::FRAMEBUFFER,
+        attachment,
+        WebGl2RenderingContext::RENDERBUFFER,
+        Some(renderbuffer),
+      );
+    }
+    let status = gl_context.check_framebuffer_status(WebGl2RenderingContext::FRAMEBUFFER);
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+    if status != WebGl2RenderingContext::FRAMEBUFFER_COMPLETE {
+      return Err(error::lost_or(gl_context, || DemoError::IncompleteFramebuffer(status)).into());
+    }
+
+    self.width = width;
+    self.height = height;
+    self.samples = samples;
+    Ok(())
+  }
+
+  pub(crate) fn bind(&self, gl_context: &WebGl2RenderingContext) {
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, Some(&self.framebuffer));
+    gl_context.viewport(0, 0, self.width, self.height);
+  }
+
+  /// Average the samples into `target`, which has to be the same size
+  pub(crate) fn resolve(&self, gl_context: &WebGl2RenderingContext, target: &RenderTarget) {
+    gl_context.bind_framebuffer(WebGl2RenderingContext::READ_FRAMEBUFFER, Some(&self.framebuffer));
+    gl_context
+      .bind_framebuffer(WebGl2RenderingContext::DRAW_FRAMEBUFFER, Some(&target.framebuffer));
+    gl_context.blit_framebuffer(
+      0,
+      0,
+      self.width,
+      self.height,
+      0,
+      0,
+      self.width,
+      self.height,
+      WebGl2RenderingContext::COLOR_BUFFER_BIT,
+      WebGl2RenderingContext::NEAREST,
+    );
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+  }
+}
+
+/// Stands in for the canvas when the frame is drawn at a lower resolution or multisampled, and
+/// stretches the result over the canvas afterwards
+pub struct CanvasTarget {
+  resolved: RenderTarget,
+  multisample: MultisampleTarget,
+  /// How the current frame is being drawn, set by `bind`
+  scaled: bool,
+  multisampled: bool,
+}
+
+impl CanvasTarget {
+  pub(crate) fn new(gl_context: &WebGl2RenderingContext) -> Result<Self, JsValue> {
+    Ok(CanvasTarget {
+      resolved: RenderTarget::new(gl_context)?,
+      multisample: MultisampleTarget::new(gl_context)?,
+      scaled: false,
+      multisampled: false,
+    })
+  }
+
+  /// Bind what the frame should be drawn into, the canvas itself at full resolution without
+  /// multisampling
+  pub(crate) fn bind(
+    &mut self,
+    gl_context: &WebGl2RenderingContext,
+    width: i32,
+    height: i32,
+    scale: f32,
+    samples: i32,
+  ) -> Result<(), JsValue> {
+    self.multisampled = samples > 0;
+    self.scaled = scale < 1.0 || self.multisampled;
+    if !self.scaled {
+      gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+      gl_context.viewport(0, 0, width, height);
+      return Ok(());
+    }
+
+    let (scaled_width, scaled_height) = scaled_size(width, height, scale);
+    self.resolved.resize(gl_context, scaled_width, scaled_height)?;
+    if self.multisampled {
+      self.multisample.resize(gl_context, scaled_width, scaled_height, samples)?;
+      self.multisample.bind(gl_context);
+    } else {
+      self.resolved.bind(gl_context);
+    }
+    Ok(())
+  }
+
+  /// Bring what was drawn since `bind` onto the canvas
+  pub(crate) fn present(&self, gl_context: &WebGl2RenderingContext, width: i32, height: i32) {
+    if !self.scaled {
+      return;
+    }
+    if self.multisampled {
+      self.multisample.resolve(gl_context, &self.resolved);
+    }
+    self.resolved.blit(gl_context, None, width, height);
+  }
+}
+
+/// `width` × `height` scaled down, never below a pixel
+pub fn scaled_size(width: i32, height: i32, scale: f32) -> (i32, i32) {
+  let scale = |size: i32| ((size as f32 * scale).round() as i32).max(1);
+  (scale(width), scale(height))
+}
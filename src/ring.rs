@@ -0,0 +1,361 @@
+use js_sys::{Atomics, Int32Array};
+use std::sync::{
+  atomic::{AtomicU32, Ordering},
+  Arc,
+};
+
+/// Words before the frames: the write and read counts, the overflow and underrun counts, the
+/// words per frame and the capacity in frames, then two unused words to keep frames aligned
+pub(crate) const HEADER_WORDS: usize = 8;
+const WRITE: usize = 0;
+const READ: usize = 1;
+const OVERFLOWS: usize = 2;
+const UNDERRUNS: usize = 3;
+const STRIDE: usize = 4;
+const CAPACITY: usize = 5;
+
+/// 32 bit words both sides of the ring can reach, atomics natively and a view of a
+/// `SharedArrayBuffer` in the browser, where the audio thread writes with `Atomics` from JS
+pub trait Words {
+  fn load(&self, index: usize) -> u32;
+  fn store(&self, index: usize, value: u32);
+}
+
+impl Words for Arc<[AtomicU32]> {
+  fn load(&self, index: usize) -> u32 {
+    self[index].load(Ordering::Acquire)
+  }
+
+  fn store(&self, index: usize, value: u32) {
+    self[index].store(value, Ordering::Release)
+  }
+}
+
+impl Words for Int32Array {
+  fn load(&self, index: usize) -> u32 {
+    Atomics::load(self, index as u32).unwrap_or(0) as u32
+  }
+
+  fn store(&self, index: usize, value: u32) {
+    let _ = Atomics::store(self, index as u32, value as i32);
+  }
+}
+
+/// Words a ring of `capacity` frames of `stride` values takes, capacities are rounded up to a
+/// power of two so the counts can wrap
+pub fn words_needed(capacity: usize, stride: usize) -> usize {
+  HEADER_WORDS + capacity.next_power_of_two() * stride
+}
+
+/// Frames of `f32` values handed from one producer to one consumer without locks. Both sides
+/// only ever write their own count, so neither waits on the other: a full ring drops the new
+/// frame and an empty one has nothing to give, and both are counted in the header.
+#[derive(Clone)]
+pub struct FeatureRing<W> {
+  words: W,
+  stride: usize,
+  capacity: u32,
+}
+
+impl<W: Words + Clone> FeatureRing<W> {
+  /// Lay out an empty ring in `words`, which has to hold `words_needed(capacity, stride)`
+  pub fn create(words: W, capacity: usize, stride: usize) -> Self {
+    let capacity = capacity.next_power_of_two() as u32;
+    for index in [WRITE, READ, OVERFLOWS, UNDERRUNS] {
+      words.store(index, 0);
+    }
+    words.store(STRIDE, stride as u32);
+    words.store(CAPACITY, capacity);
+    FeatureRing { words, stride, capacity }
+  }
+
+  /// The reading end, the writing one is `RingProducer` in the worklet's JS
+  pub fn consumer(self) -> Consumer<W> {
+    Consumer { ring: self }
+  }
+
+  /// The two ends, each to be used from a single thread
+  #[cfg(test)]
+  pub fn split(self) -> (Producer<W>, Consumer<W>) {
+    (Producer { ring: self.clone() }, Consumer { ring: self })
+  }
+
+  /// Frames pushed but not popped yet
+  pub fn len(&self) -> usize {
+    self.words.load(WRITE).wrapping_sub(self.words.load(READ)) as usize
+  }
+
+  /// Frames dropped because the consumer fell behind
+  pub fn overflows(&self) -> u32 {
+    self.words.load(OVERFLOWS)
+  }
+
+  /// Times the consumer wanted a frame that had not arrived
+  pub fn underruns(&self) -> u32 {
+    self.words.load(UNDERRUNS)
+  }
+
+  fn slot(&self, count: u32) -> usize {
+    HEADER_WORDS + (count % self.capacity) as usize * self.stride
+  }
+
+  fn count(&self, index: usize) {
+    self.words.store(index, self.words.load(index).wrapping_add(1));
+  }
+}
+
+/// Stands in for the worklet's `RingProducer` in tests, the two have to write the same words
+#[cfg(test)]
+pub struct Producer<W> {
+  ring: FeatureRing<W>,
+}
+
+#[cfg(test)]
+impl<W: Words + Clone> Producer<W> {
+  /// Append a frame, missing values are zero and extra ones ignored. `false` when the ring was
+  /// full and the frame dropped.
+  pub fn push(&self, frame: &[f32]) -> bool {
+    let words = &self.ring.words;
+    let write = words.load(WRITE);
+    if write.wrapping_sub(words.load(READ)) >= self.ring.capacity {
+      self.ring.count(OVERFLOWS);
+      return false;
+    }
+    let slot = self.ring.slot(write);
+    for index in 0..self.ring.stride {
+      words.store(slot + index, frame.get(index).copied().unwrap_or(0.0).to_bits());
+    }
+    // Publishes the frame, the consumer does not look at the slot before this
+    words.store(WRITE, write.wrapping_add(1));
+    true
+  }
+}
+
+pub struct Consumer<W> {
+  ring: FeatureRing<W>,
+}
+
+impl<W: Words + Clone> Consumer<W> {
+  /// Take the oldest frame into `frame`, `false` when there is none
+  pub fn pop(&self, frame: &mut [f32]) -> bool {
+    let words = &self.ring.words;
+    let read = words.load(READ);
+    if read == words.load(WRITE) {
+      return false;
+    }
+    let slot = self.ring.slot(read);
+    for (index, value) in frame.iter_mut().take(self.ring.stride).enumerate() {
+      *value = f32::from_bits(words.load(slot + index));
+    }
+    // Hands the slot back to the producer
+    words.store(READ, read.wrapping_add(1));
+    true
+  }
+
+  pub fn ring(&self) -> &FeatureRing<W> {
+    &self.ring
+  }
+}
+
+/// Features at any moment between the frames of a ring whose frames start with their time in
+/// seconds, so the renderer sees smooth values however its frames line up with the audio's
+pub struct Interpolator {
+  before: Vec<f32>,
+  after: Vec<f32>,
+  /// Which of the two hold a frame
+  has_before: bool,
+  has_after: bool,
+}
+
+impl Interpolator {
+  pub fn new(stride: usize) -> Self {
+    Interpolator {
+      before: vec![0.0; stride],
+      after: vec![0.0; stride],
+      has_before: false,
+      has_after: false,
+    }
+  }
+
+  /// Pop every frame up to the first one after `time`, handing each to `popped`, and write the
+  /// values at `time` into `out`. The latest values are held, and an underrun counted, when
+  /// nothing has arrived past `time` yet. `false` before the first frame.
+  pub fn sample<W: Words + Clone>(
+    &mut self,
+    consumer: &Consumer<W>,
+    time: f32,
+    out: &mut [f32],
+    mut popped: impl FnMut(&[f32]),
+  ) -> bool {
+    loop {
+      if self.has_after {
+        if self.after[0] > time {
+          break;
+        }
+        std::mem::swap(&mut self.before, &mut self.after);
+        self.has_before = true;
+        self.has_after = false;
+      }
+      if !consumer.pop(&mut self.after) {
+        if self.has_before {
+          consumer.ring.count(UNDERRUNS);
+        }
+        break;
+      }
+      self.has_after = true;
+      popped(&self.after);
+    }
+
+    let values = match (self.has_before, self.has_after) {
+      (true, true) => {
+        let span = self.after[0] - self.before[0];
+        let amount =
+          if span > 0.0 { ((time - self.before[0]) / span).clamp(0.0, 1.0) } else { 1.0 };
+        for ((value, before), after) in out.iter_mut().zip(&self.before).zip(&self.after) {
+          *value = before + (after - before) * amount;
+        }
+        return true;
+      }
+      (true, false) => &self.before,
+      // Everything there is lies ahead, it is the best guess until `time` catches up
+      (false, true) => &self.after,
+      (false, false) => return false,
+    };
+    for (value, held) in out.iter_mut().zip(values) {
+      *value = *held;
+    }
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::thread;
+
+  fn ring(capacity: usize, stride: usize) -> FeatureRing<Arc<[AtomicU32]>> {
+    let words: Arc<[AtomicU32]> =
+      (0..words_needed(capacity, stride)).map(|_| AtomicU32::new(0)).collect();
+    FeatureRing::create(words, capacity, stride)
+  }
+
+  /// `worklet/analyser-processor.js` hard codes this layout
+  #[test]
+  fn the_header_matches_the_worklet() {
+    assert_eq!((HEADER_WORDS, WRITE, READ, OVERFLOWS, STRIDE, CAPACITY), (8, 0, 1, 2, 4, 5));
+
+    let ring = ring(2, 3);
+    let words = ring.words.clone();
+    assert_eq!((words.load(STRIDE), words.load(CAPACITY)), (3, 2));
+    let (producer, _consumer) = ring.split();
+    for frame in [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0], [7.0, 8.0, 9.0]] {
+      producer.push(&frame);
+    }
+    assert_eq!((words.load(WRITE), words.load(READ), words.load(OVERFLOWS)), (2, 0, 1));
+    // Frames follow the header back to back, slot `write % capacity`
+    let frames: Vec<f32> =
+      (HEADER_WORDS..HEADER_WORDS + 6).map(|index| f32::from_bits(words.load(index))).collect();
+    assert_eq!(frames, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+  }
+
+  #[test]
+  fn frames_cross_threads_in_order() {
+    let (producer, consumer) = ring(64, 3).split();
+    const FRAMES: u32 = 100_000;
+
+    let writer = thread::spawn(move || {
+      for count in 0..FRAMES {
+        let frame = [count as f32, count as f32 * 0.5, -(count as f32)];
+        while !producer.push(&frame) {
+          thread::yield_now();
+        }
+      }
+    });
+
+    let mut frame = [0.0; 3];
+    let mut expected = 0;
+    while expected < FRAMES {
+      if consumer.pop(&mut frame) {
+        assert_eq!(frame, [expected as f32, expected as f32 * 0.5, -(expected as f32)]);
+        expected += 1;
+      } else {
+        thread::yield_now();
+      }
+    }
+    writer.join().unwrap();
+    assert_eq!(consumer.ring().len(), 0);
+  }
+
+  #[test]
+  fn a_full_ring_drops_and_counts() {
+    let (producer, consumer) = ring(3, 1).split();
+    // Rounded up to four frames
+    for value in 0..6 {
+      producer.push(&[value as f32]);
+    }
+    assert_eq!(consumer.ring().overflows(), 2);
+
+    let mut frame = [0.0];
+    let mut values = Vec::new();
+    while consumer.pop(&mut frame) {
+      values.push(frame[0]);
+    }
+    assert_eq!(values, [0.0, 1.0, 2.0, 3.0]);
+    assert!(producer.push(&[6.0]));
+  }
+
+  #[test]
+  fn counts_survive_wrapping() {
+    let (producer, consumer) = ring(4, 1).split();
+    consumer.ring().words.store(WRITE, u32::MAX - 1);
+    consumer.ring().words.store(READ, u32::MAX - 1);
+    let mut frame = [0.0];
+    for value in 0..10 {
+      assert!(producer.push(&[value as f32]));
+      assert!(consumer.pop(&mut frame));
+      assert_eq!(frame[0], value as f32);
+    }
+  }
+
+  #[test]
+  fn interpolates_to_the_render_time() {
+    let (producer, consumer) = ring(16, 2).split();
+    let mut interpolator = Interpolator::new(2);
+    let mut out = [0.0; 2];
+    assert!(!interpolator.sample(&consumer, 0.0, &mut out, |_| {}));
+
+    for (time, value) in [(0.0, 0.0), (1.0, 10.0), (2.0, 20.0), (3.0, 40.0)] {
+      producer.push(&[time, value]);
+    }
+    let mut popped = 0;
+    assert!(interpolator.sample(&consumer, 1.5, &mut out, |_| popped += 1));
+    assert_eq!(out, [1.5, 15.0]);
+    assert_eq!(popped, 3);
+
+    assert!(interpolator.sample(&consumer, 2.75, &mut out, |_| {}));
+    assert_eq!(out, [2.75, 35.0]);
+    assert_eq!(consumer.ring().underruns(), 0);
+
+    // Nothing past 3.0 yet, the last frame is held
+    assert!(interpolator.sample(&consumer, 3.5, &mut out, |_| {}));
+    assert_eq!(out, [3.0, 40.0]);
+    assert_eq!(consumer.ring().underruns(), 1);
+
+    producer.push(&[4.0, 0.0]);
+    assert!(interpolator.sample(&consumer, 3.5, &mut out, |_| {}));
+    assert_eq!(out, [3.5, 20.0]);
+  }
+
+  #[test]
+  fn frames_ahead_of_the_render_time_are_kept() {
+    let (producer, consumer) = ring(8, 2).split();
+    let mut interpolator = Interpolator::new(2);
+    producer.push(&[5.0, 1.0]);
+    producer.push(&[6.0, 2.0]);
+    let mut out = [0.0; 2];
+    assert!(interpolator.sample(&consumer, 1.0, &mut out, |_| {}));
+    assert_eq!(out, [5.0, 1.0]);
+    assert_eq!(consumer.ring().len(), 1);
+    assert_eq!(consumer.ring().underruns(), 0);
+  }
+}
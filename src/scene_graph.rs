@@ -0,0 +1,288 @@
+use crate::error::DemoError;
+use nalgebra_glm::{self as glm, Mat4, Vec3};
+use std::collections::HashMap;
+
+pub type NodeId = usize;
+
+/// Local translation, rotation (euler angles in radians, applied X then Y then Z) and scale
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+  pub translation: Vec3,
+  pub rotation: Vec3,
+  pub scale: Vec3,
+}
+
+impl Transform {
+  pub fn identity() -> Self {
+    Transform {
+      translation: glm::vec3(0.0, 0.0, 0.0),
+      rotation: glm::vec3(0.0, 0.0, 0.0),
+      scale: glm::vec3(1.0, 1.0, 1.0),
+    }
+  }
+
+  /// `translation * rotation * scale`
+  pub fn matrix(&self) -> Mat4 {
+    let matrix = glm::translate(&glm::identity(), &self.translation);
+    let matrix = glm::rotate(&matrix, self.rotation.z, &glm::vec3(0.0, 0.0, 1.0));
+    let matrix = glm::rotate(&matrix, self.rotation.y, &glm::vec3(0.0, 1.0, 0.0));
+    let matrix = glm::rotate(&matrix, self.rotation.x, &glm::vec3(1.0, 0.0, 0.0));
+    glm::scale(&matrix, &self.scale)
+  }
+}
+
+/// One vertex attribute of a mesh, sourced from a named buffer
+pub struct MeshAttribute {
+  pub attrib_name: String,
+  pub buffer_name: String,
+  pub num_components: i32,
+  /// Bytes between consecutive elements, `0` for tightly packed
+  pub stride: i32,
+  /// Bytes from the start of the buffer to the first element
+  pub offset: i32,
+  /// `0` for per vertex data, `1` for per instance data
+  pub divisor: u32,
+}
+
+/// Geometry drawn with `drawArrays`, or `drawArraysInstanced` when it has instances. There is no
+/// index buffer, so vertices shared between primitives are repeated in the attribute buffers.
+pub struct Mesh {
+  pub attributes: Vec<MeshAttribute>,
+  /// Primitive type, e.g. `WebGl2RenderingContext::TRIANGLE_STRIP`
+  pub mode: u32,
+  pub vertex_count: i32,
+  /// Number of instances to draw, `0` for a regular non instanced draw
+  pub instance_count: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UniformValue {
+  Float(f32),
+  Vec2([f32; 2]),
+  Vec3([f32; 3]),
+  Vec4([f32; 4]),
+}
+
+impl UniformValue {
+  /// A float or vector uniform from one to four components
+  pub fn from_slice(values: &[f32]) -> Option<Self> {
+    match *values {
+      [x] => Some(UniformValue::Float(x)),
+      [x, y] => Some(UniformValue::Vec2([x, y])),
+      [x, y, z] => Some(UniformValue::Vec3([x, y, z])),
+      [x, y, z, w] => Some(UniformValue::Vec4([x, y, z, w])),
+      _ => None,
+    }
+  }
+
+  /// The same value with `amount` added to every component
+  pub fn offset(self, amount: f32) -> Self {
+    match self {
+      UniformValue::Float(x) => UniformValue::Float(x + amount),
+      UniformValue::Vec2(v) => UniformValue::Vec2(v.map(|x| x + amount)),
+      UniformValue::Vec3(v) => UniformValue::Vec3(v.map(|x| x + amount)),
+      UniformValue::Vec4(v) => UniformValue::Vec4(v.map(|x| x + amount)),
+    }
+  }
+}
+
+/// A program together with the uniform values it is drawn with
+pub struct Material {
+  pub program: String,
+  pub uniforms: HashMap<String, UniformValue>,
+}
+
+/// What a leaf node draws, both are keys into the `SceneGraph`'s meshes and materials
+pub struct Drawable {
+  pub mesh: String,
+  pub material: String,
+}
+
+pub struct Node {
+  pub name: String,
+  pub transform: Transform,
+  pub visible: bool,
+  pub children: Vec<NodeId>,
+  pub drawable: Option<Drawable>,
+}
+
+/// A draw call produced by traversing the graph
+pub struct DrawItem<'a> {
+  pub world_matrix: Mat4,
+  pub mesh_name: &'a str,
+  pub mesh: &'a Mesh,
+  pub material: &'a Material,
+}
+
+pub struct SceneGraph {
+  nodes: Vec<Node>,
+  pub meshes: HashMap<String, Mesh>,
+  pub materials: HashMap<String, Material>,
+}
+
+impl SceneGraph {
+  pub const ROOT: NodeId = 0;
+  /// Name of the root node, `find` resolves it before any node added under it
+  pub const ROOT_NAME: &'static str = "root";
+
+  pub fn new() -> Self {
+    let root = Node {
+      name: SceneGraph::ROOT_NAME.into(),
+      transform: Transform::identity(),
+      visible: true,
+      children: Vec::new(),
+      drawable: None,
+    };
+    SceneGraph { nodes: vec![root], meshes: HashMap::new(), materials: HashMap::new() }
+  }
+
+  /// Add a node under `parent`, `None` if there is no such parent
+  pub fn add_node(
+    &mut self,
+    parent: NodeId,
+    name: &str,
+    transform: Transform,
+    drawable: Option<Drawable>,
+  ) -> Option<NodeId> {
+    let id = self.nodes.len();
+    self.nodes.get_mut(parent)?.children.push(id);
+    self.nodes.push(Node {
+      name: name.into(),
+      transform,
+      visible: true,
+      children: Vec::new(),
+      drawable,
+    });
+    Some(id)
+  }
+
+  /// `None` for an id from another graph past the end of this one
+  pub fn node(&self, id: NodeId) -> Option<&Node> {
+    self.nodes.get(id)
+  }
+
+  pub fn node_mut(&mut self, id: NodeId) -> Option<&mut Node> {
+    self.nodes.get_mut(id)
+  }
+
+  pub fn find(&self, name: &str) -> Option<NodeId> {
+    self.nodes.iter().position(|node| node.name == name)
+  }
+
+  /// World matrix of every visible node, indexed by `NodeId`. Hidden nodes and their whole
+  /// subtree are `None`.
+  pub fn world_matrices(&self) -> Vec<Option<Mat4>> {
+    let mut world_matrices = vec![None; self.nodes.len()];
+    let mut stack = vec![(SceneGraph::ROOT, glm::identity())];
+    while let Some((id, parent_matrix)) = stack.pop() {
+      let node = &self.nodes[id];
+      if !node.visible {
+        continue;
+      }
+      let world_matrix: Mat4 = parent_matrix * node.transform.matrix();
+      world_matrices[id] = Some(world_matrix);
+      stack.extend(node.children.iter().map(|&child| (child, world_matrix)));
+    }
+    world_matrices
+  }
+
+  /// Draw calls for every visible leaf, in the order the nodes were added
+  pub fn draw_list(&self) -> Result<Vec<DrawItem<'_>>, DemoError> {
+    let world_matrices = self.world_matrices();
+    let mut draw_list = Vec::new();
+    for (node, world_matrix) in self.nodes.iter().zip(world_matrices) {
+      if let (Some(drawable), Some(world_matrix)) = (&node.drawable, world_matrix) {
+        let mesh = self.meshes.get(&drawable.mesh).ok_or_else(|| {
+          DemoError::UnknownReference(format!("mesh `{}` of node `{}`", drawable.mesh, node.name))
+        })?;
+        let material = self.materials.get(&drawable.material).ok_or_else(|| {
+          let what = format!("material `{}` of node `{}`", drawable.material, node.name);
+          DemoError::UnknownReference(what)
+        })?;
+        draw_list.push(DrawItem { world_matrix, mesh_name: &drawable.mesh, mesh, material });
+      }
+    }
+    Ok(draw_list)
+  }
+}
+
+impl Default for SceneGraph {
+  fn default() -> Self {
+    SceneGraph::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn transform(translation: Vec3, rotation: Vec3, scale: Vec3) -> Transform {
+    Transform { translation, rotation, scale }
+  }
+
+  fn assert_point(matrix: &Mat4, point: Vec3, expected: Vec3) {
+    let moved = matrix * glm::vec4(point.x, point.y, point.z, 1.0);
+    assert!(glm::distance(&moved.xyz(), &expected) < 1e-5, "{:?} != {:?}", moved.xyz(), expected);
+  }
+
+  #[test]
+  fn transforms_scale_then_rotate_then_translate() {
+    let quarter = std::f32::consts::FRAC_PI_2;
+    let matrix =
+      transform(glm::vec3(10.0, 0.0, 0.0), glm::vec3(0.0, 0.0, quarter), glm::vec3(2.0, 1.0, 1.0))
+        .matrix();
+    // Stretched along x, turned onto y, then moved
+    assert_point(&matrix, glm::vec3(1.0, 0.0, 0.0), glm::vec3(10.0, 2.0, 0.0));
+
+    // X is applied before Y: the y axis turns onto z, then z onto x
+    let matrix =
+      transform(glm::zero(), glm::vec3(quarter, quarter, 0.0), glm::vec3(1.0, 1.0, 1.0)).matrix();
+    assert_point(&matrix, glm::vec3(0.0, 1.0, 0.0), glm::vec3(1.0, 0.0, 0.0));
+  }
+
+  #[test]
+  fn children_are_placed_relative_to_their_parent() {
+    let mut graph = SceneGraph::new();
+    let parent_transform =
+      transform(glm::vec3(0.0, 5.0, 0.0), glm::zero(), glm::vec3(2.0, 2.0, 2.0));
+    let parent = graph.add_node(SceneGraph::ROOT, "parent", parent_transform, None).unwrap();
+    let child_transform =
+      transform(glm::vec3(1.0, 0.0, 0.0), glm::zero(), glm::vec3(1.0, 1.0, 1.0));
+    let child = graph.add_node(parent, "child", child_transform, None).unwrap();
+
+    let world_matrices = graph.world_matrices();
+    let child_matrix = world_matrices[child].expect("the child is visible");
+    assert_point(&child_matrix, glm::zero(), glm::vec3(2.0, 5.0, 0.0));
+    assert_eq!(graph.find("child"), Some(child));
+  }
+
+  #[test]
+  fn hidden_parents_hide_their_children() {
+    let mut graph = SceneGraph::new();
+    graph.meshes.insert(
+      "mesh".into(),
+      Mesh { attributes: Vec::new(), mode: 0, vertex_count: 3, instance_count: 0 },
+    );
+    graph
+      .materials
+      .insert("material".into(), Material { program: "plain".into(), uniforms: HashMap::new() });
+    let drawable = || Some(Drawable { mesh: "mesh".into(), material: "material".into() });
+    let parent = graph.add_node(SceneGraph::ROOT, "parent", Transform::identity(), None).unwrap();
+    let child = graph.add_node(parent, "child", Transform::identity(), drawable()).unwrap();
+    graph.add_node(SceneGraph::ROOT, "sibling", Transform::identity(), drawable()).unwrap();
+    assert_eq!(graph.draw_list().unwrap().len(), 2);
+
+    graph.node_mut(parent).unwrap().visible = false;
+    assert_eq!(graph.world_matrices()[child], None);
+    assert_eq!(graph.draw_list().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn unknown_ids_are_not_found() {
+    let mut graph = SceneGraph::new();
+    assert_eq!(graph.add_node(7, "orphan", Transform::identity(), None), None);
+    assert_eq!(graph.find("orphan"), None);
+    assert!(graph.node(7).is_none());
+    assert!(graph.node_mut(1).is_none());
+  }
+}
@@ -0,0 +1,272 @@
+use crate::{document, error::DemoError, render_target::RenderTarget, utils};
+use nalgebra_glm::Mat4;
+use std::cell::RefCell;
+use wasm_bindgen::{prelude::*, Clamped, JsCast};
+use web_sys::{
+  Blob, CanvasRenderingContext2d, HtmlCanvasElement, ImageData, WebGl2RenderingContext,
+};
+
+/// Tiles never get bigger than this, even where the GPU allows it, to keep each one cheap
+const MAX_TILE_SIZE: i32 = 4096;
+/// Larger captures would not fit in a 2D canvas to be encoded
+const MAX_CAPTURE_SIZE: u32 = 16384;
+/// The 2D canvas holds four bytes a pixel, this keeps it to 256 MiB
+const MAX_CAPTURE_PIXELS: u64 = 8192 * 8192;
+
+thread_local! {
+  /// Handed from `screenshot` to the render loop, which draws it after its next frame
+  static PENDING_SCREENSHOT: RefCell<Option<Screenshot>> = const { RefCell::new(None) };
+}
+
+pub struct Screenshot {
+  pub width: i32,
+  pub height: i32,
+  file_name: String,
+}
+
+/// A rectangle of the capture in pixels, with `y` counting up from the bottom like WebGL does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+  pub x: i32,
+  pub y: i32,
+  pub width: i32,
+  pub height: i32,
+}
+
+/// Split a `width` × `height` capture into tiles no larger than `max_tile` on either side, row by
+/// row from the bottom left. The last tile of a row or column takes whatever is left.
+pub fn tiles(width: i32, height: i32, max_tile: i32) -> Vec<Tile> {
+  let mut tiles = Vec::new();
+  for y in (0..height).step_by(max_tile as usize) {
+    for x in (0..width).step_by(max_tile as usize) {
+      tiles.push(Tile { x, y, width: max_tile.min(width - x), height: max_tile.min(height - y) });
+    }
+  }
+  tiles
+}
+
+/// Applied after the projection, stretches the part of the view covered by `tile` over the whole
+/// viewport, so drawing the full view into the tile's target renders just that part
+#[rustfmt::skip]
+pub fn tile_projection(tile: &Tile, width: i32, height: i32) -> Mat4 {
+  let (width, height) = (width as f32, height as f32);
+  let (x, y, w, h) = (tile.x as f32, tile.y as f32, tile.width as f32, tile.height as f32);
+  let scale_x = width / w;
+  let scale_y = height / h;
+  // Moves the centre of the tile, in clip space of the full view, to the origin
+  let offset_x = (width - 2.0 * x - w) / w;
+  let offset_y = (height - 2.0 * y - h) / h;
+  Mat4::new(
+    scale_x, 0.0, 0.0, offset_x,
+    0.0, scale_y, 0.0, offset_y,
+    0.0, 0.0, 1.0, 0.0,
+    0.0, 0.0, 0.0, 1.0,
+  )
+}
+
+/// Render the scene at `width` × `height` pixels, whatever the canvas size, and download it as
+/// a PNG named `file_name` or `democ.png`. Taken after the next frame is drawn.
+#[wasm_bindgen]
+pub fn screenshot(width: u32, height: u32, file_name: Option<String>) -> Result<(), JsValue> {
+  if width == 0 || height == 0 || width > MAX_CAPTURE_SIZE || height > MAX_CAPTURE_SIZE {
+    return Err(
+      format!("Expected a size from 1 to {} pixels, got {}x{}", MAX_CAPTURE_SIZE, width, height)
+        .into(),
+    );
+  }
+  if width as u64 * height as u64 > MAX_CAPTURE_PIXELS {
+    return Err(
+      format!("Expected at most {} pixels, got {}x{}", MAX_CAPTURE_PIXELS, width, height).into(),
+    );
+  }
+  let request = Screenshot {
+    width: width as i32,
+    height: height as i32,
+    file_name: file_name.unwrap_or_else(|| "democ.png".to_string()),
+  };
+  PENDING_SCREENSHOT.with(|pending| *pending.borrow_mut() = Some(request));
+  Ok(())
+}
+
+pub(crate) fn take_pending_screenshot() -> Option<Screenshot> {
+  PENDING_SCREENSHOT.with(|pending| pending.borrow_mut().take())
+}
+
+impl Screenshot {
+  /// Render every tile through `draw`, which gets the tile's projection and draws into whatever
+  /// framebuffer is bound, then copy the tiles into a 2D canvas and start the download
+  pub(crate) fn capture(
+    &self,
+    gl_context: &WebGl2RenderingContext,
+    mut draw: impl FnMut(Mat4) -> Result<(), JsValue>,
+  ) -> Result<(), JsValue> {
+    let limit = |parameter| -> Result<i32, JsValue> {
+      gl_context
+        .get_parameter(parameter)?
+        .as_f64()
+        .map(|size| size as i32)
+        .ok_or_else(|| JsValue::from(format!("Failed to read the limit `{:#x}`", parameter)))
+    };
+    let max_tile = MAX_TILE_SIZE
+      .min(limit(WebGl2RenderingContext::MAX_TEXTURE_SIZE)?)
+      .min(limit(WebGl2RenderingContext::MAX_RENDERBUFFER_SIZE)?);
+
+    let document = document()?;
+    let canvas = document.create_element("canvas")?.dyn_into::<HtmlCanvasElement>()?;
+    canvas.set_width(self.width as u32);
+    canvas.set_height(self.height as u32);
+    let context = canvas
+      .get_context("2d")?
+      .ok_or_else(|| DemoError::ObjectCreation("a 2D context".to_string()))?
+      .dyn_into::<CanvasRenderingContext2d>()?;
+
+    let mut target = RenderTarget::new(gl_context)?;
+    let result = self.render_tiles(gl_context, &mut target, max_tile, &context, &mut draw);
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+    target.delete(gl_context);
+    result?;
+    self.save(&canvas)
+  }
+
+  /// Draw the tiles one at a time into `context`, so only a tile's pixels are ever held at once
+  fn render_tiles(
+    &self,
+    gl_context: &WebGl2RenderingContext,
+    target: &mut RenderTarget,
+    max_tile: i32,
+    context: &CanvasRenderingContext2d,
+    draw: &mut impl FnMut(Mat4) -> Result<(), JsValue>,
+  ) -> Result<(), JsValue> {
+    let mut tile_pixels = Vec::new();
+    for tile in tiles(self.width, self.height, max_tile) {
+      target.resize(gl_context, tile.width, tile.height)?;
+      target.bind(gl_context);
+      draw(tile_projection(&tile, self.width, self.height))?;
+
+      tile_pixels.resize(tile.width as usize * tile.height as usize * 4, 0);
+      gl_context.read_pixels_with_opt_u8_array(
+        0,
+        0,
+        tile.width,
+        tile.height,
+        WebGl2RenderingContext::RGBA,
+        WebGl2RenderingContext::UNSIGNED_BYTE,
+        Some(&mut tile_pixels),
+      )?;
+
+      // WebGL rows start at the bottom, image rows at the top
+      flip_rows(&mut tile_pixels, tile.width as usize * 4);
+      let image = ImageData::new_with_u8_clamped_array_and_sh(
+        Clamped(&tile_pixels),
+        tile.width as u32,
+        tile.height as u32,
+      )?;
+      let top = self.height - tile.y - tile.height;
+      context.put_image_data(&image, tile.x as f64, top as f64)?;
+    }
+    Ok(())
+  }
+
+  /// Encode the canvas as PNG and download the result
+  fn save(&self, canvas: &HtmlCanvasElement) -> Result<(), JsValue> {
+    let file_name = self.file_name.clone();
+    let callback = Closure::once(move |blob: Option<Blob>| {
+      let result = match blob {
+        Some(blob) => utils::download(&blob, &file_name),
+        None => Err(DemoError::ObjectCreation("the screenshot's PNG".to_string()).into()),
+      };
+      if let Err(err) = result {
+        web_sys::console::error_1(&err);
+      }
+    });
+    canvas.to_blob(callback.as_ref().unchecked_ref())?;
+    callback.forget();
+    Ok(())
+  }
+}
+
+/// Turn rows of `row_bytes` upside down in place
+fn flip_rows(pixels: &mut [u8], row_bytes: usize) {
+  let rows = pixels.len() / row_bytes;
+  for row in 0..rows / 2 {
+    let (top, bottom) = pixels.split_at_mut((rows - 1 - row) * row_bytes);
+    top[row * row_bytes..(row + 1) * row_bytes].swap_with_slice(&mut bottom[..row_bytes]);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::assert_close;
+  use nalgebra_glm as glm;
+
+  /// Where a point given in normalized device coordinates of the full view lands in the tile
+  fn project(tile: &Tile, width: i32, height: i32, x: f32, y: f32) -> (f32, f32) {
+    let point = tile_projection(tile, width, height) * glm::vec4(x, y, 0.5, 1.0);
+    (point.x / point.w, point.y / point.w)
+  }
+
+  fn assert_lands_on(actual: (f32, f32), expected: (f32, f32)) {
+    assert_close(actual.0, expected.0, 1e-5);
+    assert_close(actual.1, expected.1, 1e-5);
+  }
+
+  #[test]
+  fn small_captures_are_one_tile() {
+    assert_eq!(tiles(500, 300, 4096), vec![Tile { x: 0, y: 0, width: 500, height: 300 }]);
+  }
+
+  #[test]
+  fn tiles_cover_the_capture_exactly_once() {
+    let (width, height) = (3840, 2160);
+    let tiles = tiles(width, height, 1000);
+    assert_eq!(tiles.len(), 4 * 3);
+    assert_eq!(tiles.last(), Some(&Tile { x: 3000, y: 2000, width: 840, height: 160 }));
+
+    let mut covered = vec![0u8; (width * height) as usize];
+    for tile in &tiles {
+      assert!(tile.width <= 1000 && tile.height <= 1000);
+      for y in tile.y..tile.y + tile.height {
+        for x in tile.x..tile.x + tile.width {
+          covered[(y * width + x) as usize] += 1;
+        }
+      }
+    }
+    assert!(covered.iter().all(|&count| count == 1));
+  }
+
+  #[test]
+  fn a_single_tile_keeps_the_projection() {
+    let tile = Tile { x: 0, y: 0, width: 640, height: 480 };
+    assert_eq!(tile_projection(&tile, 640, 480), Mat4::identity());
+  }
+
+  #[test]
+  fn tile_corners_map_to_the_viewport_corners() {
+    let (width, height) = (4000, 3000);
+    for tile in tiles(width, height, 1500) {
+      // The tile's corners in normalized device coordinates of the full view
+      let left = 2.0 * tile.x as f32 / width as f32 - 1.0;
+      let right = 2.0 * (tile.x + tile.width) as f32 / width as f32 - 1.0;
+      let bottom = 2.0 * tile.y as f32 / height as f32 - 1.0;
+      let top = 2.0 * (tile.y + tile.height) as f32 / height as f32 - 1.0;
+      assert_lands_on(project(&tile, width, height, left, bottom), (-1.0, -1.0));
+      assert_lands_on(project(&tile, width, height, right, top), (1.0, 1.0));
+    }
+  }
+
+  #[test]
+  fn rows_are_flipped_in_place() {
+    let mut pixels: Vec<u8> = (0..5).flat_map(|row| [row; 8]).collect();
+    flip_rows(&mut pixels, 8);
+    let expected: Vec<u8> = (0..5).rev().flat_map(|row| [row; 8]).collect();
+    assert_eq!(pixels, expected);
+  }
+
+  #[test]
+  fn depth_is_untouched() {
+    let tile = Tile { x: 100, y: 50, width: 100, height: 50 };
+    let point = tile_projection(&tile, 400, 200) * glm::vec4(0.3, -0.2, 0.7, 2.0);
+    assert_eq!((point.z, point.w), (0.7, 2.0));
+  }
+}
@@ -1,144 +1,551 @@
 use super::*;
 use crate::{
-  buffer_attrib, buffer_attrib::BufferAttrib, buffers, program_info::ProgramInfo, utils::*,
-};
-use nalgebra_glm;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
-use wasm_bindgen::{prelude::*, JsCast};
-use web_sys::{
-  console, AudioContext, EventTarget, HtmlCanvasElement, HtmlMediaElement, WebGl2RenderingContext,
-  WebGlBuffer,
+  beat::BeatDetector,
+  camera::{self, Camera, CameraRig},
+  context_loss::{self, ContextStatus},
+  fm_osc::FmOsc,
+  instancing::{self, BarField},
+  midi::SharedMidi,
+  modulation::{Destination, FmParam, ModulationInputs},
+  offline::{self, OfflineRender},
+  particles::ParticleSystem,
+  presentation::{self, PresentationState},
+  preset::{self, BufferSource, Preset, PresetScene},
+  quality::{self, QualityGovernor},
+  render_target::{self, CanvasTarget},
+  scene_graph::UniformValue,
+  screenshot,
+  stats::Profiler,
+  transitions::{Transition, TransitionRenderer},
+  utils::*,
 };
+use web_sys::{HtmlAudioElement, WebGlUniformLocation};
+
+const PARTICLE_COUNT: usize = 20000;
+/// Longest step a frame advances the simulation by, after a stall or a hidden tab everything
+/// carries on instead of jumping ahead
+const MAX_DT: f32 = 0.1;
+
+/// GPU objects created once and shared by every frame
+pub struct RenderResources {
+  pub particles: ParticleSystem,
+  pub transitions: TransitionRenderer,
+  pub canvas_target: CanvasTarget,
+}
 
+impl RenderResources {
+  pub fn new(gl_context: &WebGl2RenderingContext) -> Result<Self, JsValue> {
+    Ok(RenderResources {
+      particles: ParticleSystem::new(gl_context, PARTICLE_COUNT)?,
+      transitions: TransitionRenderer::new(gl_context)?,
+      canvas_target: CanvasTarget::new(gl_context)?,
+    })
+  }
+}
+
+/// Draw the visible nodes of `preset_scene` into the bound framebuffer and viewport, clearing to
+/// `clear_color` first if there is one
 pub fn draw_scene(
   gl_context: &WebGl2RenderingContext,
-  program_info: ProgramInfo,
-  buffers: HashMap<String, WebGlBuffer>,
+  particles: &ParticleSystem,
+  preset_scene: &mut PresetScene,
   time: f32,
+  clear_color: Option<[f32; 3]>,
+  camera: &mut Camera,
 ) -> Result<(), JsValue> {
-  gl_context.clear_color(1.0, 0.5, 0.5, 1.0);
   // gl_context.clear_depth(0.0);
   gl_context.enable(WebGl2RenderingContext::DEPTH_TEST);
   gl_context.depth_func(WebGl2RenderingContext::LEQUAL); // Near objects obscure far ones
 
-  // Clear the canvas before drawing to it
-  gl_context
-    .clear(WebGl2RenderingContext::COLOR_BUFFER_BIT | WebGl2RenderingContext::DEPTH_BUFFER_BIT);
+  // Clear the canvas before drawing to it, later passes keep what the earlier ones drew
+  if let Some([r, g, b]) = clear_color {
+    gl_context.clear_color(r, g, b, 1.0);
+    gl_context
+      .clear(WebGl2RenderingContext::COLOR_BUFFER_BIT | WebGl2RenderingContext::DEPTH_BUFFER_BIT);
+  } else {
+    gl_context.clear(WebGl2RenderingContext::DEPTH_BUFFER_BIT);
+  }
 
-  // Projection and model view matrices
-  let projection_matrix = create_perspective_matrix(&gl_context)?;
-  let model_view_matrix = create_model_view_matrix(time);
-
-  // Tell WebGl to pull out the positions from the vertices buffer into the `a_vertex_position` attribute
-  let a_vertex_position = (*program_info
-    .attrib_locations
-    .get(&"a_vertex_position".to_string())
-    .ok_or("Failed to get `a_vertex_position` attribute")?) as u32;
-
-  let a_vertex_position_buffer_attrib = BufferAttrib {
-    name: "vertices".into(),
-    buffer: buffers
-      .get(&"vertices".to_string())
-      .ok_or("Failed to get `a_vertex_position` attribute")?,
-    target: WebGl2RenderingContext::ARRAY_BUFFER,
-    num_components: 2,
-    buffer_type: WebGl2RenderingContext::FLOAT,
-    normalize: false,
-    stride: 0,
-    offset: 0,
-  };
-  buffer_attrib::bind_buffer_to_attrib(
-    &gl_context,
-    &a_vertex_position_buffer_attrib,
-    a_vertex_position,
-  )?;
-
-  let a_vertex_color = (*program_info
-    .attrib_locations
-    .get(&"a_vertex_color".to_string())
-    .ok_or("Failed to get `a_vertex_color` attribute")?) as u32;
-  let a_vertex_color_buffer_attrib = BufferAttrib {
-    name: "colors".into(),
-    buffer: buffers.get(&"colors".to_string()).ok_or("Failed to get `a_vertex_color` attribute")?,
-    target: WebGl2RenderingContext::ARRAY_BUFFER,
-    num_components: 4,
-    buffer_type: WebGl2RenderingContext::FLOAT,
-    normalize: false,
-    stride: 0,
-    offset: 0,
-  };
-  buffer_attrib::bind_buffer_to_attrib(&gl_context, &a_vertex_color_buffer_attrib, a_vertex_color)?;
-
-  // Tell WebGl to use our program when drawing
-  gl_context.use_program(Some(&program_info.program));
-
-  let projection_matrix = &projection_matrix[0..];
-  gl_context.uniform_matrix4fv_with_f32_array(
-    program_info.uniform_locations.get(&"u_projection_matrix".to_string()).unwrap().as_ref(),
-    false,
-    projection_matrix,
-  );
-
-  let model_view_matrix = &model_view_matrix[0..];
-  gl_context.uniform_matrix4fv_with_f32_array(
-    program_info.uniform_locations.get(&"u_model_view_matrix".to_string()).unwrap().as_ref(),
-    false,
-    model_view_matrix,
-  );
+  // The projection and view are shared by every node
+  let projection_matrix = mat4_to_f32_16(camera.projection_matrix());
+  let view_matrix = camera.view_matrix();
 
-  gl_context
-    .uniform1f(program_info.uniform_locations.get(&"u_time".to_string()).unwrap().as_ref(), time);
+  let PresetScene { scene_graph, programs, buffers, vertex_arrays, .. } = preset_scene;
+  let mut current_program = None;
+  for draw_item in scene_graph.draw_list()? {
+    let program_info = programs.get(&draw_item.material.program).ok_or_else(|| {
+      DemoError::UnknownReference(format!("program `{}`", draw_item.material.program))
+    })?;
+
+    // The vertex array remembers which buffer feeds each of the mesh's attributes
+    vertex_arrays.bind(
+      gl_context,
+      draw_item.mesh_name,
+      draw_item.mesh,
+      &draw_item.material.program,
+      program_info,
+      buffers,
+    )?;
+
+    // Tell WebGl to use our program when drawing, consecutive nodes often share one
+    if current_program != Some(&draw_item.material.program) {
+      gl_context.use_program(Some(&program_info.program));
+      current_program = Some(&draw_item.material.program);
+    }
+
+    let uniform_location =
+      |name: &str| program_info.uniform_locations.get(name).and_then(|location| location.as_ref());
+
+    gl_context.uniform_matrix4fv_with_f32_array(
+      uniform_location("u_projection_matrix"),
+      false,
+      &projection_matrix[0..],
+    );
+
+    let model_view_matrix = mat4_to_f32_16(view_matrix * draw_item.world_matrix);
+    gl_context.uniform_matrix4fv_with_f32_array(
+      uniform_location("u_model_view_matrix"),
+      false,
+      &model_view_matrix[0..],
+    );
 
-  let vertex_count = 4;
-  let offset = 0; // How many bytes inside the buffer to start from
-  gl_context.draw_arrays(WebGl2RenderingContext::TRIANGLE_STRIP, offset, vertex_count);
+    gl_context.uniform1f(uniform_location("u_time"), time);
+
+    for (name, value) in &draw_item.material.uniforms {
+      set_uniform(gl_context, uniform_location(name), *value);
+    }
+
+    let offset = 0; // How many bytes inside the buffer to start from
+    let mesh = draw_item.mesh;
+    if mesh.instance_count > 0 {
+      gl_context.draw_arrays_instanced(mesh.mode, offset, mesh.vertex_count, mesh.instance_count);
+    } else {
+      gl_context.draw_arrays(mesh.mode, offset, mesh.vertex_count);
+    }
+  }
+  gl_context.bind_vertex_array(None);
+
+  // Particles live in their own buffers, the node only places the emitter
+  if let Some(emitter) = scene_graph.find(preset::PARTICLES_NODE) {
+    if let Some(world_matrix) = scene_graph.world_matrices()[emitter] {
+      let model_view_matrix = mat4_to_f32_16(view_matrix * world_matrix);
+      particles.draw(gl_context, &model_view_matrix, &projection_matrix);
+    }
+  }
 
   Ok(())
 }
 
-fn create_perspective_matrix(gl_context: &WebGl2RenderingContext) -> Result<[f32; 16], JsValue> {
-  // Create a perspective matrix, a special matrix that is used to simulate the distortion of perspective in a camera.
-  // Our field of view is 45 degrees, which a width/height ratio that matches the display size of the canvas and we
-  // only want to see objects between 0.1 and 100.0 units away from the camera
-  let field_of_view = 45.0 * std::f32::consts::PI / 180.0;
-  let canvas: HtmlCanvasElement = gl_context
-    .canvas()
-    .ok_or("Failed to get canvas on draw")?
-    .dyn_into::<web_sys::HtmlCanvasElement>()?;
-  let aspect = (canvas.client_width() / canvas.client_height()) as f32;
-  let z_near = 0.1;
-  let z_far = 100.0;
-  let projection_matrix = nalgebra_glm::perspective(aspect, field_of_view, z_near, z_far);
-  Ok(mat4_to_f32_16(projection_matrix))
+/// Draw every pass of `scene` into whatever framebuffer is bound, leaving out the optional ones
+/// unless `optional_passes` is set
+fn draw_passes(
+  gl_context: &WebGl2RenderingContext,
+  particles: &ParticleSystem,
+  preset_scene: &mut PresetScene,
+  scene: usize,
+  time: f32,
+  camera: &mut Camera,
+  optional_passes: bool,
+) -> Result<(), JsValue> {
+  for pass in 0..preset_scene.scenes[scene].passes.len() {
+    if preset_scene.scenes[scene].passes[pass].optional && !optional_passes {
+      continue;
+    }
+    preset_scene.show_pass(scene, pass);
+    let clear_color = preset_scene.scenes[scene].passes[pass].clear_color;
+    draw_scene(gl_context, particles, preset_scene, time, clear_color, camera)?;
+  }
+  Ok(())
+}
+
+fn set_uniform(
+  gl_context: &WebGl2RenderingContext,
+  location: Option<&WebGlUniformLocation>,
+  value: UniformValue,
+) {
+  match value {
+    UniformValue::Float(x) => gl_context.uniform1f(location, x),
+    UniformValue::Vec2([x, y]) => gl_context.uniform2f(location, x, y),
+    UniformValue::Vec3([x, y, z]) => gl_context.uniform3f(location, x, y, z),
+    UniformValue::Vec4([x, y, z, w]) => gl_context.uniform4f(location, x, y, z, w),
+  }
 }
 
-/// Rotate the square
-fn create_model_view_matrix(angle: f32) -> [f32; 16] {
-  let model_view_matrix = nalgebra_glm::identity();
-  let translation_vector = nalgebra_glm::vec3(0.0, 0.0, -6.0);
-  let translated_matrix = nalgebra_glm::translate(&model_view_matrix, &translation_vector);
-  let rotation_vector = nalgebra_glm::vec3(0.0, 0.0, 1.0);
-  let rotated_matrix = nalgebra_glm::rotate(&translated_matrix, angle, &rotation_vector);
-  mat4_to_f32_16(rotated_matrix)
+/// Blank the canvas, used to black out the projector between scenes
+fn clear_to_black(gl_context: &WebGl2RenderingContext) {
+  gl_context.clear_color(0.0, 0.0, 0.0, 1.0);
+  gl_context
+    .clear(WebGl2RenderingContext::COLOR_BUFFER_BIT | WebGl2RenderingContext::DEPTH_BUFFER_BIT);
 }
 
-pub(crate) fn do_webgl(gl_context: WebGl2RenderingContext) -> Result<(), JsValue> {
+pub(crate) fn do_webgl(
+  gl_context: WebGl2RenderingContext,
+  presentation: Rc<RefCell<PresentationState>>,
+  audio_frame: SharedAudio,
+  midi: SharedMidi,
+  track: Option<HtmlAudioElement>,
+  preset: Preset,
+) -> Result<(), JsValue> {
   /* WebGl */
 
-  let program_info = ProgramInfo::new(&gl_context)?;
+  let mut preset_scene = PresetScene::instantiate(&gl_context, &preset)?;
+  let mut bar_field = BarField::new();
+  let mut resources = RenderResources::new(&gl_context)?;
+
+  let canvas: HtmlCanvasElement = gl_context
+    .canvas()
+    .ok_or_else(|| DemoError::MissingElement("the canvas".to_string()))?
+    .dyn_into::<web_sys::HtmlCanvasElement>()?;
+  let camera_rig = Rc::new(RefCell::new(CameraRig::new(preset.camera())));
+  camera::init_controls(&canvas, camera_rig.clone())?;
+  let context_status = context_loss::init(&canvas)?;
+  // Everything on the GPU is rebuilt from this after the context comes back
+  let mut current_preset = preset;
+  // Seeded by the first frame, the page may have been open for a while before it
+  let mut last_time: Option<f32> = None;
+
+  // Sources of the modulation matrix that are not measured by the audio loop
+  let mut beat = BeatDetector::new();
+  // Created the first time a route drives it, so presets without one stay silent
+  let mut fm_osc: Option<FmOsc> = None;
 
-  let buffers = buffers::make_buffers(&gl_context)?;
+  // The scene drawn last frame, a change starts a transition away from it
+  let mut shown_scene = 0;
+  let mut transition: Option<Transition> = None;
+  // The transition a timeline cue asked for, used instead of the preset's default
+  let mut cue_transition = None;
+  // The previous preset while a transition fades it out
+  let mut outgoing_preset: Option<PresetScene> = None;
+  // While exporting, frames step through the track at a fixed rate instead of following the clock
+  let mut export: Option<OfflineRender> = None;
+  let mut profiler = Profiler::new(&gl_context)?;
+  let mut governor = QualityGovernor::new();
 
   // Draw scene every 0.01 seconds
   let ref_count = Rc::new(RefCell::new(None));
   let ref_count_clone = ref_count.clone();
 
   *ref_count_clone.borrow_mut() = Some(Closure::wrap(Box::new(move |t| {
-    draw_scene(&gl_context.clone(), program_info.clone(), buffers.clone(), t * 0.001f32).unwrap();
-    request_animation_frame(ref_count.borrow().as_ref().unwrap());
-  }) as Box<dyn FnMut(f32)>));
+    let now = t * 0.001f32;
 
-  request_animation_frame(ref_count_clone.borrow().as_ref().unwrap());
+    // Keep the loop ticking while the context is gone, but leave everything where it was
+    match context_status.get() {
+      ContextStatus::Live => {}
+      ContextStatus::Lost => {
+        last_time = Some(now);
+        profiler.report(request_animation_frame(&ref_count));
+        return;
+      }
+      ContextStatus::Restored => {
+        // The old objects died with the context, there is nothing left to delete
+        let restored = PresetScene::instantiate(&gl_context, &current_preset)
+          .and_then(|restored| Ok((restored, RenderResources::new(&gl_context)?)));
+        match restored {
+          Ok((restored, restored_resources)) => {
+            preset_scene = restored;
+            resources = restored_resources;
+            outgoing_preset = None;
+            transition = None;
+            profiler.restart(&gl_context);
+            context_status.set(ContextStatus::Live);
+          }
+          // Tried again next frame, the context may be lost again already
+          Err(err) => {
+            profiler.report(Err(err));
+            last_time = Some(now);
+            profiler.report(request_animation_frame(&ref_count));
+            return;
+          }
+        }
+      }
+    }
+    profiler.begin_frame(&gl_context, now);
 
-  Ok(())
+    // Swap in a preset handed over by `load_preset`, the old one keeps playing if it fails
+    if let Some(preset) = preset::take_pending_preset() {
+      match PresetScene::instantiate(&gl_context, &preset) {
+        Ok(loaded) => {
+          let mut previous = std::mem::replace(&mut preset_scene, loaded);
+          transition = preset_scene
+            .transition
+            .and_then(|desc| Transition::start(&desc, shown_scene, preset_scene.tempo));
+          if let Some(mut outgoing) = outgoing_preset.take() {
+            outgoing.delete(&gl_context);
+          }
+          if transition.is_some() {
+            outgoing_preset = Some(previous);
+          } else {
+            previous.delete(&gl_context);
+          }
+          *camera_rig.borrow_mut() = CameraRig::new(preset.camera());
+          let mut presentation = presentation.borrow_mut();
+          presentation.set_scenes(preset.scene_names());
+          if let Err(err) = presentation::announce_scenes(&presentation) {
+            web_sys::console::error_1(&err);
+          }
+          shown_scene = 0;
+          current_preset = preset;
+        }
+        Err(err) => web_sys::console::error_1(&err),
+      }
+    }
+
+    if let Some(render) = offline::take_pending_export() {
+      export = Some(render);
+    }
+
+    let (time, dt) = match &export {
+      Some(render) => (render.time(), render.dt()),
+      None => (now, last_time.map_or(0.0, |last_time| (now - last_time).clamp(0.0, MAX_DT))),
+    };
+    let mut camera_rig = camera_rig.borrow_mut();
+    camera_rig.update(dt);
+    last_time = Some(now);
+
+    // With a timeline everything authored follows the music, otherwise the time since loading.
+    // Until the track first plays there is no music to follow, so the visuals keep moving on the
+    // time since loading while the timeline holds its first cue.
+    let waiting_for_track = export.is_none()
+      && track.as_ref().is_some_and(|track| track.paused() && track.current_time() == 0.0);
+    let clock = match (&mut preset_scene.sequencer, &track) {
+      (Some(sequencer), Some(track)) => {
+        // Exports run straight through the track, past the end of the loop
+        let position = match &export {
+          Some(render) => render.time(),
+          None => {
+            let position = track.current_time() as f32;
+            match sequencer.loop_restart(position) {
+              Some(start) => {
+                track.set_current_time(start as f64);
+                start
+              }
+              None => position,
+            }
+          }
+        };
+        if let Some((scene, transition)) = sequencer.update(position) {
+          presentation.borrow_mut().scene = scene;
+          cue_transition = transition;
+        }
+        if waiting_for_track {
+          time
+        } else {
+          position
+        }
+      }
+      _ => time,
+    };
+
+    // A MIDI program change picks the scene, wrapping around when there are fewer scenes
+    if let Some(program) = midi.borrow_mut().program_change.take() {
+      presentation.borrow_mut().scene = program as usize % preset_scene.scenes.len();
+    }
+
+    let exporting = export.is_some();
+    // Exports measure the decoded track at the frame's time instead of listening live
+    let live_audio = audio_frame.borrow();
+    let audio = match &mut export {
+      Some(render) => {
+        render.analyse();
+        &render.audio_frame
+      }
+      None => &*live_audio,
+    };
+    // Fed every frame, whatever is shown, so its history is current when particles come on
+    let is_beat = beat.update(audio.bands.first().copied().unwrap_or(0.0), dt);
+    preset_scene.animate(clock);
+    let lfos = preset_scene.sample_lfos(clock);
+    let automation = preset_scene.sample_automation(clock);
+    let inputs = ModulationInputs {
+      bands: &audio.bands,
+      rms: audio.rms,
+      stereo: &audio.stereo,
+      quantum: audio.quantum,
+      harmony: &audio.harmony,
+      descriptors: audio.descriptors,
+      beat_phase: beat.phase(),
+      lfos: &lfos,
+      automation: &automation,
+      midi_cc: &midi.borrow().controls,
+      time: clock,
+    };
+    // Only a resize changes the aspect, so the rig keeps its projection cached in between and the
+    // copy modulation moves around starts out with it
+    camera_rig.camera.set_aspect(canvas.client_width() as f32 / canvas.client_height() as f32);
+    camera_rig.camera.projection_matrix();
+    let mut camera = camera_rig.camera.clone();
+    for (destination, value) in preset_scene.modulate(&inputs, dt) {
+      match destination {
+        Destination::Camera(param) => param.apply(&mut camera, value),
+        Destination::FmOsc(param) => {
+          if fm_osc.is_none() {
+            match FmOsc::new() {
+              Ok(osc) => fm_osc = Some(osc),
+              Err(err) => web_sys::console::error_1(&err),
+            }
+          }
+          if let Some(osc) = &mut fm_osc {
+            match param {
+              FmParam::FrequencyRatio => osc.set_fm_frequency(value),
+              FmParam::Amount => osc.set_fm_amount(value),
+              FmParam::Gain => osc.set_gain(value),
+              FmParam::Note => osc.set_note(value.round().clamp(0.0, 127.0) as u8),
+            }
+          }
+        }
+        Destination::Uniform { .. } | Destination::Node { .. } => {}
+      }
+    }
+
+    let (scene, blackout) = {
+      let presentation = presentation.borrow();
+      (presentation.scene % preset_scene.scenes.len(), presentation.blackout)
+    };
+
+    if scene != shown_scene {
+      // Starting over from whatever is on screen now, a preset still fading out is cut off
+      if let Some(mut outgoing) = outgoing_preset.take() {
+        outgoing.delete(&gl_context);
+      }
+      transition = cue_transition
+        .take()
+        .or(preset_scene.transition)
+        .and_then(|desc| Transition::start(&desc, shown_scene, preset_scene.tempo));
+      shown_scene = scene;
+    } else {
+      // A cue to the scene already shown has nothing to transition, it must not linger until a
+      // later, unrelated scene change picks it up
+      cue_transition = None;
+    }
+
+    // Exports are not in a hurry and always get the best quality
+    let settings = quality::settings();
+    let quality = if settings.adaptive && !exporting {
+      let busy_ms = profiler.busy_ms();
+      if governor.update(settings.target_fps, dt * 1000.0, busy_ms) {
+        profiler.set_quality_level(governor.level());
+      }
+      governor.quality()
+    } else {
+      quality::LEVELS[0]
+    };
+    resources.particles.set_active_fraction(quality.particles);
+
+    if preset_scene.scene_shows(scene, preset::PARTICLES_NODE) {
+      let binding = &preset_scene.particles;
+      resources.particles.update(&gl_context, time, dt, &audio.bands, is_beat, binding);
+    }
+
+    bar_field.update(&audio.bands);
+    for (name, source) in &preset_scene.source_buffers {
+      let data = match source {
+        BufferSource::BarField => bar_field.data(),
+        BufferSource::Goniometer => &audio.stereo.scope,
+      };
+      if let Some(buffer) = preset_scene.buffers.get(name) {
+        instancing::upload_instances(&gl_context, buffer, data);
+      }
+    }
+
+    // A transition without offscreen targets to draw into turns into a cut
+    let (width, height) = (canvas.width() as i32, canvas.height() as i32);
+    if transition.is_some() {
+      let (scaled_width, scaled_height) =
+        render_target::scaled_size(width, height, quality.resolution_scale);
+      if let Err(err) = resources.transitions.resize(&gl_context, scaled_width, scaled_height) {
+        profiler.report(Err(err));
+        transition = None;
+      }
+    }
+
+    if blackout {
+      clear_to_black(&gl_context);
+    } else if let Some(running) = &mut transition {
+      // Both scenes go offscreen first, then get blended onto the canvas
+      resources.transitions.from.bind(&gl_context);
+      let outgoing = match &mut outgoing_preset {
+        Some(outgoing) => {
+          outgoing.animate(clock);
+          outgoing
+        }
+        None => &mut preset_scene,
+      };
+      let from_scene = running.from_scene % outgoing.scenes.len();
+      profiler.report(draw_passes(
+        &gl_context,
+        &resources.particles,
+        outgoing,
+        from_scene,
+        time,
+        &mut camera,
+        quality.optional_passes,
+      ));
+      resources.transitions.to.bind(&gl_context);
+      profiler.report(draw_passes(
+        &gl_context,
+        &resources.particles,
+        &mut preset_scene,
+        scene,
+        time,
+        &mut camera,
+        quality.optional_passes,
+      ));
+      resources.transitions.draw(&gl_context, running, width, height);
+
+      running.advance(dt);
+      if running.finished() {
+        transition = None;
+        if let Some(mut outgoing) = outgoing_preset.take() {
+          outgoing.delete(&gl_context);
+        }
+      }
+    } else {
+      let target = &mut resources.canvas_target;
+      let (scale, samples) = (quality.resolution_scale, quality.msaa_samples);
+      if let Err(err) = target.bind(&gl_context, width, height, scale, samples) {
+        // Still draw something, straight onto the canvas
+        profiler.report(Err(err));
+        let _ = target.bind(&gl_context, width, height, 1.0, 0);
+      }
+      profiler.report(draw_passes(
+        &gl_context,
+        &resources.particles,
+        &mut preset_scene,
+        scene,
+        time,
+        &mut camera,
+        quality.optional_passes,
+      ));
+      resources.canvas_target.present(&gl_context, width, height);
+    }
+
+    // Screenshots render the incoming scene again at their own size
+    if let Some(request) = screenshot::take_pending_screenshot() {
+      let mut camera = camera.clone();
+      camera.set_aspect(request.width as f32 / request.height as f32);
+      let particles = &resources.particles;
+      let result = request.capture(&gl_context, |tile| {
+        camera.set_tile(tile);
+        draw_passes(&gl_context, particles, &mut preset_scene, scene, time, &mut camera, true)
+      });
+      if let Err(err) = result {
+        web_sys::console::error_1(&err);
+      }
+    }
+
+    if let Some(render) = &mut export {
+      match render.capture(&canvas) {
+        Ok(false) => {}
+        Ok(true) => export = None,
+        Err(err) => {
+          web_sys::console::error_1(&err);
+          export = None;
+        }
+      }
+    }
+    profiler.end_frame(&gl_context, dt);
+    profiler.report(request_animation_frame(&ref_count));
+  }) as Box<dyn FnMut(f32)>));
+
+  request_animation_frame(&ref_count_clone)
 }
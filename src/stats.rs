@@ -0,0 +1,316 @@
+use crate::{document, error::DemoError, window};
+use std::{cell::RefCell, collections::VecDeque};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{HtmlElement, WebGl2RenderingContext, WebGlQuery};
+
+/// From `EXT_disjoint_timer_query_webgl2`, which web-sys has no constants for
+const TIME_ELAPSED_EXT: u32 = 0x88BF;
+const GPU_DISJOINT_EXT: u32 = 0x8FBB;
+/// Queries still waiting for the GPU, older ones are given up on
+const MAX_PENDING_QUERIES: usize = 8;
+
+/// Lower bounds of the frame rate histogram buckets, the last bucket takes everything slower
+const FPS_BUCKETS: [f32; 4] = [110.0, 55.0, 28.0, 15.0];
+/// Frames averaged over for the frame rate and timings
+const WINDOW_FRAMES: usize = 120;
+/// A frame counts as dropped when it took this many times the display refresh interval
+const DROPPED_FRAME_FACTOR: f32 = 1.5;
+
+const OVERLAY_ELEMENT: &str = "stats";
+/// Seconds between overlay refreshes, often enough to read and cheap on layout
+const OVERLAY_INTERVAL: f32 = 0.25;
+
+thread_local! {
+  static STATS: RefCell<FrameStats> = RefCell::new(FrameStats::default());
+}
+
+/// A summary of recent frames, as handed out by `frame_stats`
+#[wasm_bindgen]
+#[derive(Clone, Default)]
+pub struct FrameStats {
+  /// Frames per second, averaged over the last couple of seconds
+  pub fps: f32,
+  /// Milliseconds between frames
+  pub frame_ms: f32,
+  /// Milliseconds the render loop kept the main thread busy
+  pub cpu_ms: f32,
+  /// Milliseconds the GPU spent drawing, without timer query support `undefined`
+  pub gpu_ms: Option<f32>,
+  pub frames: u32,
+  /// Frames the display refreshed without a new one being ready
+  pub dropped_frames: u32,
+  /// Failed steps of frames, like a draw call that could not be made
+  pub errors: u32,
+  /// Index into the quality levels the governor picked, `0` being the best
+  pub quality_level: u32,
+  histogram: [u32; FPS_BUCKETS.len() + 1],
+}
+
+#[wasm_bindgen]
+impl FrameStats {
+  /// Frames counted at 110 fps and up, 55 to 110, 28 to 55, 15 to 28 and below 15
+  #[wasm_bindgen(getter)]
+  pub fn histogram(&self) -> Vec<u32> {
+    self.histogram.to_vec()
+  }
+}
+
+/// The latest frame timings
+#[wasm_bindgen]
+pub fn frame_stats() -> FrameStats {
+  STATS.with(|stats| stats.borrow().clone())
+}
+
+/// Start counting frames, drops and errors from zero again
+#[wasm_bindgen]
+pub fn reset_frame_stats() {
+  STATS.with(|stats| *stats.borrow_mut() = FrameStats::default());
+}
+
+/// Show or hide the overlay
+pub(crate) fn toggle_overlay() {
+  let overlay =
+    window().document().and_then(|document| document.get_element_by_id(OVERLAY_ELEMENT));
+  if let Some(overlay) = overlay.and_then(|overlay| overlay.dyn_into::<HtmlElement>().ok()) {
+    overlay.set_hidden(!overlay.hidden());
+  }
+}
+
+/// Measures how long the GPU takes for the commands between `begin` and `end`. Results arrive a
+/// few frames late.
+pub struct GpuTimer {
+  pending: VecDeque<WebGlQuery>,
+  /// Finished queries ready to be used again
+  free: Vec<WebGlQuery>,
+  running: bool,
+}
+
+impl GpuTimer {
+  /// `None` when the browser does not expose timer queries
+  pub(crate) fn new(gl_context: &WebGl2RenderingContext) -> Option<Self> {
+    gl_context.get_extension("EXT_disjoint_timer_query_webgl2").ok().flatten()?;
+    Some(GpuTimer { pending: VecDeque::new(), free: Vec::new(), running: false })
+  }
+
+  pub(crate) fn begin(&mut self, gl_context: &WebGl2RenderingContext) {
+    if self.pending.len() >= MAX_PENDING_QUERIES {
+      return;
+    }
+    let query = match self.free.pop().or_else(|| gl_context.create_query()) {
+      Some(query) => query,
+      None => return,
+    };
+    gl_context.begin_query(TIME_ELAPSED_EXT, &query);
+    self.pending.push_back(query);
+    self.running = true;
+  }
+
+  pub(crate) fn end(&mut self, gl_context: &WebGl2RenderingContext) {
+    if self.running {
+      gl_context.end_query(TIME_ELAPSED_EXT);
+      self.running = false;
+    }
+  }
+
+  /// Milliseconds of the most recent frame the GPU finished, if one did since the last call
+  pub(crate) fn poll(&mut self, gl_context: &WebGl2RenderingContext) -> Option<f32> {
+    // A disjoint operation, like a clock change, makes every pending result meaningless
+    let disjoint =
+      gl_context.get_parameter(GPU_DISJOINT_EXT).ok().and_then(|value| value.as_bool());
+    if disjoint == Some(true) {
+      self.free.extend(self.pending.drain(..));
+      return None;
+    }
+
+    let mut latest = None;
+    while let Some(query) = self.pending.front() {
+      let available = gl_context
+        .get_query_parameter(query, WebGl2RenderingContext::QUERY_RESULT_AVAILABLE)
+        .as_bool()
+        .unwrap_or(false);
+      if !available {
+        break;
+      }
+      let nanoseconds =
+        gl_context.get_query_parameter(query, WebGl2RenderingContext::QUERY_RESULT).as_f64();
+      latest = nanoseconds.map(|nanoseconds| (nanoseconds / 1.0e6) as f32);
+      self.free.extend(self.pending.pop_front());
+    }
+    latest
+  }
+}
+
+/// Times the render loop, publishes the results to `frame_stats` and keeps the overlay current
+pub struct Profiler {
+  gpu_timer: Option<GpuTimer>,
+  /// `performance.now()` at the start of the current frame
+  frame_start: f64,
+  /// Page time of the previous frame in seconds
+  last_frame: Option<f32>,
+  /// Milliseconds between frames and spent on the CPU, for the last `WINDOW_FRAMES` frames
+  intervals: VecDeque<f32>,
+  cpu_times: VecDeque<f32>,
+  /// Milliseconds of the latest frame on the CPU and GPU
+  last_cpu_ms: Option<f32>,
+  last_gpu_ms: Option<f32>,
+  overlay: Option<HtmlElement>,
+  since_overlay: f32,
+  /// The failure logged last, so one that repeats every frame is only logged once
+  last_error: Option<String>,
+}
+
+impl Profiler {
+  /// Adds the hidden overlay to the page, toggled with `toggle_overlay`
+  pub(crate) fn new(gl_context: &WebGl2RenderingContext) -> Result<Self, JsValue> {
+    let document = document()?;
+    let overlay = match document.get_element_by_id(OVERLAY_ELEMENT) {
+      Some(overlay) => overlay,
+      None => {
+        let overlay = document.create_element("pre")?;
+        overlay.set_id(OVERLAY_ELEMENT);
+        document
+          .body()
+          .ok_or_else(|| DemoError::MissingElement("the body".to_string()))?
+          .append_child(&overlay)?;
+        overlay
+      }
+    };
+    let overlay = overlay.dyn_into::<HtmlElement>()?;
+    overlay.set_hidden(true);
+
+    Ok(Profiler {
+      gpu_timer: GpuTimer::new(gl_context),
+      frame_start: 0.0,
+      last_frame: None,
+      intervals: VecDeque::with_capacity(WINDOW_FRAMES),
+      cpu_times: VecDeque::with_capacity(WINDOW_FRAMES),
+      last_cpu_ms: None,
+      last_gpu_ms: None,
+      overlay: Some(overlay),
+      since_overlay: 0.0,
+      last_error: None,
+    })
+  }
+
+  /// Call first thing in the frame, with the page time in seconds
+  pub(crate) fn begin_frame(&mut self, gl_context: &WebGl2RenderingContext, now: f32) {
+    self.frame_start = now_ms();
+    let interval = self.last_frame.map(|last_frame| (now - last_frame) * 1000.0);
+    self.last_frame = Some(now);
+
+    let gpu_ms = self.gpu_timer.as_mut().and_then(|gpu_timer| {
+      let gpu_ms = gpu_timer.poll(gl_context);
+      gpu_timer.begin(gl_context);
+      gpu_ms
+    });
+
+    STATS.with(|stats| {
+      let mut stats = stats.borrow_mut();
+      if let Some(gpu_ms) = gpu_ms {
+        stats.gpu_ms = Some(gpu_ms);
+        self.last_gpu_ms = Some(gpu_ms);
+      }
+      let interval = match interval {
+        Some(interval) if interval > 0.0 => interval,
+        _ => return,
+      };
+      push_window(&mut self.intervals, interval);
+      stats.frames += 1;
+
+      // The shortest recent interval is as close as we get to the display's refresh interval
+      let refresh = self.intervals.iter().copied().fold(f32::MAX, f32::min);
+      if interval > refresh * DROPPED_FRAME_FACTOR {
+        stats.dropped_frames += (interval / refresh).round() as u32 - 1;
+      }
+
+      let fps = 1000.0 / interval;
+      let bucket = FPS_BUCKETS.iter().position(|&bound| fps >= bound).unwrap_or(FPS_BUCKETS.len());
+      stats.histogram[bucket] += 1;
+
+      stats.frame_ms = average(&self.intervals);
+      stats.fps = 1000.0 / stats.frame_ms;
+    });
+  }
+
+  /// Call last thing in the frame, after every draw call
+  pub(crate) fn end_frame(&mut self, gl_context: &WebGl2RenderingContext, dt: f32) {
+    if let Some(gpu_timer) = &mut self.gpu_timer {
+      gpu_timer.end(gl_context);
+    }
+    let cpu_ms = (now_ms() - self.frame_start) as f32;
+    self.last_cpu_ms = Some(cpu_ms);
+    push_window(&mut self.cpu_times, cpu_ms);
+    let stats = STATS.with(|stats| {
+      let mut stats = stats.borrow_mut();
+      stats.cpu_ms = average(&self.cpu_times);
+      stats.clone()
+    });
+
+    self.since_overlay += dt;
+    if self.since_overlay < OVERLAY_INTERVAL {
+      return;
+    }
+    self.since_overlay = 0.0;
+    if let Some(overlay) = self.overlay.as_ref().filter(|overlay| !overlay.hidden()) {
+      let gpu = stats.gpu_ms.map_or("n/a".to_string(), |gpu_ms| format!("{:.2} ms", gpu_ms));
+      overlay.set_text_content(Some(&format!(
+        "{:.1} fps\nframe {:.2} ms\ncpu {:.2} ms\ngpu {}\ndropped {}\nerrors {}\nquality {}",
+        stats.fps,
+        stats.frame_ms,
+        stats.cpu_ms,
+        gpu,
+        stats.dropped_frames,
+        stats.errors,
+        stats.quality_level
+      )));
+    }
+  }
+
+  /// Start over after the context was lost, the pause is not a dropped frame and the old timer
+  /// queries are gone with the context
+  pub(crate) fn restart(&mut self, gl_context: &WebGl2RenderingContext) {
+    self.gpu_timer = GpuTimer::new(gl_context);
+    self.last_frame = None;
+    self.last_gpu_ms = None;
+  }
+
+  /// How long the slower of the CPU and GPU took on the latest frame they both finished
+  pub(crate) fn busy_ms(&self) -> Option<f32> {
+    match (self.last_cpu_ms, self.last_gpu_ms) {
+      (Some(cpu_ms), Some(gpu_ms)) => Some(cpu_ms.max(gpu_ms)),
+      (cpu_ms, gpu_ms) => cpu_ms.or(gpu_ms),
+    }
+  }
+
+  pub(crate) fn set_quality_level(&self, level: usize) {
+    STATS.with(|stats| stats.borrow_mut().quality_level = level as u32);
+  }
+
+  /// Count a failed step of the frame. A failure is only logged when it differs from the last
+  /// one logged, one that repeats every frame would flood the console.
+  pub(crate) fn report(&mut self, result: Result<(), JsValue>) {
+    if let Err(err) = result {
+      let message = format!("{:?}", err);
+      if self.last_error.as_ref() != Some(&message) {
+        web_sys::console::error_1(&err);
+        self.last_error = Some(message);
+      }
+      STATS.with(|stats| stats.borrow_mut().errors += 1);
+    }
+  }
+}
+
+fn now_ms() -> f64 {
+  window().performance().map_or(0.0, |performance| performance.now())
+}
+
+fn push_window(window: &mut VecDeque<f32>, value: f32) {
+  if window.len() == WINDOW_FRAMES {
+    window.pop_front();
+  }
+  window.push_back(value);
+}
+
+fn average(window: &VecDeque<f32>) -> f32 {
+  window.iter().sum::<f32>() / window.len().max(1) as f32
+}
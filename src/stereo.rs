@@ -0,0 +1,102 @@
+use crate::instancing::BAND_COUNT;
+use std::f32::consts::FRAC_1_SQRT_2;
+use wasm_bindgen::prelude::*;
+use web_sys::{AnalyserNode, AudioContext, AudioNode};
+
+/// Points of the goniometer, the most recent samples of both channels
+pub(crate) const SCOPE_POINTS: usize = 512;
+
+/// What the two channels do relative to each other
+#[derive(Clone, Debug, Default)]
+pub struct StereoFrame {
+  /// Spectrum band energies of each channel, like `AudioFrame::bands`
+  pub left_bands: Vec<f32>,
+  pub right_bands: Vec<f32>,
+  /// `-1.0` all left, `0.0` centered, `1.0` all right
+  pub balance: f32,
+  /// Share of the level in the side signal: `0.0` mono, `0.5` unrelated channels, `1.0` the
+  /// channels cancelling out
+  pub width: f32,
+  /// Phase correlation, `1.0` mono, `0.0` unrelated channels, `-1.0` out of phase
+  pub correlation: f32,
+  /// `x, y` pairs of the goniometer, side across and mid up, so mono draws a vertical line
+  pub scope: Vec<f32>,
+}
+
+/// Measure balance, width, correlation and the goniometer from the same stretch of both channels
+pub fn measure(left: &[f32], right: &[f32], frame: &mut StereoFrame) {
+  let (mut left_power, mut right_power, mut product) = (0.0f32, 0.0f32, 0.0f32);
+  let (mut mid_power, mut side_power) = (0.0f32, 0.0f32);
+  for (&l, &r) in left.iter().zip(right) {
+    left_power += l * l;
+    right_power += r * r;
+    product += l * r;
+    mid_power += (l + r) * (l + r);
+    side_power += (l - r) * (l - r);
+  }
+
+  let (left_rms, right_rms) = (left_power.sqrt(), right_power.sqrt());
+  frame.balance =
+    if left_rms + right_rms > 0.0 { (right_rms - left_rms) / (left_rms + right_rms) } else { 0.0 };
+  let (mid_rms, side_rms) = (mid_power.sqrt(), side_power.sqrt());
+  frame.width = if mid_rms + side_rms > 0.0 { side_rms / (mid_rms + side_rms) } else { 0.0 };
+  let energy = (left_power * right_power).sqrt();
+  frame.correlation = if energy > 0.0 { (product / energy).clamp(-1.0, 1.0) } else { 0.0 };
+
+  let points = left.len().min(right.len());
+  let start = points.saturating_sub(SCOPE_POINTS);
+  frame.scope.clear();
+  for (&l, &r) in left[start..points].iter().zip(&right[start..points]) {
+    frame.scope.push((l - r) * FRAC_1_SQRT_2);
+    frame.scope.push((l + r) * FRAC_1_SQRT_2);
+  }
+  frame.scope.resize(SCOPE_POINTS * 2, 0.0);
+}
+
+/// An analyser per channel of `input`, which has to carry exactly two: mono is copied to both,
+/// more channels are mixed down to stereo first
+pub(crate) struct StereoAnalyser {
+  left: AnalyserNode,
+  right: AnalyserNode,
+  bytes: Vec<u8>,
+  left_samples: Vec<f32>,
+  right_samples: Vec<f32>,
+}
+
+impl StereoAnalyser {
+  pub fn new(context: &AudioContext, input: &AudioNode) -> Result<Self, JsValue> {
+    let splitter = context.create_channel_splitter_with_number_of_outputs(2)?;
+    input.connect_with_audio_node(&splitter)?;
+    let left = context.create_analyser()?;
+    let right = context.create_analyser()?;
+    splitter.connect_with_audio_node_and_output(&left, 0)?;
+    splitter.connect_with_audio_node_and_output(&right, 1)?;
+    let fft_size = left.fft_size() as usize;
+    Ok(StereoAnalyser {
+      left,
+      right,
+      bytes: vec![0; BAND_COUNT],
+      left_samples: vec![0.0; fft_size],
+      right_samples: vec![0.0; fft_size],
+    })
+  }
+
+  /// Read the latest block of both channels into `frame`
+  pub fn update(&mut self, frame: &mut StereoFrame) {
+    for (analyser, bands) in
+      [(&self.left, &mut frame.left_bands), (&self.right, &mut frame.right_bands)]
+    {
+      analyser.get_byte_frequency_data(&mut self.bytes);
+      bands.clear();
+      bands.extend(self.bytes.iter().map(|&value| value as f32 / 255.0));
+    }
+    self.left.get_float_time_domain_data(&mut self.left_samples);
+    self.right.get_float_time_domain_data(&mut self.right_samples);
+    measure(&self.left_samples, &self.right_samples, frame);
+  }
+
+  /// The block of both channels the latest `update` read, oldest sample first
+  pub fn samples(&self) -> [&[f32]; 2] {
+    [&self.left_samples, &self.right_samples]
+  }
+}
@@ -0,0 +1,31 @@
+use std::f32::consts::PI;
+
+/// `length` samples of a sine at `frequency` Hz
+pub fn sine(frequency: f32, amplitude: f32, sample_rate: f32, length: usize) -> Vec<f32> {
+  (0..length)
+    .map(|index| amplitude * (2.0 * PI * frequency * index as f32 / sample_rate).sin())
+    .collect()
+}
+
+/// `length` samples of white noise in `-0.5..0.5`, the same every run: a cheap linear
+/// congruential generator
+pub fn noise(length: usize) -> Vec<f32> {
+  let mut state = 12345u32;
+  (0..length)
+    .map(|_| {
+      state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+      (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+    })
+    .collect()
+}
+
+pub fn assert_close(actual: impl Into<f64>, expected: impl Into<f64>, tolerance: f64) {
+  let (actual, expected) = (actual.into(), expected.into());
+  assert!(
+    (actual - expected).abs() <= tolerance,
+    "{} is not within {} of {}",
+    actual,
+    tolerance,
+    expected
+  );
+}
@@ -0,0 +1,141 @@
+use crate::{document, error::DemoError, transitions::TransitionDesc};
+use serde::Deserialize;
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{Document, HtmlAudioElement};
+
+/// The `<audio>` element whose playback position drives the timeline
+const TRACK_ELEMENT: &str = "audio-src";
+const PLAY_PAUSE_BUTTON: &str = "play-pause";
+
+/// When each scene starts, relative to the music track
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Timeline {
+  /// Sorted by time
+  pub cues: Vec<Cue>,
+  /// Jump back to `start` whenever playback reaches `end`
+  #[serde(rename = "loop")]
+  pub loop_section: Option<Section>,
+  /// Times count beats at the preset's tempo instead of seconds
+  #[serde(default)]
+  pub in_beats: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cue {
+  pub time: f32,
+  /// Name of the scene shown from `time` on
+  pub scene: String,
+  /// How to get there, the preset's default transition when left out
+  pub transition: Option<TransitionDesc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Section {
+  pub start: f32,
+  pub end: f32,
+}
+
+/// A cue with its time in seconds and its scene resolved to an index
+struct SequencedCue {
+  start: f32,
+  scene: usize,
+  transition: Option<TransitionDesc>,
+}
+
+/// Follows the track position and reports which scene should be showing
+pub struct Sequencer {
+  cues: Vec<SequencedCue>,
+  /// Start and end in seconds
+  loop_section: Option<(f32, f32)>,
+  current_cue: Option<usize>,
+}
+
+impl Sequencer {
+  /// Cues naming a scene that is not in `scene_names` are dropped
+  pub fn new(timeline: &Timeline, scene_names: &[&str], tempo: f32) -> Self {
+    let seconds = |time: f32| if timeline.in_beats { time * 60.0 / tempo } else { time };
+    let cues = timeline
+      .cues
+      .iter()
+      .filter_map(|cue| {
+        let scene = scene_names.iter().position(|name| *name == cue.scene)?;
+        Some(SequencedCue { start: seconds(cue.time), scene, transition: cue.transition })
+      })
+      .collect();
+    let loop_section =
+      timeline.loop_section.map(|section| (seconds(section.start), seconds(section.end)));
+    Sequencer { cues, loop_section, current_cue: None }
+  }
+
+  /// Where playback has to jump to when `position` ran past the end of the loop
+  pub fn loop_restart(&self, position: f32) -> Option<f32> {
+    match self.loop_section {
+      Some((start, end)) if position >= end => Some(start),
+      _ => None,
+    }
+  }
+
+  /// The scene to switch to, and the transition to use, when `position` entered another cue
+  /// since the last call. Seeking backwards or forwards lands on whichever cue covers the new
+  /// position.
+  pub fn update(&mut self, position: f32) -> Option<(usize, Option<TransitionDesc>)> {
+    let passed = self.cues.partition_point(|cue| cue.start <= position);
+    let cue = passed.checked_sub(1);
+    if cue == self.current_cue {
+      return None;
+    }
+    self.current_cue = cue;
+    cue.map(|cue| (self.cues[cue].scene, self.cues[cue].transition))
+  }
+}
+
+/// The music track, if the page has one
+pub(crate) fn find_track(document: &Document) -> Result<Option<HtmlAudioElement>, JsValue> {
+  match document.get_element_by_id(TRACK_ELEMENT) {
+    Some(element) => Ok(Some(element.dyn_into::<HtmlAudioElement>()?)),
+    None => Ok(None),
+  }
+}
+
+/// The music track, hooked up to the play/pause button when the page has one
+pub(crate) fn init_track(document: &Document) -> Result<Option<HtmlAudioElement>, JsValue> {
+  let track = match find_track(document)? {
+    Some(track) => track,
+    None => return Ok(None),
+  };
+
+  if let Some(button) = document.get_element_by_id(PLAY_PAUSE_BUTTON) {
+    let playing_track = track.clone();
+    let toggled_button = button.clone();
+    let closure = Closure::wrap(Box::new(move || {
+      let playing = if playing_track.paused() {
+        if let Err(err) = playing_track.play() {
+          web_sys::console::error_1(&err);
+        }
+        true
+      } else {
+        let _ = playing_track.pause();
+        false
+      };
+      let _ = toggled_button.set_attribute("data-playing", &playing.to_string());
+      let _ = toggled_button.set_attribute("aria-checked", &playing.to_string());
+    }) as Box<dyn FnMut()>);
+    button.add_event_listener_with_callback("click", closure.as_ref().unchecked_ref())?;
+    closure.forget();
+  }
+
+  Ok(Some(track))
+}
+
+/// Move the music, and with it the timeline, to `seconds`
+#[wasm_bindgen]
+pub fn seek(seconds: f64) -> Result<(), JsValue> {
+  let document = document()?;
+  let track = find_track(&document)?
+    .ok_or_else(|| DemoError::MissingElement("the music track".to_string()))?;
+  track.set_current_time(seconds);
+  Ok(())
+}
@@ -0,0 +1,230 @@
+use crate::{error, program_info::ProgramInfo, render_target::RenderTarget};
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+use web_sys::{WebGl2RenderingContext, WebGlUniformLocation, WebGlVertexArrayObject};
+
+const BLEND_VERT_SOURCE: &str = r#"#version 300 es
+    in vec2 a_position;
+    out vec2 v_uv;
+
+    void main(void) {
+      v_uv = a_position * 0.5 + 0.5;
+      gl_Position = vec4(a_position, 0.0, 1.0);
+    }
+"#;
+
+/// Mixes the outgoing scene in `u_from` with the incoming one in `u_to`. `u_kind` is the
+/// `TransitionKind::shader_index` of the transition.
+const BLEND_FRAG_SOURCE: &str = r#"#version 300 es
+    precision mediump float;
+
+    uniform sampler2D u_from;
+    uniform sampler2D u_to;
+    uniform float u_progress;
+    uniform int u_kind;
+    uniform vec2 u_resolution;
+
+    in vec2 v_uv;
+    out vec4 out_color;
+
+    float hash(vec2 p) {
+      return fract(sin(dot(p, vec2(127.1, 311.7))) * 43758.5453);
+    }
+
+    void main() {
+      vec4 from = texture(u_from, v_uv);
+      vec4 to = texture(u_to, v_uv);
+      float p = u_progress;
+      float mask = p;
+      if (u_kind == 1) {
+        // Wipe from left to right with a soft edge
+        mask = smoothstep(v_uv.x - 0.05, v_uv.x + 0.05, p * 1.1 - 0.05);
+      } else if (u_kind == 2) {
+        // Blocks of pixels flip over in random order
+        mask = step(hash(floor(v_uv * u_resolution / 4.0)), p);
+      } else if (u_kind == 3) {
+        // The outgoing scene rushes towards the camera while it fades
+        from = texture(u_from, (v_uv - 0.5) / (1.0 + p * 2.0) + 0.5);
+      } else if (u_kind == 4) {
+        // Dark parts of the outgoing scene give way first
+        float luma = dot(from.rgb, vec3(0.299, 0.587, 0.114));
+        mask = smoothstep(luma - 0.1, luma + 0.1, p * 1.2 - 0.1);
+      }
+      out_color = mix(from, to, mask);
+    }
+"#;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionKind {
+  /// Switch instantly
+  Cut,
+  Crossfade,
+  Wipe,
+  /// Noise decides where the incoming scene shows up first
+  Dissolve,
+  Zoom,
+  /// The incoming scene shows through the dark parts of the outgoing one first
+  LumaKey,
+}
+
+impl TransitionKind {
+  fn shader_index(self) -> i32 {
+    match self {
+      TransitionKind::Cut | TransitionKind::Crossfade => 0,
+      TransitionKind::Wipe => 1,
+      TransitionKind::Dissolve => 2,
+      TransitionKind::Zoom => 3,
+      TransitionKind::LumaKey => 4,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Length {
+  Seconds(f32),
+  /// Beats at the preset's tempo
+  Beats(f32),
+}
+
+impl Length {
+  pub fn seconds(self, tempo: f32) -> f32 {
+    match self {
+      Length::Seconds(seconds) => seconds,
+      Length::Beats(beats) => beats * 60.0 / tempo,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransitionDesc {
+  pub kind: TransitionKind,
+  pub length: Length,
+}
+
+/// A running transition away from `from_scene`
+pub struct Transition {
+  pub kind: TransitionKind,
+  pub from_scene: usize,
+  elapsed: f32,
+  duration: f32,
+}
+
+impl Transition {
+  /// `None` when the transition is a cut
+  pub fn start(desc: &TransitionDesc, from_scene: usize, tempo: f32) -> Option<Self> {
+    let duration = desc.length.seconds(tempo);
+    if desc.kind == TransitionKind::Cut || duration <= 0.0 {
+      return None;
+    }
+    Some(Transition { kind: desc.kind, from_scene, elapsed: 0.0, duration })
+  }
+
+  pub fn advance(&mut self, dt: f32) {
+    self.elapsed += dt;
+  }
+
+  /// `0.0` showing only the outgoing scene up to `1.0` showing only the incoming one
+  pub fn progress(&self) -> f32 {
+    (self.elapsed / self.duration).clamp(0.0, 1.0)
+  }
+
+  pub fn finished(&self) -> bool {
+    self.elapsed >= self.duration
+  }
+}
+
+/// The offscreen targets the two scenes are drawn into and the program blending them
+pub struct TransitionRenderer {
+  pub from: RenderTarget,
+  pub to: RenderTarget,
+  program_info: ProgramInfo,
+  vertex_array: WebGlVertexArrayObject,
+}
+
+impl TransitionRenderer {
+  pub(crate) fn new(gl_context: &WebGl2RenderingContext) -> Result<Self, JsValue> {
+    let program_info = ProgramInfo::new(gl_context, BLEND_VERT_SOURCE, BLEND_FRAG_SOURCE)?;
+    program_info.require_uniforms(&["u_from", "u_to", "u_progress", "u_kind", "u_resolution"])?;
+
+    // A full screen quad as a triangle strip, kept alive by the vertex array
+    let quad = crate::buffers::init_buffer(
+      gl_context,
+      &[-1.0, -1.0, 1.0, -1.0, -1.0, 1.0, 1.0, 1.0],
+      WebGl2RenderingContext::ARRAY_BUFFER,
+      WebGl2RenderingContext::STATIC_DRAW,
+    )?;
+    let vertex_array =
+      error::created(gl_context, gl_context.create_vertex_array(), "a vertex array object")?;
+    gl_context.bind_vertex_array(Some(&vertex_array));
+    gl_context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&quad));
+    let position = program_info.attrib("a_position")?;
+    gl_context.vertex_attrib_pointer_with_i32(
+      position,
+      2,
+      WebGl2RenderingContext::FLOAT,
+      false,
+      0,
+      0,
+    );
+    gl_context.enable_vertex_attrib_array(position);
+    gl_context.bind_vertex_array(None);
+
+    Ok(TransitionRenderer {
+      from: RenderTarget::new(gl_context)?,
+      to: RenderTarget::new(gl_context)?,
+      program_info,
+      vertex_array,
+    })
+  }
+
+  /// Size both targets to the canvas
+  pub(crate) fn resize(
+    &mut self,
+    gl_context: &WebGl2RenderingContext,
+    width: i32,
+    height: i32,
+  ) -> Result<(), JsValue> {
+    self.from.resize(gl_context, width, height)?;
+    self.to.resize(gl_context, width, height)
+  }
+
+  /// Blend the two targets onto the canvas
+  pub(crate) fn draw(
+    &self,
+    gl_context: &WebGl2RenderingContext,
+    transition: &Transition,
+    width: i32,
+    height: i32,
+  ) {
+    gl_context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+    gl_context.viewport(0, 0, width, height);
+    gl_context.disable(WebGl2RenderingContext::DEPTH_TEST);
+    gl_context.use_program(Some(&self.program_info.program));
+
+    gl_context.active_texture(WebGl2RenderingContext::TEXTURE0);
+    gl_context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&self.from.texture));
+    gl_context.active_texture(WebGl2RenderingContext::TEXTURE1);
+    gl_context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&self.to.texture));
+
+    gl_context.uniform1i(self.uniform("u_from"), 0);
+    gl_context.uniform1i(self.uniform("u_to"), 1);
+    gl_context.uniform1f(self.uniform("u_progress"), transition.progress());
+    gl_context.uniform1i(self.uniform("u_kind"), transition.kind.shader_index());
+    gl_context.uniform2f(self.uniform("u_resolution"), width as f32, height as f32);
+
+    gl_context.bind_vertex_array(Some(&self.vertex_array));
+    gl_context.draw_arrays(WebGl2RenderingContext::TRIANGLE_STRIP, 0, 4);
+    gl_context.bind_vertex_array(None);
+
+    gl_context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, None);
+    gl_context.active_texture(WebGl2RenderingContext::TEXTURE0);
+    gl_context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, None);
+  }
+
+  fn uniform(&self, name: &str) -> Option<&WebGlUniformLocation> {
+    self.program_info.uniform_locations.get(name).and_then(|location| location.as_ref())
+  }
+}
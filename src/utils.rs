@@ -1,41 +1,34 @@
-use std::convert::TryInto;
+use crate::{document, window};
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+use web_sys::{Blob, HtmlAnchorElement, Url};
 
-pub(crate) fn to_f32_8<T>(v: Vec<T>) -> [T; 8]
-where
-  T: Copy,
-{
-  let slice = v.as_slice();
-  let array: [T; 8] = match slice.try_into() {
-    Ok(ba) => ba,
-    Err(_) => {
-      panic!("Expected a `vec` of length `{}` but received a `vec` of length `{}`", 8, v.len())
-    }
-  };
-  array
-}
+/// How long a download's object URL outlives the click, browsers may start reading it later
+const REVOKE_DELAY_MS: i32 = 60_000;
 
-pub(crate) fn to_f32_16<T>(v: Vec<T>) -> [T; 16]
+pub(crate) fn mat4_to_f32_16<T>(v: nalgebra_glm::TMat4<T>) -> [T; 16]
 where
-  T: Copy,
+  T: 'static + Copy + PartialEq + std::fmt::Debug,
 {
-  let slice = v.as_slice();
-  let array: [T; 16] = match slice.try_into() {
-    Ok(ba) => ba,
-    Err(_) => {
-      panic!("Expected a `vec` of length `{}` but received a `vec` of length `{}`", 16, v.len())
-    }
-  };
-  array
+  // Column major, like WebGL expects
+  std::array::from_fn(|index| v[index])
 }
 
-pub(crate) fn mat4_to_f32_16<T: 'static>(v: nalgebra_glm::TMat4<T>) -> [T; 16]
-where
-  T: Copy + PartialEq + std::fmt::Debug,
-{
-  let slice = v.as_slice();
-  let array: [T; 16] = match slice.try_into() {
-    Ok(ba) => ba,
-    Err(_) => panic!("Expected"),
-  };
-  array
+/// Save `blob` as `file_name` through the browser's downloads
+pub(crate) fn download(blob: &Blob, file_name: &str) -> Result<(), JsValue> {
+  let url = Url::create_object_url_with_blob(blob)?;
+  let document = document()?;
+  let link = document.create_element("a")?.dyn_into::<HtmlAnchorElement>()?;
+  link.set_href(&url);
+  link.set_download(file_name);
+  link.click();
+
+  let revoke = Closure::once(move || {
+    let _ = Url::revoke_object_url(&url);
+  });
+  window().set_timeout_with_callback_and_timeout_and_arguments_0(
+    revoke.as_ref().unchecked_ref(),
+    REVOKE_DELAY_MS,
+  )?;
+  revoke.forget();
+  Ok(())
 }
@@ -0,0 +1,92 @@
+use crate::{
+  buffer_attrib::{self, BufferAttrib},
+  error::{self, DemoError},
+  program_info::ProgramInfo,
+  scene_graph::Mesh,
+};
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use web_sys::{WebGl2RenderingContext, WebGlBuffer, WebGlVertexArrayObject};
+
+/// Vertex array objects by mesh, then by program. Attribute locations differ between programs,
+/// so the same mesh drawn with two programs needs two vertex arrays.
+#[derive(Default)]
+pub struct VertexArrayCache {
+  /// Nested rather than keyed by a pair, so lookups borrow the names instead of allocating
+  vertex_arrays: HashMap<String, HashMap<String, WebGlVertexArrayObject>>,
+}
+
+impl VertexArrayCache {
+  pub fn new() -> Self {
+    VertexArrayCache::default()
+  }
+
+  /// Bind the vertex array for `mesh` drawn with `program_info`, building it the first time
+  pub(crate) fn bind(
+    &mut self,
+    gl_context: &WebGl2RenderingContext,
+    mesh_name: &str,
+    mesh: &Mesh,
+    program_name: &str,
+    program_info: &ProgramInfo,
+    buffers: &HashMap<String, WebGlBuffer>,
+  ) -> Result<(), JsValue> {
+    let cached =
+      self.vertex_arrays.get(mesh_name).and_then(|by_program| by_program.get(program_name));
+    if let Some(vertex_array) = cached {
+      gl_context.bind_vertex_array(Some(vertex_array));
+      return Ok(());
+    }
+
+    let vertex_array = create_vertex_array(gl_context, mesh, program_info, buffers)?;
+    gl_context.bind_vertex_array(Some(&vertex_array));
+    self
+      .vertex_arrays
+      .entry(mesh_name.to_string())
+      .or_default()
+      .insert(program_name.to_string(), vertex_array);
+    Ok(())
+  }
+
+  /// Delete every vertex array, needed once the buffers or programs they refer to are gone
+  pub(crate) fn clear(&mut self, gl_context: &WebGl2RenderingContext) {
+    for (_, by_program) in self.vertex_arrays.drain() {
+      for vertex_array in by_program.values() {
+        gl_context.delete_vertex_array(Some(vertex_array));
+      }
+    }
+  }
+}
+
+/// Record every attribute binding of `mesh` into a new vertex array object
+fn create_vertex_array(
+  gl_context: &WebGl2RenderingContext,
+  mesh: &Mesh,
+  program_info: &ProgramInfo,
+  buffers: &HashMap<String, WebGlBuffer>,
+) -> Result<WebGlVertexArrayObject, JsValue> {
+  let vertex_array =
+    error::created(gl_context, gl_context.create_vertex_array(), "a vertex array object")?;
+  gl_context.bind_vertex_array(Some(&vertex_array));
+
+  for mesh_attribute in &mesh.attributes {
+    let attribute = program_info.attrib(&mesh_attribute.attrib_name)?;
+    let buffer_attrib = BufferAttrib {
+      name: mesh_attribute.buffer_name.clone(),
+      buffer: buffers.get(&mesh_attribute.buffer_name).ok_or_else(|| {
+        DemoError::UnknownReference(format!("buffer `{}`", mesh_attribute.buffer_name))
+      })?,
+      target: WebGl2RenderingContext::ARRAY_BUFFER,
+      num_components: mesh_attribute.num_components,
+      buffer_type: WebGl2RenderingContext::FLOAT,
+      normalize: false,
+      stride: mesh_attribute.stride,
+      offset: mesh_attribute.offset,
+      divisor: mesh_attribute.divisor,
+    };
+    buffer_attrib::bind_buffer_to_attrib(gl_context, &buffer_attrib, attribute)?;
+  }
+
+  gl_context.bind_vertex_array(None);
+  Ok(vertex_array)
+}
@@ -0,0 +1,374 @@
+use crate::{
+  ring::{self, Consumer, FeatureRing, Interpolator},
+  window,
+};
+use js_sys::{Float32Array, Int32Array, Object, Reflect, SharedArrayBuffer, WebAssembly};
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+  AudioContext, AudioNode, AudioWorkletNode, AudioWorkletNodeOptions, ChannelCountMode,
+  MessageEvent, Response,
+};
+
+/// Samples per channel in a render quantum, what the processor gets on every call
+pub(crate) const QUANTUM: usize = 128;
+/// Values the processor posts per quantum: the context time in seconds, then rms, peak, low and
+/// high band levels and onset strength
+pub(crate) const FEATURE_COUNT: usize = 6;
+
+/// Must match `registerProcessor` in the processor script
+const PROCESSOR_NAME: &str = "democ-analyser";
+/// Served next to the page, the processor runs its own instance of this crate's wasm
+const PROCESSOR_URL: &str = "worklet/analyser-processor.js";
+const WASM_URL: &str = "pkg/webgl_rs_practice_bg.wasm";
+/// Quanta the processor collects before posting them, about 6 ms at 44.1 kHz
+const QUANTA_PER_MESSAGE: u32 = 2;
+/// Quanta the shared ring holds, about 0.75 s at 44.1 kHz so a few dropped frames lose nothing
+const RING_CAPACITY: usize = 256;
+/// Seconds the renderer looks behind the context time, so there usually is a quantum on either
+/// side to interpolate between
+const RENDER_DELAY: f64 = 0.01;
+
+/// Crossovers of the one-pole filters splitting off the lows and the highs, in Hz
+const LOW_CUTOFF: f32 = 150.0;
+const HIGH_CUTOFF: f32 = 4000.0;
+/// Seconds the energy average onsets are measured against looks back
+const ONSET_WINDOW: f32 = 0.25;
+
+thread_local! {
+  /// Only used inside the processor's instance of the module
+  static WORKLET: RefCell<Option<WorkletState>> = const { RefCell::new(None) };
+  /// Only used on the page, once the processor is running
+  static FEED: RefCell<Option<SharedFeed>> = const { RefCell::new(None) };
+}
+
+/// Levels of one render quantum, or of every quantum since the last animation frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QuantumFeatures {
+  pub rms: f32,
+  pub peak: f32,
+  /// Levels below `LOW_CUTOFF` and above `HIGH_CUTOFF`
+  pub low: f32,
+  pub high: f32,
+  /// `0.0` steady, towards `1.0` as the energy jumps above its recent average
+  pub onset: f32,
+}
+
+impl QuantumFeatures {
+  /// Fold in a later quantum. Peaks and onsets keep the strongest so a transient lasting a
+  /// single quantum survives until the frame reads it, levels average.
+  fn merge(&mut self, other: &QuantumFeatures, count: u32) {
+    let weight = 1.0 / (count + 1) as f32;
+    self.rms += (other.rms - self.rms) * weight;
+    self.low += (other.low - self.low) * weight;
+    self.high += (other.high - self.high) * weight;
+    self.peak = self.peak.max(other.peak);
+    self.onset = self.onset.max(other.onset);
+  }
+}
+
+/// The DSP the processor runs on every render quantum
+pub struct QuantumAnalyser {
+  low_coefficient: f32,
+  high_coefficient: f32,
+  /// Outputs of the two lowpass filters, the highs are what the second one takes out
+  low_state: f32,
+  high_state: f32,
+  average_coefficient: f32,
+  average_energy: f32,
+}
+
+impl QuantumAnalyser {
+  pub fn new(sample_rate: f32) -> Self {
+    let one_pole = |cutoff: f32| 1.0 - (-2.0 * std::f32::consts::PI * cutoff / sample_rate).exp();
+    QuantumAnalyser {
+      low_coefficient: one_pole(LOW_CUTOFF),
+      high_coefficient: one_pole(HIGH_CUTOFF),
+      low_state: 0.0,
+      high_state: 0.0,
+      average_coefficient: 1.0 - (-(QUANTUM as f32) / (sample_rate * ONSET_WINDOW)).exp(),
+      average_energy: 0.0,
+    }
+  }
+
+  /// Measure one quantum, the channels are mixed to mono first
+  pub fn process(&mut self, channels: &[&[f32]]) -> QuantumFeatures {
+    let length = channels.iter().map(|channel| channel.len()).min().unwrap_or(0);
+    let (mut energy, mut peak, mut low_energy, mut high_energy) = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+    for index in 0..length {
+      let sample =
+        channels.iter().map(|channel| channel[index]).sum::<f32>() / channels.len() as f32;
+      self.low_state += self.low_coefficient * (sample - self.low_state);
+      self.high_state += self.high_coefficient * (sample - self.high_state);
+      let high = sample - self.high_state;
+      energy += sample * sample;
+      low_energy += self.low_state * self.low_state;
+      high_energy += high * high;
+      peak = peak.max(sample.abs());
+    }
+    let samples = length.max(1) as f32;
+    let energy = energy / samples;
+
+    let flux = (energy - self.average_energy).max(0.0) / (self.average_energy + 1e-6);
+    self.average_energy += self.average_coefficient * (energy - self.average_energy);
+
+    QuantumFeatures {
+      rms: energy.sqrt(),
+      peak,
+      low: (low_energy / samples).sqrt(),
+      high: (high_energy / samples).sqrt(),
+      onset: flux / (1.0 + flux),
+    }
+  }
+}
+
+/// What the processor's instance keeps between calls. The inputs never grow, so pointers to them
+/// stay valid for the processor to copy samples into.
+struct WorkletState {
+  analyser: QuantumAnalyser,
+  inputs: [Vec<f32>; 2],
+  features: [f32; FEATURE_COUNT],
+}
+
+/// Called by the processor once its instance of the module is up
+#[no_mangle]
+pub extern "C" fn democ_worklet_init(sample_rate: f32) {
+  WORKLET.with(|worklet| {
+    *worklet.borrow_mut() = Some(WorkletState {
+      analyser: QuantumAnalyser::new(sample_rate),
+      inputs: [vec![0.0; QUANTUM], vec![0.0; QUANTUM]],
+      features: [0.0; FEATURE_COUNT],
+    });
+  });
+}
+
+/// Where the processor writes the `QUANTUM` samples of `channel`, `0` or `1`, null before init
+#[no_mangle]
+pub extern "C" fn democ_worklet_input(channel: u32) -> *mut f32 {
+  WORKLET.with(|worklet| match (worklet.borrow_mut().as_mut(), channel) {
+    (Some(state), 0 | 1) => state.inputs[channel as usize].as_mut_ptr(),
+    _ => std::ptr::null_mut(),
+  })
+}
+
+/// Analyse the inputs of the quantum starting at `time`, using the first `channels` of them.
+/// Returns where the `FEATURE_COUNT` results are, null before init.
+#[no_mangle]
+pub extern "C" fn democ_worklet_process(channels: u32, time: f64) -> *const f32 {
+  WORKLET.with(|worklet| {
+    let mut worklet = worklet.borrow_mut();
+    let state = match worklet.as_mut() {
+      Some(state) => state,
+      None => return std::ptr::null(),
+    };
+    let [left, right] = &state.inputs;
+    let inputs = [left.as_slice(), right.as_slice()];
+    let features = state.analyser.process(&inputs[..(channels as usize).clamp(1, 2)]);
+    state.features =
+      [time as f32, features.rms, features.peak, features.low, features.high, features.onset];
+    state.features.as_ptr()
+  })
+}
+
+/// Collected from the processor, through the shared ring when the page is cross-origin isolated
+/// and from its messages otherwise, and taken by the audio loop every frame
+#[derive(Default)]
+pub(crate) struct WorkletFeed {
+  features: QuantumFeatures,
+  quanta: u32,
+  /// Context time of the latest quantum
+  pub time: f32,
+  ring: Option<(Consumer<Int32Array>, Interpolator)>,
+}
+
+impl WorkletFeed {
+  /// Everything measured since the last call, `None` when no quantum arrived in between
+  pub fn take(&mut self) -> Option<QuantumFeatures> {
+    if self.quanta == 0 {
+      return None;
+    }
+    self.quanta = 0;
+    Some(std::mem::take(&mut self.features))
+  }
+
+  /// Catch up with the shared ring, if there is one. Peaks and onsets of every quantum up to
+  /// `context_time` are kept, levels are interpolated to it.
+  pub fn read_ring(&mut self, context_time: f64) {
+    let (consumer, mut interpolator) = match self.ring.take() {
+      Some(ring) => ring,
+      None => return,
+    };
+    let mut levels = [0.0; FEATURE_COUNT];
+    let time = (context_time - RENDER_DELAY) as f32;
+    if interpolator.sample(&consumer, time, &mut levels, |quantum| self.push(quantum)) {
+      self.quanta = self.quanta.max(1);
+      self.features.rms = levels[1];
+      self.features.low = levels[3];
+      self.features.high = levels[4];
+    }
+    self.ring = Some((consumer, interpolator));
+  }
+
+  /// Fold in the next quantum
+  pub fn add(&mut self, features: &QuantumFeatures) {
+    if self.quanta == 0 {
+      self.features = *features;
+    } else {
+      self.features.merge(features, self.quanta);
+    }
+    self.quanta += 1;
+  }
+
+  /// One quantum as the processor posts it, `FEATURE_COUNT` values
+  fn push(&mut self, values: &[f32]) {
+    self.add(&QuantumFeatures {
+      rms: values[1],
+      peak: values[2],
+      low: values[3],
+      high: values[4],
+      onset: values[5],
+    });
+    self.time = values[0];
+  }
+}
+
+pub(crate) type SharedFeed = Rc<RefCell<WorkletFeed>>;
+
+/// Load the processor, feed it `input` and collect what it posts into `feed`. Fails on browsers
+/// without `AudioWorklet`, or when the processor script or the wasm are not served, the
+/// `AnalyserNode` path keeps working then.
+pub(crate) async fn start(
+  context: AudioContext,
+  input: AudioNode,
+  feed: SharedFeed,
+) -> Result<(), JsValue> {
+  let response: Response = JsFuture::from(window().fetch_with_str(WASM_URL)).await?.dyn_into()?;
+  if !response.ok() {
+    return Err(format!("Failed to fetch `{}`: {}", WASM_URL, response.status()).into());
+  }
+  let bytes = JsFuture::from(response.array_buffer()?).await?;
+  let module = JsFuture::from(WebAssembly::compile(&bytes)).await?;
+  JsFuture::from(context.audio_worklet()?.add_module(PROCESSOR_URL)?).await?;
+
+  let processor_options = Object::new();
+  Reflect::set(&processor_options, &"module".into(), &module)?;
+  Reflect::set(&processor_options, &"quantaPerMessage".into(), &QUANTA_PER_MESSAGE.into())?;
+  // Memory can only be shared once the page is cross-origin isolated
+  let isolated = Reflect::get(&window(), &"crossOriginIsolated".into())?.is_truthy();
+  if isolated {
+    let words = ring::words_needed(RING_CAPACITY, FEATURE_COUNT);
+    let buffer = SharedArrayBuffer::new((words * 4) as u32);
+    let ring = FeatureRing::create(Int32Array::new(&buffer), RING_CAPACITY, FEATURE_COUNT);
+    let consumer = ring.consumer();
+    feed.borrow_mut().ring = Some((consumer, Interpolator::new(FEATURE_COUNT)));
+    Reflect::set(&processor_options, &"ring".into(), &buffer)?;
+  }
+  let options = AudioWorkletNodeOptions::new();
+  options.set_number_of_inputs(1);
+  options.set_number_of_outputs(0);
+  options.set_channel_count(2);
+  options.set_channel_count_mode(ChannelCountMode::Explicit);
+  options.set_processor_options(Some(&processor_options));
+  let node = AudioWorkletNode::new_with_options(&context, PROCESSOR_NAME, &options)?;
+
+  let feed_clone = feed.clone();
+  let closure = Closure::wrap(Box::new(move |event: MessageEvent| {
+    // Every message is `QUANTA_PER_MESSAGE` quanta of `FEATURE_COUNT` values
+    if let Ok(values) = event.data().dyn_into::<Float32Array>() {
+      let mut feed = feed.borrow_mut();
+      for quantum in values.to_vec().chunks_exact(FEATURE_COUNT) {
+        feed.push(quantum);
+      }
+    }
+  }) as Box<dyn FnMut(MessageEvent)>);
+  node.port()?.set_onmessage(Some(closure.as_ref().unchecked_ref()));
+  closure.forget();
+
+  input.connect_with_audio_node(&node)?;
+  FEED.with(|slot| *slot.borrow_mut() = Some(feed_clone));
+  Ok(())
+}
+
+/// How the processor's features reach the page: `transport` is `ring` or `messages`, and with the
+/// ring `queued` quanta not read yet, `overflows` dropped because the page fell behind and
+/// `underruns` frames that found no newer quantum. `undefined` while the worklet is not running.
+#[wasm_bindgen]
+pub fn audio_worklet_stats() -> Result<JsValue, JsValue> {
+  let feed = match FEED.with(|slot| slot.borrow().clone()) {
+    Some(feed) => feed,
+    None => return Ok(JsValue::UNDEFINED),
+  };
+  let stats = Object::new();
+  match &feed.borrow().ring {
+    Some((consumer, _)) => {
+      let ring = consumer.ring();
+      Reflect::set(&stats, &"transport".into(), &"ring".into())?;
+      Reflect::set(&stats, &"queued".into(), &(ring.len() as u32).into())?;
+      Reflect::set(&stats, &"overflows".into(), &ring.overflows().into())?;
+      Reflect::set(&stats, &"underruns".into(), &ring.underruns().into())?;
+    }
+    None => {
+      Reflect::set(&stats, &"transport".into(), &"messages".into())?;
+    }
+  }
+  Ok(stats.into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::sine;
+
+  const RATE: f32 = 48000.0;
+
+  /// Run `quanta` quanta of a sine at `freq` through `analyser`, returning the last features
+  fn run(
+    analyser: &mut QuantumAnalyser,
+    freq: f32,
+    amplitude: f32,
+    quanta: usize,
+  ) -> QuantumFeatures {
+    let samples = sine(freq, amplitude, RATE, quanta * QUANTUM);
+    let mut features = QuantumFeatures::default();
+    for quantum in samples.chunks(QUANTUM) {
+      features = analyser.process(&[quantum, quantum]);
+    }
+    features
+  }
+
+  #[test]
+  fn lows_and_highs_are_split() {
+    let bass = run(&mut QuantumAnalyser::new(RATE), 50.0, 0.5, 40);
+    assert!(bass.low > 0.8 * bass.rms, "{:?}", bass);
+    assert!(bass.high < 0.1 * bass.low, "{:?}", bass);
+
+    let treble = run(&mut QuantumAnalyser::new(RATE), 12000.0, 0.5, 40);
+    // One pole filters roll off gently, but the split is clear
+    assert!(treble.high > 0.5 * treble.rms, "{:?}", treble);
+    assert!(treble.low < 0.1 * treble.high, "{:?}", treble);
+    assert!((treble.peak - 0.5).abs() < 0.01, "{:?}", treble);
+  }
+
+  #[test]
+  fn onsets_fire_on_a_rise_and_fade_while_steady() {
+    // One period per quantum, so every quantum of the tone has the same energy
+    let mut analyser = QuantumAnalyser::new(RATE);
+    assert_eq!(run(&mut analyser, 375.0, 0.0, 40).onset, 0.0);
+    let attack = run(&mut analyser, 375.0, 0.5, 1);
+    assert!(attack.onset > 0.9, "{:?}", attack);
+    // Well past the window the average has caught up
+    let steady = run(&mut analyser, 375.0, 0.5, 400);
+    assert!(steady.onset < 0.05, "{:?}", steady);
+  }
+
+  #[test]
+  fn channels_are_mixed_to_mono() {
+    let samples = [0.5; QUANTUM];
+    let inverted = [-0.5; QUANTUM];
+    let features = QuantumAnalyser::new(RATE).process(&[&samples, &inverted]);
+    assert_eq!((features.rms, features.peak), (0.0, 0.0));
+    let features = QuantumAnalyser::new(RATE).process(&[&samples]);
+    assert_eq!((features.rms, features.peak), (0.5, 0.5));
+  }
+}
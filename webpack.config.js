@@ -2,6 +2,7 @@ const path = require('path');
 const HtmlWebpackPlugin = require('html-webpack-plugin');
 const webpack = require('webpack');
 const WasmPackPlugin = require("@wasm-tool/wasm-pack-plugin");
+const CopyPlugin = require('copy-webpack-plugin');
 
 module.exports = {
     entry: './index.js',
@@ -21,9 +22,21 @@ module.exports = {
         new HtmlWebpackPlugin({
             template: 'index.html'
         }),
+        new HtmlWebpackPlugin({
+            template: 'controls.html',
+            filename: 'controls.html',
+            inject: false
+        }),
       new WasmPackPlugin({
           crateDirectory: path.resolve(__dirname, ".")
       }),
+        // The audio worklet loads these by URL, outside of the bundle
+        new CopyPlugin({
+          patterns: [
+            { from: 'worklet/analyser-processor.js', to: 'worklet' },
+            { from: 'pkg/webgl_rs_practice_bg.wasm', to: 'pkg' }
+          ]
+        }),
         // Have this example work in Edge which doesn't ship `TextEncoder` or
         // `TextDecoder` at this time.
         new webpack.ProvidePlugin({
@@ -31,5 +44,12 @@ module.exports = {
           TextEncoder: ['text-encoding', 'TextEncoder']
         })
     ],
+    devServer: {
+        // Cross-origin isolation lets the audio worklet share its ring buffer with the page
+        headers: {
+            'Cross-Origin-Opener-Policy': 'same-origin',
+            'Cross-Origin-Embedder-Policy': 'require-corp'
+        }
+    },
     mode: 'development'
 };
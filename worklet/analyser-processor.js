@@ -0,0 +1,91 @@
+// Runs `src/worklet.rs` on the audio thread. The page hands over the crate's compiled wasm, which
+// is instantiated here a second time; only the plain `democ_worklet_*` exports are called, so
+// the imports wasm-bindgen expects are stubbed out.
+
+// Must match `FEATURE_COUNT` and `QUANTUM` in `src/worklet.rs`
+const FEATURE_COUNT = 6;
+const QUANTUM = 128;
+// Layout of the shared ring's header, must match `src/ring.rs`
+const HEADER_WORDS = 8;
+const WRITE = 0;
+const READ = 1;
+const OVERFLOWS = 2;
+const STRIDE = 4;
+const CAPACITY = 5;
+
+// The producing end of `FeatureRing`, the page consumes from the same `SharedArrayBuffer`
+class RingProducer {
+  constructor(buffer) {
+    this.words = new Int32Array(buffer);
+    this.floats = new Float32Array(buffer);
+    this.stride = Atomics.load(this.words, STRIDE);
+    this.capacity = Atomics.load(this.words, CAPACITY);
+  }
+
+  push(frame) {
+    const write = Atomics.load(this.words, WRITE) >>> 0;
+    const read = Atomics.load(this.words, READ) >>> 0;
+    if (((write - read) >>> 0) >= this.capacity) {
+      Atomics.add(this.words, OVERFLOWS, 1);
+      return false;
+    }
+    this.floats.set(frame, HEADER_WORDS + (write % this.capacity) * this.stride);
+    // Publishes the frame, the page does not look at the slot before this
+    Atomics.store(this.words, WRITE, (write + 1) | 0);
+    return true;
+  }
+}
+
+class DemocAnalyser extends AudioWorkletProcessor {
+  constructor(options) {
+    super();
+    const { module, quantaPerMessage, ring } = options.processorOptions;
+    const imports = {};
+    for (const { module: name, name: field } of WebAssembly.Module.imports(module)) {
+      imports[name] = imports[name] || {};
+      imports[name][field] = () => {
+        throw new Error(`${field} is not available in the audio worklet`);
+      };
+    }
+    this.exports = new WebAssembly.Instance(module, imports).exports;
+    this.exports.democ_worklet_init(sampleRate);
+    // Without a shared ring, which needs a cross-origin isolated page, quanta are posted
+    this.ring = ring ? new RingProducer(ring) : null;
+    this.quantaPerMessage = quantaPerMessage;
+    this.batch = new Float32Array(FEATURE_COUNT * quantaPerMessage);
+    this.batched = 0;
+  }
+
+  process(inputs) {
+    const channels = inputs[0];
+    if (channels.length === 0) {
+      // Nothing connected yet, keep the node alive for when something is
+      return true;
+    }
+
+    // Views are made every quantum, growing the memory detaches the old ones
+    const memory = this.exports.memory.buffer;
+    const count = Math.min(channels.length, 2);
+    for (let channel = 0; channel < count; channel++) {
+      const pointer = this.exports.democ_worklet_input(channel);
+      new Float32Array(memory, pointer, QUANTUM).set(channels[channel]);
+    }
+    const pointer = this.exports.democ_worklet_process(count, currentTime);
+    const features = new Float32Array(this.exports.memory.buffer, pointer, FEATURE_COUNT);
+    if (this.ring) {
+      this.ring.push(features);
+      return true;
+    }
+    this.batch.set(features, this.batched * FEATURE_COUNT);
+
+    this.batched += 1;
+    if (this.batched === this.quantaPerMessage) {
+      this.port.postMessage(this.batch, [this.batch.buffer]);
+      this.batch = new Float32Array(FEATURE_COUNT * this.quantaPerMessage);
+      this.batched = 0;
+    }
+    return true;
+  }
+}
+
+registerProcessor('democ-analyser', DemocAnalyser);